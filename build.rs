@@ -0,0 +1,75 @@
+use std::process::Command;
+
+// Dependencies whose resolved versions are reported by `rag-rs info`
+const REPORTED_DEPENDENCIES: [&str; 4] = ["qdrant-client", "async-openai", "bm25", "axum"];
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Minimal Cargo.lock scan: `name = "..."` is always followed by `version = "..."`.
+// When several versions of a dependency are locked, the root package lists the one it
+// depends on as "<name> <version>".
+fn locked_version(lockfile: &str, dependency: &str) -> Option<String> {
+    let root_dependency = format!("\"{} ", dependency);
+    let root_package = lockfile
+        .split("[[package]]")
+        .find(|p| p.contains("name = \"rag-rs\""))
+        .unwrap_or_default();
+    if let Some(entry) = root_package
+        .lines()
+        .map(|l| l.trim())
+        .find(|l| l.starts_with(&root_dependency))
+    {
+        return Some(
+            entry
+                .trim_start_matches(&root_dependency)
+                .trim_end_matches(',')
+                .trim_matches('"')
+                .to_string(),
+        );
+    }
+    let name_line = format!("name = \"{}\"", dependency);
+    let mut lines = lockfile.lines();
+    while let Some(line) = lines.next() {
+        if line.trim() == name_line {
+            let version_line = lines.next()?;
+            return Some(
+                version_line
+                    .trim()
+                    .trim_start_matches("version = ")
+                    .trim_matches('"')
+                    .to_string(),
+            );
+        }
+    }
+    None
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    if let Some(hash) = command_output("git", &["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=GIT_HASH={}", hash);
+    }
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = command_output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=RUSTC_VERSION={}", version);
+    }
+    let lockfile = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for dependency in REPORTED_DEPENDENCIES {
+        if let Some(version) = locked_version(&lockfile, dependency) {
+            println!(
+                "cargo:rustc-env=DEP_VERSION_{}={}",
+                dependency.to_uppercase().replace('-', "_"),
+                version
+            );
+        }
+    }
+}
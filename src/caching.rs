@@ -1,16 +1,126 @@
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    AeadCore, Aes256Gcm, KeyInit, Nonce,
+    aead::{Aead, OsRng, rand_core::RngCore},
+};
+use anyhow::Context;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::SyncIoBridge;
+use tracing::{debug, warn};
+
+use crate::checkpointing::content_hash;
 
 const DEFAULT_CACHE_DIR: &str = "./.rag-rs-cache";
 const DEFAULT_CHUNK_SIZE: usize = 1024;
+// Index of the cache directory, missing until the first write
+const INDEX_DIRECTORY: &str = "index-v5";
+// Random salt of the encryption keys derived from a passphrase, created in the cache directory
+const ENCRYPTION_SALT_FILE: &str = "encryption-salt";
+#[cfg(not(test))]
+const PBKDF2_ROUNDS: u32 = 600_000;
+// the derivation takes seconds without optimizations
+#[cfg(test)]
+const PBKDF2_ROUNDS: u32 = 1_000;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CacheError {
+    #[error(transparent)]
+    Cacache(#[from] cacache::Error),
+    #[error(
+        "Could not decrypt the cache entry {key}: it was written with another key, or not encrypted"
+    )]
+    Decryption { key: String },
+    #[error("The cache entry {key} expired")]
+    Expired { key: String },
+    #[error("The content of the cache entry {key} was corrupted")]
+    Corrupted { key: String },
+    #[error("The chunk size of the cache should be greater than 0")]
+    InvalidChunkSize,
+    #[error("Could not access the content of the cache entry {key}")]
+    Io {
+        key: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Could not serialize the cache entry {key}")]
+    Serialization {
+        key: String,
+        #[source]
+        source: bincode::error::EncodeError,
+    },
+    #[error("Could not deserialize the cache entry {key}")]
+    Deserialization {
+        key: String,
+        #[source]
+        source: bincode::error::DecodeError,
+    },
+}
+
+impl CacheError {
+    fn io(key: &str) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| Self::Io {
+            key: key.to_string(),
+            source,
+        }
+    }
+}
+
+/// Limits of the cache, see `Cache::options`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheOptions {
+    /// Maximum size of the content of the whole cache directory: the least recently used
+    /// entries are evicted after each write until the cache fits
+    pub max_size_bytes: Option<u64>,
+    /// Entries written longer ago are treated as misses when read, and removed
+    pub ttl: Option<Duration>,
+}
+
+/// An entry of the cache, see `Cache::list`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CacheEntryInfo {
+    /// Key of the entry, without the namespace
+    pub key: String,
+    pub size: usize,
+    /// When the entry was written, in milliseconds since the Unix epoch
+    pub created_at: u128,
+    /// Path of the file the content of the entry was derived from
+    pub original_path: Option<String>,
+}
+
+/// Counts of `Cache::verify`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub corrupted_removed: usize,
+    /// Size of the corrupted content removed from the disk
+    pub bytes_reclaimed: u64,
+}
 
 pub struct Cache {
     pub directory: String,
     pub chunk_size: usize,
+    pub namespace: String,
+    /// When set, the content of the entries is encrypted at rest with AES-256-GCM
+    pub encryption_key: Option<[u8; 32]>,
+    pub options: CacheOptions,
 }
 
 impl Cache {
-    pub fn new(directory: Option<String>, chunk_size: Option<usize>) -> Self {
+    pub fn new(
+        directory: Option<String>,
+        chunk_size: Option<usize>,
+        namespace: Option<String>,
+    ) -> Self {
         let cache_dir = match directory {
             Some(s) => s,
             None => DEFAULT_CACHE_DIR.to_string(),
@@ -22,69 +132,1220 @@ impl Cache {
         Self {
             directory: cache_dir,
             chunk_size: cache_chunk_size,
+            namespace: namespace.unwrap_or_default(),
+            encryption_key: None,
+            options: CacheOptions::default(),
+        }
+    }
+
+    /// Load an encryption key from a file, holding either the key as 64 hexadecimal
+    /// characters, or a passphrase the key is derived from (see `derive_encryption_key`)
+    /// with the salt of the cache directory
+    pub async fn load_encryption_key(&self, key_file: &Path) -> anyhow::Result<[u8; 32]> {
+        let content = tokio::fs::read_to_string(key_file)
+            .await
+            .with_context(|| format!("Could not read the encryption key file {:?}", key_file))?;
+        let content = content.trim();
+        if content.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The encryption key file {:?} is empty",
+                key_file
+            ));
+        }
+        if let Some(key) = parse_hex_key(content) {
+            return Ok(key);
+        }
+        let salt_path = Path::new(&self.directory).join(ENCRYPTION_SALT_FILE);
+        let salt = match tokio::fs::read(&salt_path).await {
+            Ok(salt) => salt,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut salt = vec![0_u8; 16];
+                OsRng.fill_bytes(&mut salt);
+                tokio::fs::create_dir_all(&self.directory).await?;
+                tokio::fs::write(&salt_path, &salt).await?;
+                salt
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not read {:?}", salt_path));
+            }
+        };
+        Ok(derive_encryption_key(content, &salt))
+    }
+
+    /// Key of an entry derived from `content` (e.g. the bytes of a file), so that the same
+    /// content is found at any path, and changed content never hits a stale entry
+    pub fn content_key(kind: &str, content: impl AsRef<[u8]>) -> String {
+        format!("{}-{}", kind, content_hash(content))
+    }
+
+    fn key(&self, key: &str) -> String {
+        if self.namespace.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.namespace, key)
+        }
+    }
+
+    /// Write an entry, recording the path of the file its content was derived from. With
+    /// `max_size_bytes`, the least recently used entries are evicted afterwards.
+    pub async fn write_bytes(
+        &self,
+        key: &str,
+        content: Vec<u8>,
+        original_path: Option<&Path>,
+    ) -> Result<(), CacheError> {
+        let content = match &self.encryption_key {
+            Some(encryption_key) => encrypt(encryption_key, &content),
+            None => content,
+        };
+        self.write_stream(key, content.as_slice(), original_path)
+            .await?;
+        Ok(())
+    }
+
+    /// Write an entry from a reader through a buffer of `chunk_size` bytes, returning its
+    /// size, see `write_bytes`. Encrypted entries are buffered whole, since their content is
+    /// encrypted at once.
+    pub async fn write_from_reader(
+        &self,
+        key: &str,
+        mut reader: impl AsyncRead + Unpin,
+        original_path: Option<&Path>,
+    ) -> Result<u64, CacheError> {
+        if self.encryption_key.is_none() {
+            return self.write_stream(key, reader, original_path).await;
         }
+        let mut content: Vec<u8> = vec![];
+        reader
+            .read_to_end(&mut content)
+            .await
+            .map_err(CacheError::io(key))?;
+        let size = content.len() as u64;
+        self.write_bytes(key, content, original_path).await?;
+        Ok(size)
     }
 
-    pub async fn write_file_content(
+    async fn write_stream(
         &self,
-        file_path: &str,
-        file_content: String,
-    ) -> cacache::Result<()> {
-        let to_cache = file_content.into_bytes();
-        let mut fd = cacache::Writer::create(&self.directory, file_path).await?;
-        for chunk in to_cache.chunks(self.chunk_size) {
-            fd.write_all(chunk)
+        key: &str,
+        mut reader: impl AsyncRead + Unpin,
+        original_path: Option<&Path>,
+    ) -> Result<u64, CacheError> {
+        self.check_chunk_size()?;
+        let mut fd = cacache::WriteOpts::new()
+            .algorithm(cacache::Algorithm::Sha256)
+            .open_hash(&self.directory)
+            .await?;
+        let mut buf = vec![0_u8; self.chunk_size];
+        let mut size: usize = 0;
+        loop {
+            let read = reader.read(&mut buf).await.map_err(CacheError::io(key))?;
+            if read == 0 {
+                break;
+            }
+            fd.write_all(&buf[..read])
                 .await
-                .expect("Should be able to write to file");
+                .map_err(CacheError::io(key))?;
+            size += read;
+        }
+        let integrity = fd.commit().await?;
+        // indexed once written, since the size is only known then. The time of the index
+        // entry is refreshed when the entry is read, see `touch`
+        let metadata = json!({
+            "path": original_path.map(|p| p.to_string_lossy()),
+            "created_at": now_millis(),
+        });
+        let opts = cacache::WriteOpts::new()
+            .integrity(integrity)
+            .size(size)
+            .metadata(metadata);
+        cacache::index::insert_async(Path::new(&self.directory), &self.key(key), opts).await?;
+        if let Some(max_size_bytes) = self.options.max_size_bytes {
+            self.evict_to_size(max_size_bytes).await?;
+        }
+        Ok(size as u64)
+    }
+
+    /// Read an entry. With `ttl`, expired entries are removed and reported as
+    /// `CacheError::Expired`, and so are the corrupted ones as `CacheError::Corrupted`.
+    pub async fn read_bytes(&self, key: &str) -> Result<Vec<u8>, CacheError> {
+        let mut content: Vec<u8> = vec![];
+        self.read_stream(key, &mut content).await?;
+        match &self.encryption_key {
+            Some(encryption_key) => {
+                decrypt(encryption_key, &content).ok_or_else(|| CacheError::Decryption {
+                    key: key.to_string(),
+                })
+            }
+            None => Ok(content),
         }
-        fd.commit().await?;
-        Ok(())
     }
 
-    pub async fn read_file_content(&self, file_path: &str) -> cacache::Result<String> {
-        let mut fd = cacache::Reader::open(&self.directory, file_path).await?;
-        let mut buf = String::new();
-        fd.read_to_string(&mut buf)
+    /// Read an entry to a writer through a buffer of `chunk_size` bytes, returning its size,
+    /// see `read_bytes`. The integrity of the content is checked once it is all written: on
+    /// failure, the writer received corrupted content. Encrypted entries are buffered whole.
+    pub async fn read_to_writer(
+        &self,
+        key: &str,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<u64, CacheError> {
+        if self.encryption_key.is_none() {
+            return self.read_stream(key, writer).await;
+        }
+        let content = self.read_bytes(key).await?;
+        writer
+            .write_all(&content)
             .await
-            .expect("Should be able to read from file");
+            .map_err(CacheError::io(key))?;
+        writer.flush().await.map_err(CacheError::io(key))?;
+        Ok(content.len() as u64)
+    }
+
+    async fn read_stream(
+        &self,
+        key: &str,
+        writer: impl AsyncWrite + Unpin,
+    ) -> Result<u64, CacheError> {
+        self.check_chunk_size()?;
+        let Some(entry) = cacache::metadata(&self.directory, self.key(key)).await? else {
+            return Err(cacache::Error::EntryNotFound(
+                Path::new(&self.directory).to_path_buf(),
+                self.key(key),
+            )
+            .into());
+        };
+        if let Some(ttl) = self.options.ttl
+            && created_at(&entry) + ttl.as_millis() < now_millis()
+        {
+            self.remove_entries(vec![entry]).await?;
+            return Err(CacheError::Expired {
+                key: key.to_string(),
+            });
+        }
+        if self.options.max_size_bytes.is_some() {
+            self.touch(&entry).await?;
+        }
+        match self.copy_content(key, &entry, writer).await {
+            Err(CacheError::Cacache(error)) if is_corruption(&error) => {
+                warn!(
+                    event = "CacheCorruption",
+                    key, "Removing the corrupted cache entry {}: {}", key, error
+                );
+                self.remove_entries(vec![entry]).await?;
+                Err(CacheError::Corrupted {
+                    key: key.to_string(),
+                })
+            }
+            result => result,
+        }
+    }
+
+    /// Copy the content of an entry to a writer, checking its integrity once it is all written
+    async fn copy_content(
+        &self,
+        key: &str,
+        entry: &cacache::Metadata,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<u64, CacheError> {
+        let mut fd = cacache::Reader::open_hash(&self.directory, entry.integrity.clone()).await?;
+        let mut buf = vec![0_u8; self.chunk_size];
+        let mut size: u64 = 0;
+        loop {
+            let read = fd.read(&mut buf).await.map_err(CacheError::io(key))?;
+            if read == 0 {
+                break;
+            }
+            writer
+                .write_all(&buf[..read])
+                .await
+                .map_err(CacheError::io(key))?;
+            size += read as u64;
+        }
+        writer.flush().await.map_err(CacheError::io(key))?;
         fd.check()?;
-        Ok(buf)
+        if size != entry.size as u64 {
+            return Err(cacache::Error::SizeMismatch(entry.size, size as usize).into());
+        }
+        Ok(size)
+    }
+
+    /// Check the integrity of the content of the entries of the namespace (of the whole cache
+    /// without a namespace), removing the corrupted ones along with their content
+    pub async fn verify(&self) -> Result<VerifyReport, CacheError> {
+        self.check_chunk_size()?;
+        let entries = self.index_entries(&self.key(""))?;
+        let mut report = VerifyReport {
+            checked: entries.len(),
+            ..Default::default()
+        };
+        let mut corrupted: Vec<cacache::Metadata> = vec![];
+        // the entries sharing their content are all corrupted with it
+        let mut reclaimed: HashSet<String> = HashSet::new();
+        for entry in entries {
+            match self
+                .copy_content(&entry.key, &entry, tokio::io::sink())
+                .await
+            {
+                Ok(_) => continue,
+                Err(CacheError::Cacache(error)) if is_corruption(&error) => {
+                    debug!(
+                        event = "CacheCorruption",
+                        "The cache entry {} is corrupted: {}", entry.key, error
+                    );
+                }
+                Err(error) => return Err(error),
+            }
+            if reclaimed.insert(entry.integrity.to_string())
+                && cacache::exists(&self.directory, &entry.integrity).await
+            {
+                report.bytes_reclaimed += entry.size as u64;
+            }
+            corrupted.push(entry);
+        }
+        report.corrupted_removed = self.remove_entries(corrupted).await?;
+        Ok(report)
+    }
+
+    fn check_chunk_size(&self) -> Result<(), CacheError> {
+        if self.chunk_size == 0 {
+            return Err(CacheError::InvalidChunkSize);
+        }
+        Ok(())
+    }
+
+    /// Write a typed value (e.g. chunks along with their embeddings), serialized with bincode
+    /// straight into the entry through a pipe of `chunk_size` bytes, see `write_from_reader`.
+    /// The value is serialized on a blocking thread, hence the `Arc`.
+    pub async fn write_serialized<T: Serialize + Send + Sync + 'static>(
+        &self,
+        key: &str,
+        value: Arc<T>,
+        original_path: Option<&Path>,
+    ) -> Result<(), CacheError> {
+        self.check_chunk_size()?;
+        let (writer, reader) = tokio::io::duplex(self.chunk_size);
+        let mut writer =
+            std::io::BufWriter::with_capacity(self.chunk_size, SyncIoBridge::new(writer));
+        // the reader gets to the end of the entry once the writer is dropped
+        let encoding = tokio::task::spawn_blocking(move || {
+            bincode::serde::encode_into_std_write(
+                &*value,
+                &mut writer,
+                bincode::config::standard(),
+            )?;
+            writer
+                .flush()
+                .map_err(|e| bincode::error::EncodeError::Io { inner: e, index: 0 })
+        });
+        let written = self.write_from_reader(key, reader, original_path).await;
+        let encoded = encoding
+            .await
+            .expect("Should be able to join the serialization task");
+        written?;
+        if let Err(source) = encoded {
+            // the entry holds the value up to the error
+            self.remove(key).await?;
+            return Err(CacheError::Serialization {
+                key: key.to_string(),
+                source,
+            });
+        }
+        Ok(())
+    }
+
+    /// Read a typed value written by `write_serialized`, deserialized straight from the entry
+    /// through a pipe of `chunk_size` bytes, see `read_to_writer`
+    pub async fn read_deserialized<T: DeserializeOwned + Send + 'static>(
+        &self,
+        key: &str,
+    ) -> Result<T, CacheError> {
+        self.check_chunk_size()?;
+        let (writer, reader) = tokio::io::duplex(self.chunk_size);
+        let mut reader =
+            std::io::BufReader::with_capacity(self.chunk_size, SyncIoBridge::new(reader));
+        let decoding = tokio::task::spawn_blocking(move || {
+            bincode::serde::decode_from_std_read::<T, _, _>(
+                &mut reader,
+                bincode::config::standard(),
+            )
+        });
+        let read = self.read_to_writer(key, writer).await;
+        let decoded = decoding
+            .await
+            .expect("Should be able to join the deserialization task");
+        match (read, decoded) {
+            (Ok(_), Ok(value)) => Ok(value),
+            // the pipe is closed as soon as the deserialization fails
+            (Ok(_) | Err(CacheError::Io { .. }), Err(source)) => Err(CacheError::Deserialization {
+                key: key.to_string(),
+                source,
+            }),
+            (Err(error), _) => Err(error),
+        }
+    }
+
+    /// Remove an entry, returning whether it existed. Its content is removed as well, unless
+    /// another entry shares it
+    pub async fn remove(&self, key: &str) -> cacache::Result<bool> {
+        if !Path::new(&self.directory).exists() {
+            return Ok(false);
+        }
+        match cacache::metadata(&self.directory, self.key(key)).await? {
+            Some(entry) => Ok(self.remove_entries(vec![entry]).await? == 1),
+            None => Ok(false),
+        }
+    }
+
+    /// Remove all the entries of the namespace, returning how many were removed. Without a
+    /// namespace, the whole cache directory is cleared.
+    pub async fn clear(&self) -> cacache::Result<usize> {
+        let entries = self.index_entries(&self.key(""))?;
+        if !self.namespace.is_empty() {
+            return self.remove_entries(entries).await;
+        }
+        if Path::new(&self.directory).exists() {
+            cacache::clear(&self.directory).await?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Remove the entries of the namespace written more than `age` ago, returning how many
+    /// were removed
+    pub async fn clear_older_than(&self, age: Duration) -> cacache::Result<usize> {
+        let cutoff = now_millis().saturating_sub(age.as_millis());
+        let old: Vec<cacache::Metadata> = self
+            .index_entries(&self.key(""))?
+            .into_iter()
+            .filter(|e| created_at(e) <= cutoff)
+            .collect();
+        self.remove_entries(old).await
+    }
+
+    /// Evict the least recently used entries of the whole cache directory (whatever their
+    /// namespace) until their content fits in `max_size_bytes`, returning how many were evicted
+    pub async fn evict_to_size(&self, max_size_bytes: u64) -> cacache::Result<usize> {
+        let mut entries = self.index_entries("")?;
+        // the entries sharing their content only take its size once
+        let mut contents: HashMap<String, (u64, usize)> = HashMap::new();
+        for entry in &entries {
+            contents
+                .entry(entry.integrity.to_string())
+                .or_insert((entry.size as u64, 0))
+                .1 += 1;
+        }
+        let mut size: u64 = contents.values().map(|(s, _)| s).sum();
+        entries.sort_by_key(|e| e.time);
+        let mut evicted: Vec<cacache::Metadata> = vec![];
+        for entry in entries {
+            if size <= max_size_bytes {
+                break;
+            }
+            let (content_size, references) = contents
+                .get_mut(&entry.integrity.to_string())
+                .expect("Should have counted the content of every entry");
+            *references -= 1;
+            if *references == 0 {
+                size -= *content_size;
+            }
+            evicted.push(entry);
+        }
+        if !evicted.is_empty() {
+            debug!(
+                event = "CacheEviction",
+                "Evicting {} cache entries to fit in {} bytes",
+                evicted.len(),
+                max_size_bytes
+            );
+        }
+        self.remove_entries(evicted).await
+    }
+
+    /// Mark an entry as used now, for the eviction of the least recently used entries
+    async fn touch(&self, entry: &cacache::Metadata) -> cacache::Result<()> {
+        let opts = cacache::WriteOpts::new()
+            .integrity(entry.integrity.clone())
+            .size(entry.size)
+            .metadata(entry.metadata.clone());
+        cacache::index::insert_async(Path::new(&self.directory), &entry.key, opts).await?;
+        Ok(())
+    }
+
+    /// The entries of the namespace (of the whole cache without a namespace), sorted by key
+    pub fn list(&self) -> cacache::Result<Vec<CacheEntryInfo>> {
+        let prefix = self.key("");
+        let mut entries: Vec<CacheEntryInfo> = self
+            .index_entries(&prefix)?
+            .into_iter()
+            .map(|entry| CacheEntryInfo {
+                key: entry.key[prefix.len()..].to_string(),
+                size: entry.size,
+                created_at: created_at(&entry),
+                original_path: entry.metadata["path"].as_str().map(String::from),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(entries)
+    }
+
+    fn index_entries(&self, prefix: &str) -> cacache::Result<Vec<cacache::Metadata>> {
+        // nothing was cached yet
+        if !Path::new(&self.directory).join(INDEX_DIRECTORY).exists() {
+            return Ok(vec![]);
+        }
+        let mut entries: Vec<cacache::Metadata> = vec![];
+        for entry in cacache::list_sync(&self.directory) {
+            let entry = entry?;
+            if entry.key.starts_with(prefix) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Remove the entries, along with the content that no remaining entry shares
+    async fn remove_entries(&self, entries: Vec<cacache::Metadata>) -> cacache::Result<usize> {
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        for entry in &entries {
+            cacache::remove(&self.directory, &entry.key).await?;
+        }
+        let mut kept: HashSet<String> = HashSet::new();
+        for entry in cacache::list_sync(&self.directory) {
+            kept.insert(entry?.integrity.to_string());
+        }
+        for entry in &entries {
+            // inserted once removed, since removed entries may share their content too. The
+            // content of corrupted entries may be missing already
+            if kept.insert(entry.integrity.to_string())
+                && cacache::exists(&self.directory, &entry.integrity).await
+            {
+                cacache::remove_hash(&self.directory, &entry.integrity).await?;
+            }
+        }
+        Ok(entries.len())
+    }
+
+    /// Keys of all the entries in the cache directory, whatever their namespace,
+    /// that start with the prefix (e.g. `collection-1024/`)
+    pub fn list_entries_by_prefix(&self, prefix: &str) -> cacache::Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .index_entries(prefix)?
+            .into_iter()
+            .map(|e| e.key)
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Remove the entries whose keys start with the prefix, whatever their namespace, along
+    /// with the content no other entry shares. Returns how many were removed.
+    pub async fn evict_namespace(&self, prefix: &str) -> cacache::Result<usize> {
+        let entries = self.index_entries(prefix)?;
+        self.remove_entries(entries).await
+    }
+}
+
+/// Whether the content of an entry is missing, or does not match its integrity hash or size
+fn is_corruption(error: &cacache::Error) -> bool {
+    match error {
+        cacache::Error::IntegrityError(_) | cacache::Error::SizeMismatch(..) => true,
+        cacache::Error::IoError(source, _) => source.kind() == std::io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// When the entry was written: the time of its index entry is refreshed when it is used
+fn created_at(entry: &cacache::Metadata) -> u128 {
+    entry.metadata["created_at"]
+        .as_u64()
+        .map(u128::from)
+        .unwrap_or(entry.time)
+}
+
+/// Parse a size such as `500MB`, in bytes (`B`, or no unit), `KB`, `MB` or `GB` (of 1024
+/// bytes, 1024 KB and 1024 MB)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "'{}' should be a number of bytes, optionally followed by a unit: B, KB, MB or GB (e.g. '500MB')",
+            value
+        )
+    };
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let amount: u64 = value[..unit_start].parse().map_err(|_| invalid())?;
+    let unit = match value[unit_start..].to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1024,
+        "MB" => 1024 * 1024,
+        "GB" => 1024 * 1024 * 1024,
+        _ => return Err(invalid()),
+    };
+    amount.checked_mul(unit).ok_or_else(invalid)
+}
+
+/// Derive a 256-bit key from a passphrase, with PBKDF2-HMAC-SHA256
+pub fn derive_encryption_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    pbkdf2::pbkdf2_hmac_array::<sha2::Sha256, 32>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS)
+}
+
+fn parse_hex_key(value: &str) -> Option<[u8; 32]> {
+    if value.len() != 64 || !value.is_ascii() {
+        return None;
+    }
+    let mut key = [0_u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+/// The random nonce, followed by the ciphertext and its authentication tag
+fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(key.into());
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .expect("Should be able to encrypt the content");
+    [nonce.as_slice(), &ciphertext].concat()
+}
+
+fn decrypt(key: &[u8; 32], encrypted: &[u8]) -> Option<Vec<u8>> {
+    if encrypted.len() < NONCE_SIZE {
+        return None;
+    }
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+    Aes256Gcm::new(key.into())
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()
+}
+
+/// Parse an age such as `30d`, in seconds (`s`), minutes (`m`), hours (`h`), days (`d`)
+/// or weeks (`w`)
+pub fn parse_age(value: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "'{}' should be a number followed by a unit: s, m, h, d or w (e.g. '30d')",
+            value
+        )
+    };
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let amount: u64 = value[..unit_start].parse().map_err(|_| invalid())?;
+    let unit_secs = match &value[unit_start..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(amount * unit_secs))
+}
+
+/// Age of an entry written at `created_at` (in milliseconds since the Unix epoch), see
+/// `format_duration`
+pub fn format_age(created_at: u128) -> String {
+    let age = now_millis().saturating_sub(created_at) / 1000;
+    format_duration(Duration::from_secs(age as u64))
+}
+
+/// A duration in its largest unit, rounded down, e.g. `3d`
+pub fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        s if s >= 24 * 60 * 60 => format!("{}d", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{chunking::Chunk, parsing::ParsedDocument};
+    use bm25::{Embedding, TokenEmbedding};
 
     #[test]
     fn test_correct_cache_init() {
-        let cache = Cache::new(None, None);
+        let cache = Cache::new(None, None, None);
         assert_eq!(cache.chunk_size, DEFAULT_CHUNK_SIZE);
         assert_eq!(cache.directory, DEFAULT_CACHE_DIR);
-        let cache_1 = Cache::new(Some("data/cache".to_string()), Some(1024_usize));
+        assert_eq!(cache.namespace, "");
+        let cache_1 = Cache::new(
+            Some("data/cache".to_string()),
+            Some(1024_usize),
+            Some("collection-1024".to_string()),
+        );
         assert_eq!(cache_1.directory, "data/cache".to_string());
         assert_eq!(cache_1.chunk_size, 1024_usize);
+        assert_eq!(cache_1.namespace, "collection-1024".to_string());
     }
 
     #[tokio::test]
     async fn test_write_and_read_file() {
-        let cache = Cache::new(None, None);
+        let cache = Cache::new(None, None, None);
         let file_path = "test.txt";
         let file_content = "this is a test".to_string();
-        let res = cache.write_file_content(file_path, file_content).await;
+        let res = cache
+            .write_bytes(file_path, file_content.into_bytes(), None)
+            .await;
         assert!(res.is_ok());
-        let content = cache.read_file_content(file_path).await;
+        let content = cache.read_bytes(file_path).await;
         match content {
             Ok(buf) => {
-                assert_eq!(buf, "this is a test".to_string());
+                assert_eq!(buf, b"this is a test");
             }
             Err(e) => {
-                println!(
-                    "An error occurred while testing cache reading: {}",
-                    e.to_string()
-                );
-                assert!(false);
+                println!("An error occurred while testing cache reading: {}", e);
+                panic!();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_do_not_collide() {
+        let cache = Cache::new(None, None, Some("namespace-a".to_string()));
+        let cache_1 = Cache::new(None, None, Some("namespace-b".to_string()));
+        let file_path = "test-namespaced.txt";
+        let res = cache
+            .write_bytes(file_path, b"content a".to_vec(), None)
+            .await;
+        assert!(res.is_ok());
+        let res_1 = cache_1
+            .write_bytes(file_path, b"content b".to_vec(), None)
+            .await;
+        assert!(res_1.is_ok());
+        assert_eq!(cache.read_bytes(file_path).await.unwrap(), b"content a");
+        assert_eq!(cache_1.read_bytes(file_path).await.unwrap(), b"content b");
+        let unnamespaced = Cache::new(None, None, None);
+        assert!(unnamespaced.read_bytes(file_path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_content_keyed_entries() {
+        let directory = tempfile::tempdir().unwrap();
+        // nothing was cached yet
+        let cache = Cache::new(
+            Some(directory.path().join("cache").to_string_lossy().to_string()),
+            None,
+            Some("collection-1024".to_string()),
+        );
+        assert!(cache.list().unwrap().is_empty());
+        let key = Cache::content_key("pdf-pages", b"%PDF-1.7 first version");
+        assert_eq!(
+            key,
+            Cache::content_key("pdf-pages", b"%PDF-1.7 first version")
+        );
+        // the same path with another content has another key
+        let edited = Cache::content_key("pdf-pages", b"%PDF-1.7 second version");
+        assert_ne!(key, edited);
+        assert!(!is_listed(&cache, &key));
+        cache
+            .write_bytes(&key, b"pages".to_vec(), Some(Path::new("docs/a.pdf")))
+            .await
+            .unwrap();
+        assert!(is_listed(&cache, &key));
+        assert!(!is_listed(&cache, &edited));
+        let entries = cache.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, key);
+        assert_eq!(entries[0].size, "pages".len());
+        assert_eq!(entries[0].original_path, Some("docs/a.pdf".to_string()));
+        assert!(entries[0].created_at > 0);
+        // the entries of other namespaces are not listed, unless listing the whole cache
+        let other = Cache::new(Some(cache.directory.clone()), None, None);
+        other
+            .write_bytes("b.txt", b"other".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.list().unwrap().len(), 1);
+        let all = other.list().unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].key, "b.txt");
+        assert_eq!(all[0].original_path, None);
+        assert_eq!(all[1].key, format!("collection-1024/{}", key));
+        assert!(cache.remove(&key).await.unwrap());
+        assert!(!cache.remove(&key).await.unwrap());
+        assert!(!is_listed(&cache, &key));
+        assert!(cache.list().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clear() {
+        let temp_directory = tempfile::tempdir().unwrap();
+        let directory = temp_directory.path().to_string_lossy().to_string();
+        let cache = Cache::new(Some(directory.clone()), None, Some("cleared".to_string()));
+        let kept = Cache::new(Some(directory.clone()), None, Some("kept".to_string()));
+        assert_eq!(cache.clear().await.unwrap(), 0);
+        for key in ["a.txt", "b.txt"] {
+            cache
+                .write_bytes(key, format!("content of {}", key).into_bytes(), None)
+                .await
+                .unwrap();
+        }
+        // shares its content with a removed entry
+        kept.write_bytes("a.txt", b"content of a.txt".to_vec(), None)
+            .await
+            .unwrap();
+        let removed = cacache::metadata(&directory, "cleared/b.txt")
+            .await
+            .unwrap()
+            .unwrap()
+            .integrity;
+        assert_eq!(
+            cache
+                .clear_older_than(Duration::from_secs(3600))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(cache.clear_older_than(Duration::ZERO).await.unwrap(), 2);
+        assert!(cache.list().unwrap().is_empty());
+        assert_eq!(kept.read_bytes("a.txt").await.unwrap(), b"content of a.txt");
+        // the content of the other entry was removed from the disk
+        assert!(!cacache::exists(&directory, &removed).await);
+        let whole = Cache::new(Some(directory.clone()), None, None);
+        assert_eq!(whole.clear().await.unwrap(), 1);
+        assert!(whole.list().unwrap().is_empty());
+        assert!(kept.read_bytes("a.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_binary_entries() {
+        let temp_directory = tempfile::tempdir().unwrap();
+        let cache = Cache::new(
+            Some(temp_directory.path().to_string_lossy().to_string()),
+            Some(3),
+            None,
+        );
+        // not valid UTF-8
+        let bytes = vec![0xff, 0x00, 0xfe, 0x80, 0x7f];
+        cache
+            .write_bytes("binary", bytes.clone(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.read_bytes("binary").await.unwrap(), bytes);
+        cache
+            .write_bytes("text", b"content".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.read_bytes("text").await.unwrap(), b"content");
+        let chunks = vec![
+            Chunk {
+                sparse_embedding: Some(Embedding(vec![
+                    TokenEmbedding {
+                        index: 3,
+                        value: 0.5,
+                    },
+                    TokenEmbedding {
+                        index: 42,
+                        value: 1.25,
+                    },
+                ])),
+                source: Some("report.pdf".to_string()),
+                chunk_index: Some(0),
+                span: Some(0..16),
+                ..Chunk::from_content("First chunk text".to_string())
+            },
+            Chunk {
+                dense_embedding: Some(vec![0.1, -0.2, 0.3]),
+                ..Chunk::from_content("Second chunk".to_string())
+            },
+        ];
+        let chunks = Arc::new(chunks);
+        cache
+            .write_serialized("chunks", chunks.clone(), Some(Path::new("report.pdf")))
+            .await
+            .unwrap();
+        let read: Vec<Chunk> = cache.read_deserialized("chunks").await.unwrap();
+        assert_eq!(read, *chunks);
+        let document = ParsedDocument::from_pages(
+            "report.pdf".to_string(),
+            vec!["Page 1".to_string(), "Page 2".to_string()],
+        );
+        cache
+            .write_serialized("document", Arc::new(document.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            cache
+                .read_deserialized::<ParsedDocument>("document")
+                .await
+                .unwrap(),
+            document
+        );
+        assert!(matches!(
+            cache.read_deserialized::<Vec<Chunk>>("binary").await,
+            Err(CacheError::Deserialization { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_entries() {
+        let temp_directory = tempfile::tempdir().unwrap();
+        let mut cache = Cache::new(
+            Some(temp_directory.path().to_string_lossy().to_string()),
+            Some(64),
+            None,
+        );
+        // many times the chunk size, and not a multiple of it
+        let content: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let size = cache
+            .write_from_reader("large", content.as_slice(), Some(Path::new("large.bin")))
+            .await
+            .unwrap();
+        assert_eq!(size, content.len() as u64);
+        let entries = cache.list().unwrap();
+        assert_eq!(entries[0].size, content.len());
+        assert_eq!(entries[0].original_path, Some("large.bin".to_string()));
+        let mut read: Vec<u8> = vec![];
+        assert_eq!(
+            cache.read_to_writer("large", &mut read).await.unwrap(),
+            content.len() as u64
+        );
+        assert_eq!(read, content);
+        assert_eq!(cache.read_bytes("large").await.unwrap(), content);
+        let mut empty: Vec<u8> = vec![];
+        cache
+            .write_from_reader("empty", b"".as_slice(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.read_to_writer("empty", &mut empty).await.unwrap(), 0);
+        // encrypted entries are streamed too
+        cache.encryption_key = Some([1_u8; 32]);
+        cache
+            .write_from_reader("encrypted", content.as_slice(), None)
+            .await
+            .unwrap();
+        let mut read: Vec<u8> = vec![];
+        cache.read_to_writer("encrypted", &mut read).await.unwrap();
+        assert_eq!(read, content);
+        cache.chunk_size = 0;
+        assert!(matches!(
+            cache.read_bytes("large").await,
+            Err(CacheError::InvalidChunkSize)
+        ));
+        assert!(matches!(
+            cache.write_bytes("zero", vec![1], None).await,
+            Err(CacheError::InvalidChunkSize)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let temp_directory = tempfile::tempdir().unwrap();
+        let mut cache = Cache::new(
+            Some(temp_directory.path().to_string_lossy().to_string()),
+            None,
+            None,
+        );
+        cache.options.ttl = Some(Duration::from_millis(200));
+        // a miss, not an expiration
+        assert!(matches!(
+            cache.read_bytes("a.txt").await,
+            Err(CacheError::Cacache(_))
+        ));
+        cache
+            .write_bytes("a.txt", b"content".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.read_bytes("a.txt").await.unwrap(), b"content");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(matches!(
+            cache.read_bytes("a.txt").await,
+            Err(CacheError::Expired { .. })
+        ));
+        // the expired entry was removed
+        assert!(!is_listed(&cache, "a.txt"));
+        assert!(cache.list().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_evict_least_recently_used() {
+        let temp_directory = tempfile::tempdir().unwrap();
+        let mut cache = Cache::new(
+            Some(temp_directory.path().to_string_lossy().to_string()),
+            None,
+            None,
+        );
+        // room for three entries of 9 bytes
+        cache.options.max_size_bytes = Some(30);
+        let keys = |cache: &Cache| -> Vec<String> {
+            cache.list().unwrap().into_iter().map(|e| e.key).collect()
+        };
+        for key in ["a", "b", "c"] {
+            cache
+                .write_bytes(key, format!("content {}", key).into_bytes(), None)
+                .await
+                .unwrap();
+            // the timestamps of the index have a millisecond resolution
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(keys(&cache), vec!["a", "b", "c"]);
+        // reading a makes b the least recently used entry
+        cache.read_bytes("a").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        cache
+            .write_bytes("d", b"content d".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(keys(&cache), vec!["a", "c", "d"]);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        // entries sharing their content take its size once
+        cache
+            .write_bytes("e", b"content d".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(keys(&cache), vec!["a", "c", "d", "e"]);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        cache
+            .write_bytes("f", b"content of entry f".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(keys(&cache), vec!["d", "e", "f"]);
+        assert_eq!(cache.evict_to_size(0).await.unwrap(), 3);
+        assert!(keys(&cache).is_empty());
+    }
+
+    // see `cacache::content::path::content_path`
+    fn is_listed(cache: &Cache, key: &str) -> bool {
+        cache.list().unwrap().iter().any(|e| e.key == key)
+    }
+
+    async fn content_path(directory: &str, key: &str) -> std::path::PathBuf {
+        let entry = cacache::metadata(directory, key).await.unwrap().unwrap();
+        let (algorithm, hex) = entry.integrity.to_hex();
+        Path::new(directory)
+            .join("content-v2")
+            .join(algorithm.to_string())
+            .join(&hex[0..2])
+            .join(&hex[2..4])
+            .join(&hex[4..])
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let temp_directory = tempfile::tempdir().unwrap();
+        let directory = temp_directory.path().to_string_lossy().to_string();
+        let cache = Cache::new(Some(directory.clone()), Some(4), None);
+        assert_eq!(cache.verify().await.unwrap(), VerifyReport::default());
+        for key in ["intact", "flipped", "missing"] {
+            cache
+                .write_bytes(key, format!("content of {}", key).into_bytes(), None)
+                .await
+                .unwrap();
+        }
+        let flipped = content_path(&directory, "flipped").await;
+        let mut content = std::fs::read(&flipped).unwrap();
+        content[0] ^= 1;
+        std::fs::write(&flipped, content).unwrap();
+        std::fs::remove_file(content_path(&directory, "missing").await).unwrap();
+        let report = cache.verify().await.unwrap();
+        assert_eq!(
+            report,
+            VerifyReport {
+                checked: 3,
+                corrupted_removed: 2,
+                bytes_reclaimed: "content of flipped".len() as u64,
             }
+        );
+        assert!(!flipped.exists());
+        let keys: Vec<String> = cache.list().unwrap().into_iter().map(|e| e.key).collect();
+        assert_eq!(keys, vec!["intact"]);
+        assert_eq!(cache.verify().await.unwrap().corrupted_removed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_entries_are_misses() {
+        let temp_directory = tempfile::tempdir().unwrap();
+        let directory = temp_directory.path().to_string_lossy().to_string();
+        let cache = Cache::new(Some(directory.clone()), None, None);
+        cache
+            .write_bytes("pages", b"[\"page\"]".to_vec(), None)
+            .await
+            .unwrap();
+        let path = content_path(&directory, "pages").await;
+        std::fs::write(&path, b"[\"edit\"]").unwrap();
+        let err = cache.read_bytes("pages").await.unwrap_err();
+        assert!(matches!(err, CacheError::Corrupted { .. }));
+        // the corrupted entry was removed, and can be written again
+        assert!(!is_listed(&cache, "pages"));
+        assert!(!path.exists());
+        cache
+            .write_bytes("pages", b"[\"page\"]".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.read_bytes("pages").await.unwrap(), b"[\"page\"]");
+        // truncated content
+        std::fs::write(&path, b"").unwrap();
+        let mut written: Vec<u8> = vec![];
+        let err = cache
+            .read_to_writer("pages", &mut written)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CacheError::Corrupted { .. }));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("10B"), Ok(10));
+        assert_eq!(parse_size("500MB"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_size("2gb"), Ok(2 * 1024 * 1024 * 1024));
+        for invalid in ["", "MB", "1.5GB", "10TB", "-1"] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
         }
     }
+
+    #[test]
+    fn test_parse_and_format_age() {
+        assert_eq!(parse_age("30d"), Ok(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("2w"), Ok(Duration::from_secs(14 * 24 * 60 * 60)));
+        for invalid in ["30", "d", "", "1.5h", "10y", "-1d"] {
+            assert!(parse_age(invalid).is_err(), "{}", invalid);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        assert_eq!(format_duration(parse_age("30d").unwrap()), "30d");
+        assert_eq!(format_duration(Duration::from_secs(90)), "1m");
+        assert_eq!(format_age(now), "0s");
+        assert_eq!(format_age(now - 2 * 60 * 60 * 1000 - 5000), "2h");
+        assert_eq!(format_age(now - 3 * 24 * 60 * 60 * 1000), "3d");
+        // written in the future, e.g. by a machine with another clock
+        assert_eq!(format_age(now + 60 * 1000), "0s");
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_entries() {
+        let temp_directory = tempfile::tempdir().unwrap();
+        let directory = temp_directory.path().to_string_lossy().to_string();
+        let mut cache = Cache::new(Some(directory.clone()), Some(4), None);
+        cache.encryption_key = Some([7_u8; 32]);
+        cache
+            .write_bytes("secret.txt", b"confidential content".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.read_bytes("secret.txt").await.unwrap(),
+            b"confidential content"
+        );
+        // the content is not stored in clear
+        let stored = cacache::read(&directory, "secret.txt").await.unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains("confidential"));
+        let unencrypted = Cache::new(Some(directory.clone()), None, None);
+        // the nonce and the ciphertext are read as they are
+        assert_ne!(
+            unencrypted.read_bytes("secret.txt").await.unwrap(),
+            b"confidential content"
+        );
+        let mut other_key = Cache::new(Some(directory.clone()), None, None);
+        other_key.encryption_key = Some([8_u8; 32]);
+        assert!(matches!(
+            other_key.read_bytes("secret.txt").await,
+            Err(CacheError::Decryption { .. })
+        ));
+        // the entries written before the encryption was enabled cannot be read either
+        unencrypted
+            .write_bytes("clear.txt", b"content".to_vec(), None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            cache.read_bytes("clear.txt").await,
+            Err(CacheError::Decryption { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_load_encryption_key() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = Cache::new(
+            Some(directory.path().join("cache").to_string_lossy().to_string()),
+            None,
+            None,
+        );
+        let key_file = directory.path().join("key");
+        std::fs::write(&key_file, format!("{}\n", "0a".repeat(32))).unwrap();
+        assert_eq!(
+            cache.load_encryption_key(&key_file).await.unwrap(),
+            [10_u8; 32]
+        );
+        std::fs::write(&key_file, "correct horse battery staple\n").unwrap();
+        let key = cache.load_encryption_key(&key_file).await.unwrap();
+        // derived with the same salt, stored in the cache directory
+        assert_eq!(cache.load_encryption_key(&key_file).await.unwrap(), key);
+        let salt = std::fs::read(Path::new(&cache.directory).join(ENCRYPTION_SALT_FILE)).unwrap();
+        assert_eq!(
+            key,
+            derive_encryption_key("correct horse battery staple", &salt)
+        );
+        assert_ne!(
+            key,
+            derive_encryption_key("correct horse battery staple", b"other salt")
+        );
+        std::fs::write(&key_file, " \n").unwrap();
+        assert!(cache.load_encryption_key(&key_file).await.is_err());
+        assert!(
+            cache
+                .load_encryption_key(&directory.path().join("missing"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_evict_namespace() {
+        let directory = "./.rag-rs-cache-evict".to_string();
+        // left behind by a failed run
+        let _ = std::fs::remove_dir_all(&directory);
+        let cache = Cache::new(Some(directory.clone()), None, Some("evicted".to_string()));
+        let kept = Cache::new(Some(directory.clone()), None, Some("kept".to_string()));
+        assert!(cache.list_entries_by_prefix("evicted/").unwrap().is_empty());
+        for file_path in ["a.txt", "b.txt"] {
+            cache
+                .write_bytes(file_path, b"content".to_vec(), None)
+                .await
+                .unwrap();
+        }
+        kept.write_bytes("a.txt", b"content".to_vec(), None)
+            .await
+            .unwrap();
+        cache
+            .write_bytes("c.txt", b"unshared content".to_vec(), None)
+            .await
+            .unwrap();
+        let unshared = cacache::metadata(&directory, "evicted/c.txt")
+            .await
+            .unwrap()
+            .unwrap()
+            .integrity;
+        assert_eq!(
+            cache.list_entries_by_prefix("evicted/").unwrap(),
+            vec![
+                "evicted/a.txt".to_string(),
+                "evicted/b.txt".to_string(),
+                "evicted/c.txt".to_string()
+            ]
+        );
+        assert_eq!(cache.evict_namespace("evicted/").await.unwrap(), 3);
+        // the content of the other namespace is kept, the unshared one is removed
+        assert!(!cacache::exists(&directory, &unshared).await);
+        assert!(cache.read_bytes("a.txt").await.is_err());
+        assert!(cache.list_entries_by_prefix("evicted/").unwrap().is_empty());
+        assert_eq!(kept.read_bytes("a.txt").await.unwrap(), b"content");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 }
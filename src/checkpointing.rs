@@ -0,0 +1,302 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+
+pub const CHECKPOINT_VERSION: u32 = 1;
+pub const COMPLETION_LOG_FILE: &str = ".rag-rs-checkpoint.jsonl";
+const DONE_STATUS: &str = "done";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Parsed,
+    Embedded,
+    Uploaded,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DocumentProgress {
+    pub content_hash: String,
+    pub stage: Stage,
+}
+
+/// Progress of the `Load` runs for one collection, stored as versioned JSON in the cache directory
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Checkpoint {
+    pub version: u32,
+    pub collection_name: String,
+    pub documents: HashMap<String, DocumentProgress>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new(cache_directory: &str, collection_name: &str) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            collection_name: collection_name.to_string(),
+            documents: HashMap::new(),
+            path: PathBuf::from(cache_directory)
+                .join("checkpoints")
+                .join(format!("{}.json", collection_name)),
+        }
+    }
+
+    /// Load the checkpoint for the collection, starting from an empty one if it does not exist
+    /// or if it was written by an incompatible version.
+    pub async fn load(cache_directory: &str, collection_name: &str) -> anyhow::Result<Self> {
+        let mut checkpoint = Self::new(cache_directory, collection_name);
+        let content = match fs::read_to_string(&checkpoint.path).await {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(checkpoint),
+            Err(e) => return Err(e.into()),
+        };
+        let stored: serde_json::Value = serde_json::from_str(&content)?;
+        let version = stored.get("version").and_then(|v| v.as_u64());
+        if version != Some(CHECKPOINT_VERSION as u64) {
+            eprintln!(
+                "WARNING: checkpoint at {:?} has version {:?}, expected {}. Starting from scratch...",
+                checkpoint.path, version, CHECKPOINT_VERSION
+            );
+            return Ok(checkpoint);
+        }
+        let loaded: Checkpoint = serde_json::from_value(stored)?;
+        checkpoint.documents = loaded.documents;
+        Ok(checkpoint)
+    }
+
+    /// Whether the document with this exact content was already uploaded
+    pub fn is_uploaded(&self, document: &str, content_hash: &str) -> bool {
+        match self.documents.get(document) {
+            Some(p) => p.content_hash == content_hash && p.stage == Stage::Uploaded,
+            None => false,
+        }
+    }
+
+    /// Whether a previous run uploaded any document, e.g. before it was interrupted
+    pub fn has_uploads(&self) -> bool {
+        self.documents.values().any(|p| p.stage == Stage::Uploaded)
+    }
+
+    /// Record that the document reached `stage`, and persist the checkpoint
+    pub async fn mark(
+        &mut self,
+        document: &str,
+        content_hash: &str,
+        stage: Stage,
+    ) -> anyhow::Result<()> {
+        self.documents.insert(
+            document.to_string(),
+            DocumentProgress {
+                content_hash: content_hash.to_string(),
+                stage,
+            },
+        );
+        self.save().await
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // write to a temporary file first, so that a crash never leaves a truncated checkpoint
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?).await?;
+        fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct CompletionEntry {
+    source_file: String,
+    // missing from the entries of older versions, which are then never considered done
+    #[serde(default)]
+    content_hash: String,
+    status: String,
+}
+
+/// Append-only log of the files of a directory that were fully uploaded, stored in the
+/// directory itself. It lets an interrupted `Load` run over the same directory skip the
+/// completed files that were not edited since, and it is removed once a run completes
+/// successfully.
+#[derive(Debug)]
+pub struct CompletionLog {
+    path: PathBuf,
+    // content hash of the completed files, by source file
+    completed: HashMap<String, String>,
+}
+
+impl CompletionLog {
+    pub fn new(directory: &str) -> Self {
+        Self {
+            path: Path::new(directory).join(COMPLETION_LOG_FILE),
+            completed: HashMap::new(),
+        }
+    }
+
+    /// Read the completed files from the log in `directory`, if there is one
+    pub async fn load(directory: &str) -> anyhow::Result<Self> {
+        let mut log = Self::new(directory);
+        let content = match fs::read_to_string(&log.path).await {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(log),
+            Err(e) => return Err(e.into()),
+        };
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<CompletionEntry>(line) {
+                Ok(entry) if entry.status == DONE_STATUS => {
+                    log.completed.insert(entry.source_file, entry.content_hash);
+                }
+                Ok(_) => {}
+                // the last line can be truncated if the previous run was killed while writing it
+                Err(e) => eprintln!("WARNING: ignoring malformed line in {:?}: {}", log.path, e),
+            }
+        }
+        Ok(log)
+    }
+
+    /// Whether the file with this exact content was completed, see `Checkpoint::is_uploaded`
+    pub fn is_done(&self, source_file: &str, content_hash: &str) -> bool {
+        self.completed
+            .get(source_file)
+            .is_some_and(|h| h == content_hash)
+    }
+
+    pub async fn mark_done(&mut self, source_file: &str, content_hash: &str) -> anyhow::Result<()> {
+        let entry = CompletionEntry {
+            source_file: source_file.to_string(),
+            content_hash: content_hash.to_string(),
+            status: DONE_STATUS.to_string(),
+        };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(format!("{}\n", serde_json::to_string(&entry)?).as_bytes())
+            .await?;
+        file.flush().await?;
+        self.completed.insert(entry.source_file, entry.content_hash);
+        Ok(())
+    }
+
+    /// Delete the log, once every file was uploaded
+    pub async fn remove(self) -> anyhow::Result<()> {
+        match fs::remove_file(&self.path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+pub fn content_hash(content: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Sha256::digest(content.as_ref()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mark_and_load_checkpoint() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_path = cache_dir.path().to_str().unwrap();
+        let mut checkpoint = Checkpoint::load(cache_path, "test-collection")
+            .await
+            .unwrap();
+        assert!(checkpoint.documents.is_empty());
+        let hash = content_hash("hello world");
+        checkpoint
+            .mark("doc.txt", &hash, Stage::Parsed)
+            .await
+            .unwrap();
+        assert!(!checkpoint.is_uploaded("doc.txt", &hash));
+        assert!(!checkpoint.has_uploads());
+        checkpoint
+            .mark("doc.txt", &hash, Stage::Uploaded)
+            .await
+            .unwrap();
+        let loaded = Checkpoint::load(cache_path, "test-collection")
+            .await
+            .unwrap();
+        assert!(loaded.is_uploaded("doc.txt", &hash));
+        assert!(loaded.has_uploads());
+        // changed content is not considered uploaded
+        assert!(!loaded.is_uploaded("doc.txt", &content_hash("hello world!")));
+        // checkpoints are scoped to the collection
+        let other = Checkpoint::load(cache_path, "other-collection")
+            .await
+            .unwrap();
+        assert!(other.documents.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_checkpoint_version() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_path = cache_dir.path().to_str().unwrap();
+        let checkpoint_dir = cache_dir.path().join("checkpoints");
+        fs::create_dir_all(&checkpoint_dir).await.unwrap();
+        fs::write(
+            checkpoint_dir.join("test-collection.json"),
+            r#"{"version": 999, "documents": {"doc.txt": "uploaded"}}"#,
+        )
+        .await
+        .unwrap();
+        let checkpoint = Checkpoint::load(cache_path, "test-collection")
+            .await
+            .unwrap();
+        assert_eq!(checkpoint.version, CHECKPOINT_VERSION);
+        assert!(checkpoint.documents.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_completion_log() {
+        let directory = tempfile::tempdir().unwrap();
+        let directory_path = directory.path().to_str().unwrap();
+        let mut log = CompletionLog::load(directory_path).await.unwrap();
+        let (hash_a, hash_b) = (content_hash("a"), content_hash("b"));
+        assert!(!log.is_done("a.txt", &hash_a));
+        log.mark_done("a.txt", &hash_a).await.unwrap();
+        log.mark_done("b.pdf", &hash_b).await.unwrap();
+        let log_path = directory.path().join(COMPLETION_LOG_FILE);
+        let content = fs::read_to_string(&log_path).await.unwrap();
+        assert_eq!(
+            content.lines().next().unwrap(),
+            format!(
+                r#"{{"source_file":"a.txt","content_hash":"{}","status":"done"}}"#,
+                hash_a
+            )
+        );
+        // simulate a run killed while appending an entry
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .await
+            .unwrap();
+        file.write_all(br#"{"source_file":"c.m"#).await.unwrap();
+        let loaded = CompletionLog::load(directory_path).await.unwrap();
+        assert!(loaded.is_done("a.txt", &hash_a));
+        assert!(loaded.is_done("b.pdf", &hash_b));
+        assert!(!loaded.is_done("c.md", &content_hash("c")));
+        // a file edited since it was completed is uploaded again
+        assert!(!loaded.is_done("a.txt", &content_hash("a, edited")));
+        loaded.remove().await.unwrap();
+        assert!(!log_path.exists());
+        // removing a missing log is not an error
+        CompletionLog::new(directory_path).remove().await.unwrap();
+    }
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(content_hash("a"), content_hash("a"));
+        assert_ne!(content_hash("a"), content_hash("b"));
+        assert_eq!(content_hash("").len(), 64);
+    }
+}
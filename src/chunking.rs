@@ -1,33 +1,1032 @@
+use std::{collections::HashMap, fmt, ops::Range, str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use async_trait::async_trait;
 use bm25::Embedding;
 use memchunk::chunk;
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::cl100k_base_singleton;
+use tracing::debug;
+
+use crate::{
+    embedding::{Embedder, EmbeddingVector},
+    parsing::ParsedDocument,
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+const PARAGRAPH_SEPARATOR: &str = "\n\n";
+const HEADING_PATH_SEPARATOR: &str = " > ";
+/// Documents with fewer sentences are chunked by sentence instead of semantically
+const MIN_SEMANTIC_SENTENCES: usize = 5;
+/// Paragraphs, then lines, then sentences, then words
+const DEFAULT_SEPARATORS: &[&str] = &["\n\n", "\n", ". ", " "];
+const CHUNKING_CONFIG_METADATA_KEY: &str = "chunking_config";
+
+/// How the text of the documents is split into chunks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkStrategy {
+    /// Paragraphs grouped up to the chunk size, see `chunk_text`
+    #[default]
+    Bytes,
+    /// Whole sentences grouped up to the chunk size, see `chunk_sentences`
+    Sentence,
+    /// Markdown sections, prefixed with their heading path, see `chunk_markdown`
+    Markdown,
+    /// Split on the first separator of a hierarchy that makes the text fit, see `chunk_recursive`
+    Recursive,
+    /// Experimental: sentences grouped by topic, see `chunk_semantic`
+    Semantic,
+}
+
+impl FromStr for ChunkStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" => Ok(ChunkStrategy::Bytes),
+            "sentence" => Ok(ChunkStrategy::Sentence),
+            "markdown" => Ok(ChunkStrategy::Markdown),
+            "recursive" => Ok(ChunkStrategy::Recursive),
+            "semantic" => Ok(ChunkStrategy::Semantic),
+            other => Err(anyhow::anyhow!(
+                "Unsupported chunk strategy: {}. Supported chunk strategies are: bytes, sentence, markdown, recursive, semantic",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChunkStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkStrategy::Bytes => write!(f, "bytes"),
+            ChunkStrategy::Sentence => write!(f, "sentence"),
+            ChunkStrategy::Markdown => write!(f, "markdown"),
+            ChunkStrategy::Recursive => write!(f, "recursive"),
+            ChunkStrategy::Semantic => write!(f, "semantic"),
+        }
+    }
+}
+
+/// What the chunk size is measured in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkUnit {
+    #[default]
+    Bytes,
+    Chars,
+    /// Tokens of the `cl100k_base` encoding of the OpenAI models, to fit LLM context budgets
+    Tokens,
+}
+
+impl ChunkUnit {
+    /// Length of the text in this unit
+    pub fn measure(&self, text: &str) -> usize {
+        match self {
+            ChunkUnit::Bytes => text.len(),
+            ChunkUnit::Chars => text.chars().count(),
+            ChunkUnit::Tokens => cl100k_base_singleton().encode_ordinary(text).len(),
+        }
+    }
+
+    /// Length of the concatenation of the parts, without building it when it is not needed
+    fn measure_joined(&self, parts: &[&str]) -> usize {
+        match self {
+            // the tokens of the concatenation are not the tokens of its parts
+            ChunkUnit::Tokens => self.measure(&parts.concat()),
+            _ => parts.iter().map(|p| self.measure(p)).sum(),
+        }
+    }
+}
+
+impl FromStr for ChunkUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" => Ok(ChunkUnit::Bytes),
+            "chars" => Ok(ChunkUnit::Chars),
+            "tokens" => Ok(ChunkUnit::Tokens),
+            other => Err(anyhow::anyhow!(
+                "Unsupported chunk unit: {}. Supported chunk units are: bytes, chars, tokens",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChunkUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkUnit::Bytes => write!(f, "bytes"),
+            ChunkUnit::Chars => write!(f, "chars"),
+            ChunkUnit::Tokens => write!(f, "tokens"),
+        }
+    }
+}
+
+/// How the chunks of a collection were made, stored in its metadata
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkingConfig {
+    pub strategy: ChunkStrategy,
+    pub unit: ChunkUnit,
+    pub size: usize,
+    /// See `merge_small_chunks`. Defaults to 10% of the size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<usize>,
+    /// Separators of the recursive strategy. Defaults to paragraphs, lines, sentences and words
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub separators: Vec<String>,
+}
+
+impl ChunkingConfig {
+    pub fn new(strategy: ChunkStrategy, unit: ChunkUnit, size: usize) -> Self {
+        Self {
+            strategy,
+            unit,
+            size,
+            min_size: None,
+            separators: vec![],
+        }
+    }
+
+    /// The chunker of the strategy, merging the chunks smaller than the minimum size.
+    /// `embedder` is only used, and required, by the semantic strategy.
+    pub fn build_chunker(
+        &self,
+        embedder: Option<Arc<dyn Embedder>>,
+    ) -> anyhow::Result<Box<dyn Chunker>> {
+        let (size, unit) = (self.size, self.unit);
+        let min_size = self.min_size.unwrap_or(size / 10);
+        let inner: Box<dyn Chunker> = match self.strategy {
+            ChunkStrategy::Bytes => Box::new(ByteChunker { size, unit }),
+            ChunkStrategy::Sentence => Box::new(SentenceChunker { size, unit }),
+            ChunkStrategy::Markdown => Box::new(MarkdownChunker { size, unit }),
+            ChunkStrategy::Recursive => Box::new(RecursiveChunker {
+                size,
+                unit,
+                separators: self.separators.clone(),
+            }),
+            ChunkStrategy::Semantic => Box::new(SemanticChunker {
+                size,
+                min_size,
+                unit,
+                embedder: embedder.ok_or_else(|| {
+                    anyhow::anyhow!("The semantic chunk strategy needs an embedder")
+                })?,
+            }),
+        };
+        Ok(Box::new(MinSizeChunker {
+            inner: Box::new(PageChunker { inner }),
+            min_size,
+            size,
+            unit,
+        }))
+    }
+
+    pub fn to_metadata(&self) -> anyhow::Result<HashMap<String, serde_json::Value>> {
+        Ok(HashMap::from([(
+            CHUNKING_CONFIG_METADATA_KEY.to_string(),
+            serde_json::to_value(self)?,
+        )]))
+    }
+
+    /// Read the configuration from the collection metadata, if it was stored
+    pub fn from_metadata(
+        metadata: &HashMap<String, serde_json::Value>,
+    ) -> anyhow::Result<Option<Self>> {
+        match metadata.get(CHUNKING_CONFIG_METADATA_KEY) {
+            Some(value) => Ok(Some(serde_json::from_value(value.clone())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChunkingError {
+    #[error("The text to chunk is empty")]
+    EmptyInput,
+    #[error("The text to chunk is not valid UTF-8: {0}")]
+    Utf8Error(String),
+}
+
+/// Splits documents into chunks, recording their source
+#[async_trait]
+pub trait Chunker: Send + Sync {
+    async fn chunk(&self, document: &ParsedDocument) -> anyhow::Result<Vec<Chunk>>;
+}
+
+fn with_source(mut chunks: Vec<Chunk>, document: &ParsedDocument) -> Vec<Chunk> {
+    for chunk in chunks.iter_mut() {
+        chunk.source = Some(document.source.clone());
+    }
+    chunks
+}
+
+/// See `chunk_text`
+pub struct ByteChunker {
+    pub size: usize,
+    pub unit: ChunkUnit,
+}
+
+#[async_trait]
+impl Chunker for ByteChunker {
+    async fn chunk(&self, document: &ParsedDocument) -> anyhow::Result<Vec<Chunk>> {
+        let chunks = chunk_text(document.text.clone(), self.size, self.unit)?;
+        Ok(with_source(chunks, document))
+    }
+}
+
+/// See `chunk_sentences`
+pub struct SentenceChunker {
+    pub size: usize,
+    pub unit: ChunkUnit,
+}
+
+#[async_trait]
+impl Chunker for SentenceChunker {
+    async fn chunk(&self, document: &ParsedDocument) -> anyhow::Result<Vec<Chunk>> {
+        let chunks = chunk_sentences(document.text.clone(), self.size, self.unit);
+        Ok(with_source(chunks, document))
+    }
+}
+
+/// See `chunk_markdown`
+pub struct MarkdownChunker {
+    pub size: usize,
+    pub unit: ChunkUnit,
+}
+
+#[async_trait]
+impl Chunker for MarkdownChunker {
+    async fn chunk(&self, document: &ParsedDocument) -> anyhow::Result<Vec<Chunk>> {
+        let chunks = chunk_markdown(document.text.clone(), self.size, self.unit);
+        Ok(with_source(chunks, document))
+    }
+}
 
-#[derive(Debug)]
+/// See `chunk_recursive`
+pub struct RecursiveChunker {
+    pub size: usize,
+    pub unit: ChunkUnit,
+    pub separators: Vec<String>,
+}
+
+#[async_trait]
+impl Chunker for RecursiveChunker {
+    async fn chunk(&self, document: &ParsedDocument) -> anyhow::Result<Vec<Chunk>> {
+        let chunks = chunk_recursive(
+            document.text.clone(),
+            self.size,
+            self.unit,
+            &self.separators,
+        );
+        Ok(with_source(chunks, document))
+    }
+}
+
+/// See `chunk_semantic`
+pub struct SemanticChunker {
+    pub size: usize,
+    pub min_size: usize,
+    pub unit: ChunkUnit,
+    pub embedder: Arc<dyn Embedder>,
+}
+
+#[async_trait]
+impl Chunker for SemanticChunker {
+    async fn chunk(&self, document: &ParsedDocument) -> anyhow::Result<Vec<Chunk>> {
+        let chunks = chunk_semantic(
+            document.text.clone(),
+            self.size,
+            self.min_size,
+            self.unit,
+            self.embedder.as_ref(),
+        )
+        .await?;
+        Ok(with_source(chunks, document))
+    }
+}
+
+/// Wraps another chunker, chunking each page of the documents that have pages separately, so
+/// that the chunks do not cross page boundaries, and recording the page of each chunk
+pub struct PageChunker {
+    pub inner: Box<dyn Chunker>,
+}
+
+#[async_trait]
+impl Chunker for PageChunker {
+    async fn chunk(&self, document: &ParsedDocument) -> anyhow::Result<Vec<Chunk>> {
+        let pages = document.pages.as_ref();
+        let Some(pages) = pages.filter(|_| !document.text.trim().is_empty()) else {
+            return self.inner.chunk(document).await;
+        };
+        let mut chunks: Vec<Chunk> = vec![];
+        let mut offset = 0;
+        for (i, page) in pages.iter().enumerate() {
+            if page.trim().is_empty() {
+                offset += page.len() + 1;
+                continue;
+            }
+            let page_document = ParsedDocument::new(document.source.clone(), page.clone());
+            for mut chunk in self.inner.chunk(&page_document).await? {
+                chunk.span = chunk.span.map(|s| s.start + offset..s.end + offset);
+                chunk.page_start = Some(i + 1);
+                chunk.page_end = Some(i + 1);
+                chunks.push(chunk);
+            }
+            // the text of the document is its pages joined with newlines
+            offset += page.len() + 1;
+        }
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            chunk.chunk_index = Some(i);
+        }
+        Ok(chunks)
+    }
+}
+
+/// Wraps another chunker, merging its small chunks, see `merge_small_chunks`
+pub struct MinSizeChunker {
+    pub inner: Box<dyn Chunker>,
+    pub min_size: usize,
+    pub size: usize,
+    pub unit: ChunkUnit,
+}
+
+#[async_trait]
+impl Chunker for MinSizeChunker {
+    async fn chunk(&self, document: &ParsedDocument) -> anyhow::Result<Vec<Chunk>> {
+        let chunks = self.inner.chunk(document).await?;
+        Ok(merge_small_chunks(
+            chunks,
+            self.min_size,
+            self.size,
+            self.unit,
+        ))
+    }
+}
+
+/// A chunk of text, along with the vectors it was embedded with. Depending on the embedding
+/// provider, either or both of the embeddings are set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Chunk {
     pub content: String,
-    pub embedding: Option<Embedding>,
+    #[serde(default, with = "sparse_embedding")]
+    pub sparse_embedding: Option<Embedding>,
+    #[serde(default, alias = "embedding")]
+    pub dense_embedding: Option<Vec<f32>>,
+    #[serde(default, alias = "source_file")]
+    pub source: Option<String>,
+    /// Position of the chunk in its document
+    #[serde(default)]
+    pub chunk_index: Option<usize>,
+    /// Byte offsets of the chunk in the text of its document
+    #[serde(default)]
+    pub span: Option<Range<usize>>,
+    /// First and last pages (numbered from 1) of the chunk, for the documents that have pages
+    #[serde(default)]
+    pub page_start: Option<usize>,
+    #[serde(default)]
+    pub page_end: Option<usize>,
+    /// Headings of the Markdown section of the chunk, e.g. "Chapter 2 > Installation"
+    #[serde(default)]
+    pub heading_path: Option<String>,
+    /// ISO 639-1 code of the language to tokenize the chunk as, instead of the configured one
+    #[serde(default)]
+    pub language_override: Option<String>,
+    /// ISO 639-1 code of the language of the chunk: its override, or the detected one
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+// bm25 embeddings do not implement serde: they are (de)serialized as (index, value) pairs
+mod sparse_embedding {
+    use bm25::{Embedding, TokenEmbedding};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        embedding: &Option<Embedding>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        embedding
+            .as_ref()
+            .map(|e| e.iter().map(|t| (t.index, t.value)).collect::<Vec<_>>())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Embedding>, D::Error> {
+        let pairs: Option<Vec<(u32, f32)>> = Option::deserialize(deserializer)?;
+        Ok(pairs.map(|p| {
+            Embedding(
+                p.into_iter()
+                    .map(|(index, value)| TokenEmbedding { index, value })
+                    .collect(),
+            )
+        }))
+    }
 }
 
 impl Chunk {
     pub fn from_content(content: String) -> Self {
         Self {
             content,
-            embedding: None,
+            sparse_embedding: None,
+            dense_embedding: None,
+            source: None,
+            chunk_index: None,
+            span: None,
+            page_start: None,
+            page_end: None,
+            heading_path: None,
+            language_override: None,
+            language: None,
+        }
+    }
+
+    /// Deserialize a chunk from a JSON object, e.g. a line of a JSONL export. Only `content`
+    /// is required, and `source_file` and `embedding` are accepted for the source and the
+    /// dense embedding.
+    pub fn from_json_str(s: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    /// Serialize the chunk to a single-line JSON object, read back by `from_json_str`
+    pub fn to_json_string(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Concatenate the content of the chunks, e.g. to assemble the context of a query.
+    /// The merged chunk has no embedding, and keeps the source and language only when
+    /// all the chunks share them.
+    pub fn merge(chunks: &[Chunk], separator: &str) -> Chunk {
+        let shared = |field: fn(&Chunk) -> &Option<String>| {
+            let first = chunks.first().and_then(|c| field(c).clone());
+            chunks
+                .iter()
+                .all(|c| *field(c) == first)
+                .then_some(first)
+                .flatten()
+        };
+        Chunk {
+            source: shared(|c| &c.source),
+            language: shared(|c| &c.language),
+            ..Chunk::from_content(
+                chunks
+                    .iter()
+                    .map(|c| c.content.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(separator),
+            )
+        }
+    }
+}
+
+/// Write the chunks to a JSONL file, one chunk per line
+pub async fn write_chunks_jsonl(path: &str, chunks: &[&Chunk]) -> anyhow::Result<()> {
+    let mut lines = String::new();
+    for chunk in chunks {
+        lines.push_str(&chunk.to_json_string()?);
+        lines.push('\n');
+    }
+    tokio::fs::write(path, lines)
+        .await
+        .with_context(|| format!("Could not write the chunks to {}", path))
+}
+
+/// Read the chunks of a JSONL file written by `write_chunks_jsonl`. Blank lines are skipped.
+pub async fn read_chunks_jsonl(path: &str) -> anyhow::Result<Vec<Chunk>> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Could not read the chunks from {}", path))?;
+    let mut chunks: Vec<Chunk> = vec![];
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chunk = Chunk::from_json_str(line)
+            .with_context(|| format!("Invalid chunk on line {} of {}", i + 1, path))?;
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+/// Split the text into chunks of at most `size` bytes (or another unit). Paragraphs are kept
+/// intact and grouped greedily, and only the paragraphs longer than `size` are split.
+/// Fails with `ChunkingError::EmptyInput` when the text is only whitespace.
+///
+/// The chunks do not concatenate to the text, since the paragraphs are trimmed and joined
+/// with a single blank line. Their `span`s do round-trip: they are in order and disjoint,
+/// `text[span]` is the trimmed content of the chunk but for the blank lines between its
+/// paragraphs, and only whitespace is left out of the spans.
+pub fn chunk_text(text: String, size: usize, unit: ChunkUnit) -> anyhow::Result<Vec<Chunk>> {
+    if text.trim().is_empty() {
+        return Err(ChunkingError::EmptyInput.into());
+    }
+    let mut string_chunks: Vec<String> = vec![];
+    let mut current = String::new();
+    let paragraphs = text
+        .split(PARAGRAPH_SEPARATOR)
+        .map(|p| p.trim_matches('\n'))
+        .filter(|p| !p.trim().is_empty());
+    for paragraph in paragraphs {
+        if !current.is_empty()
+            && unit.measure_joined(&[&current, PARAGRAPH_SEPARATOR, paragraph]) > size
+        {
+            string_chunks.push(std::mem::take(&mut current));
+        }
+        if unit.measure(paragraph) > size {
+            let pieces = match unit {
+                ChunkUnit::Bytes => split_bytes(paragraph, size)?
+                    .into_iter()
+                    .map(|c| c.to_string())
+                    .collect(),
+                _ => split_sentences(paragraph, size, unit),
+            };
+            // the spaces cut out on their own are not worth a chunk
+            string_chunks.extend(pieces.into_iter().filter(|c| !c.trim().is_empty()));
+            continue;
+        }
+        if !current.is_empty() {
+            current.push_str(PARAGRAPH_SEPARATOR);
+        }
+        current.push_str(paragraph);
+    }
+    if !current.is_empty() {
+        string_chunks.push(current);
+    }
+    Ok(into_chunks(&text, string_chunks))
+}
+
+/// Split the text at the delimiters found by memchunk, moving each split point back to
+/// a char boundary so that no character is cut in half: the pieces concatenate to the text.
+/// A piece is only longer than `size` when it is a single character wider than `size`.
+fn split_bytes(text: &str, size: usize) -> Result<Vec<&str>, ChunkingError> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    while start < text.len() {
+        let rest = &text[start..];
+        let cut = chunk(rest.as_bytes()).size(size).next().unwrap_or_default();
+        // a cut in the middle of a character is moved back to its start
+        let mut end = match std::str::from_utf8(cut) {
+            Ok(valid) => valid.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(ChunkingError::Utf8Error(e.to_string())),
+        };
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        pieces.push(&rest[..end]);
+        start += end;
+    }
+    Ok(pieces)
+}
+
+/// Split the text into chunks of at most `size` bytes (or another unit) made of whole
+/// sentences. Only the sentences longer than `size` are split, between words, and only
+/// the words longer than `size` are split in the middle.
+pub fn chunk_sentences(text: String, size: usize, unit: ChunkUnit) -> Vec<Chunk> {
+    into_chunks(&text, split_sentences(&text, size, unit))
+}
+
+/// Split the text into chunks of at most `size` bytes (or another unit) on the first of the
+/// `separators` found in it (by default paragraphs, then lines, then sentences, then words),
+/// grouping the pieces greedily. The pieces longer than `size` are split on the next
+/// separators, and only the ones without any separator are cut between characters.
+pub fn chunk_recursive(
+    text: String,
+    size: usize,
+    unit: ChunkUnit,
+    separators: &[String],
+) -> Vec<Chunk> {
+    let separators: Vec<&str> = match separators.is_empty() {
+        true => DEFAULT_SEPARATORS.to_vec(),
+        false => separators.iter().map(|s| s.as_str()).collect(),
+    };
+    into_chunks(&text, split_recursive(&text, size, unit, &separators))
+}
+
+fn split_recursive(text: &str, size: usize, unit: ChunkUnit, separators: &[&str]) -> Vec<String> {
+    match separators.split_first() {
+        // the separators are kept at the end of the pieces, e.g. the period of a sentence
+        Some((separator, rest)) if !separator.is_empty() && text.contains(separator) => {
+            pack_segments(text.split_inclusive(separator), size, unit, |piece| {
+                split_recursive(piece, size, unit, rest)
+            })
+        }
+        Some((_, rest)) => split_recursive(text, size, unit, rest),
+        None => pack_segments(std::iter::once(text), size, unit, |piece| {
+            split_chars(piece, size, unit)
+        }),
+    }
+}
+
+/// Split the text into chunks of at most `size` bytes (or another unit) made of adjacent
+/// sentences about the same topic. The sentences are embedded with `embedder`, and the
+/// chunks end where the similarity between two adjacent sentences is a local minimum, well
+/// below the average one, once they reach `min_size`. Documents with fewer than a handful
+/// of sentences are chunked with `chunk_sentences`.
+pub async fn chunk_semantic(
+    text: String,
+    size: usize,
+    min_size: usize,
+    unit: ChunkUnit,
+    embedder: &dyn Embedder,
+) -> anyhow::Result<Vec<Chunk>> {
+    // the whitespace after each sentence is kept, to join them back as in the text
+    let mut sentences: Vec<String> = vec![];
+    for sentence in text.split_sentence_bounds() {
+        if sentence.trim().is_empty() {
+            continue;
+        }
+        match unit.measure(sentence.trim()) > size {
+            true => sentences.extend(
+                split_sentences(sentence, size, unit)
+                    .into_iter()
+                    .map(|s| s + " "),
+            ),
+            false => sentences.push(sentence.to_string()),
         }
     }
+    if sentences.len() < MIN_SEMANTIC_SENTENCES {
+        return Ok(chunk_sentences(text, size, unit));
+    }
+    let vectors = embedder
+        .embed_documents_in(&sentences, &vec![None; sentences.len()])
+        .await?;
+    let similarities: Vec<f32> = vectors
+        .windows(2)
+        .map(|pair| cosine_similarity(&pair[0], &pair[1]))
+        .collect();
+    let count = similarities.len() as f32;
+    let mean = similarities.iter().sum::<f32>() / count;
+    let deviation = (similarities.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / count).sqrt();
+    let is_boundary = |i: usize| {
+        let previous = i.checked_sub(1).map_or(f32::INFINITY, |p| similarities[p]);
+        let next = similarities.get(i + 1).copied().unwrap_or(f32::INFINITY);
+        similarities[i] < mean - deviation && similarities[i] < previous && similarities[i] <= next
+    };
+    let mut string_chunks: Vec<String> = vec![];
+    let mut current = String::new();
+    for (i, sentence) in sentences.iter().enumerate() {
+        if !current.is_empty() && unit.measure_joined(&[&current, sentence.trim_end()]) > size {
+            string_chunks.push(current.trim().to_string());
+            current.clear();
+        }
+        current.push_str(sentence);
+        // the similarity between this sentence and the next one
+        if i < similarities.len() && is_boundary(i) && unit.measure(current.trim()) >= min_size {
+            string_chunks.push(current.trim().to_string());
+            current.clear();
+        }
+    }
+    if !current.trim().is_empty() {
+        string_chunks.push(current.trim().to_string());
+    }
+    Ok(into_chunks(&text, string_chunks))
+}
+
+/// The cosine similarity of two embeddings, comparing the dense vectors of hybrid ones
+fn cosine_similarity(a: &EmbeddingVector, b: &EmbeddingVector) -> f32 {
+    fn cosine(dot: f32, a: f32, b: f32) -> f32 {
+        match a > 0.0 && b > 0.0 {
+            true => dot / (a.sqrt() * b.sqrt()),
+            false => 0.0,
+        }
+    }
+    match (a, b) {
+        (EmbeddingVector::Sparse(a), EmbeddingVector::Sparse(b)) => {
+            let values: HashMap<u32, f32> = a.iter().map(|t| (t.index, t.value)).collect();
+            let dot = b
+                .iter()
+                .filter_map(|t| values.get(&t.index).map(|v| v * t.value))
+                .sum();
+            let norm = |e: &Embedding| e.iter().map(|t| t.value * t.value).sum();
+            cosine(dot, norm(a), norm(b))
+        }
+        (
+            EmbeddingVector::Dense(a) | EmbeddingVector::Hybrid { dense: a, .. },
+            EmbeddingVector::Dense(b) | EmbeddingVector::Hybrid { dense: b, .. },
+        ) => {
+            let dot = a.iter().zip(b).map(|(x, y)| x * y).sum();
+            let norm = |v: &Vec<f32>| v.iter().map(|x| x * x).sum();
+            cosine(dot, norm(a), norm(b))
+        }
+        _ => 0.0,
+    }
+}
+
+fn split_sentences(text: &str, size: usize, unit: ChunkUnit) -> Vec<String> {
+    pack_segments(text.split_sentence_bounds(), size, unit, |sentence| {
+        pack_segments(sentence.split_word_bounds(), size, unit, |word| {
+            split_chars(word, size, unit)
+        })
+    })
+}
+
+/// A block of a Markdown section, which is only split if it is not a code fence
+enum MarkdownBlock {
+    Paragraph(String),
+    CodeFence(String),
+}
+
+/// A Markdown section: the headings it is nested in, and its blocks
+struct MarkdownSection {
+    headings: Vec<String>,
+    blocks: Vec<MarkdownBlock>,
+}
+
+/// Split Markdown text into chunks of at most `size` bytes (or another unit) that never span
+/// two sections.
+/// Each chunk starts with the heading path of its section (e.g. "Chapter 2 > Installation"),
+/// also stored as its `heading_path`, which is not part of its span. The paragraphs of a section are grouped greedily, and
+/// those longer than the chunk size are split between sentences. Code fences are never split,
+/// so a fence longer than the chunk size is a chunk on its own.
+pub fn chunk_markdown(text: String, size: usize, unit: ChunkUnit) -> Vec<Chunk> {
+    let mut pieces: Vec<(Option<String>, String)> = vec![];
+    for section in markdown_sections(&text) {
+        let heading_path =
+            (!section.headings.is_empty()).then(|| section.headings.join(HEADING_PATH_SEPARATOR));
+        // the heading path is part of the chunk size
+        let budget = match &heading_path {
+            Some(p) => size
+                .saturating_sub(unit.measure_joined(&[p, PARAGRAPH_SEPARATOR]))
+                .max(1),
+            None => size,
+        };
+        let mut section_pieces: Vec<String> = vec![];
+        let mut current = String::new();
+        for block in section.blocks {
+            let (content, splittable) = match block {
+                MarkdownBlock::Paragraph(p) => (p, true),
+                MarkdownBlock::CodeFence(f) => (f, false),
+            };
+            if !current.is_empty()
+                && unit.measure_joined(&[&current, PARAGRAPH_SEPARATOR, &content]) > budget
+            {
+                section_pieces.push(std::mem::take(&mut current));
+            }
+            if unit.measure(&content) > budget {
+                if splittable {
+                    section_pieces.extend(split_sentences(&content, budget, unit));
+                } else {
+                    section_pieces.push(content);
+                }
+                continue;
+            }
+            if !current.is_empty() {
+                current.push_str(PARAGRAPH_SEPARATOR);
+            }
+            current.push_str(&content);
+        }
+        if !current.is_empty() {
+            section_pieces.push(current);
+        }
+        pieces.extend(
+            section_pieces
+                .into_iter()
+                .map(|p| (heading_path.clone(), p)),
+        );
+    }
+    let (heading_paths, contents): (Vec<Option<String>>, Vec<String>) = pieces.into_iter().unzip();
+    into_chunks(&text, contents)
+        .into_iter()
+        .zip(heading_paths)
+        .map(|(chunk, heading_path)| Chunk {
+            content: match &heading_path {
+                Some(path) => format!("{}{}{}", path, PARAGRAPH_SEPARATOR, chunk.content),
+                None => chunk.content,
+            },
+            heading_path,
+            ..chunk
+        })
+        .collect()
+}
+
+/// The level and title of an ATX heading line (e.g. `## Installation`)
+fn parse_heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let rest = &line[level..];
+    if level == 0 || level > 6 || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+    Some((
+        level,
+        rest.trim().trim_end_matches('#').trim_end().to_string(),
+    ))
+}
+
+fn markdown_sections(text: &str) -> Vec<MarkdownSection> {
+    let mut sections: Vec<MarkdownSection> = vec![];
+    // the headings of the current section, with their levels
+    let mut headings: Vec<(usize, String)> = vec![];
+    let mut blocks: Vec<MarkdownBlock> = vec![];
+    let mut paragraph: Vec<&str> = vec![];
+    let mut fence: Option<(String, Vec<&str>)> = None;
+    let end_paragraph = |paragraph: &mut Vec<&str>, blocks: &mut Vec<MarkdownBlock>| {
+        if !paragraph.is_empty() {
+            blocks.push(MarkdownBlock::Paragraph(paragraph.join("\n")));
+            paragraph.clear();
+        }
+    };
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if let Some((marker, fence_lines)) = fence.as_mut() {
+            fence_lines.push(line);
+            // closed by a line of at least as many backticks (or tildes) as the opening one
+            let closing = trimmed.trim_end();
+            if closing.len() >= marker.len() && closing.chars().all(|c| marker.starts_with(c)) {
+                blocks.push(MarkdownBlock::CodeFence(fence_lines.join("\n")));
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            end_paragraph(&mut paragraph, &mut blocks);
+            let fence_char = &trimmed[..1];
+            let marker: String = trimmed
+                .chars()
+                .take_while(|c| fence_char.starts_with(*c))
+                .collect();
+            fence = Some((marker, vec![line]));
+            continue;
+        }
+        if let Some((level, title)) = parse_heading(trimmed) {
+            end_paragraph(&mut paragraph, &mut blocks);
+            sections.push(MarkdownSection {
+                headings: headings.iter().map(|(_, t)| t.clone()).collect(),
+                blocks: std::mem::take(&mut blocks),
+            });
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, title));
+            continue;
+        }
+        if line.trim().is_empty() {
+            end_paragraph(&mut paragraph, &mut blocks);
+        } else {
+            paragraph.push(line);
+        }
+    }
+    // an unclosed fence runs until the end of the document
+    if let Some((_, fence_lines)) = fence {
+        blocks.push(MarkdownBlock::CodeFence(fence_lines.join("\n")));
+    }
+    end_paragraph(&mut paragraph, &mut blocks);
+    sections.push(MarkdownSection {
+        headings: headings.into_iter().map(|(_, t)| t).collect(),
+        blocks,
+    });
+    sections.retain(|s| !s.blocks.is_empty());
+    sections
+}
+
+/// Group the segments (e.g. sentences) greedily into chunks of at most `size` units, the
+/// segments that do not fit in a chunk on their own being split with `split`
+fn pack_segments<'a>(
+    segments: impl Iterator<Item = &'a str>,
+    size: usize,
+    unit: ChunkUnit,
+    split: impl Fn(&'a str) -> Vec<String>,
+) -> Vec<String> {
+    fn flush(current: &mut String, string_chunks: &mut Vec<String>) {
+        let trimmed = current.trim();
+        if !trimmed.is_empty() {
+            string_chunks.push(trimmed.to_string());
+        }
+        current.clear();
+    }
+    let mut string_chunks: Vec<String> = vec![];
+    let mut current = String::new();
+    for segment in segments {
+        // the whitespace after a segment is only kept when another segment follows it
+        if unit.measure_joined(&[&current, segment.trim_end()]) > size {
+            flush(&mut current, &mut string_chunks);
+        }
+        if unit.measure(segment.trim()) > size {
+            flush(&mut current, &mut string_chunks);
+            string_chunks.extend(split(segment.trim()));
+            continue;
+        }
+        current.push_str(segment);
+    }
+    flush(&mut current, &mut string_chunks);
+    string_chunks
 }
 
-pub fn chunk_text(text: String, size: usize) -> Vec<Chunk> {
-    let text_bytes = text.as_bytes();
-    let chunks: Vec<&[u8]> = chunk(text_bytes).size(size).collect();
-    let string_chunks: Vec<String> = chunks
-        .iter()
-        .map(|&chunk| String::from_utf8_lossy(chunk).to_string())
+/// Split a word into pieces of at most `size` units, on character boundaries
+fn split_chars(word: &str, size: usize, unit: ChunkUnit) -> Vec<String> {
+    let mut pieces: Vec<String> = vec![];
+    let mut current = String::new();
+    for c in word.chars() {
+        if !current.is_empty()
+            && unit.measure_joined(&[&current, c.encode_utf8(&mut [0; 4])]) > size
+        {
+            pieces.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
+
+/// Merge the chunks smaller than `min_size` (e.g. the last fragment of a document) into the
+/// preceding chunk of the same section, as long as the merged chunk fits in `size + min_size`:
+/// the chunks are packed up to `size`, so that the last fragment would rarely fit otherwise.
+/// The chunks that cannot be merged (e.g. a short Markdown section) are kept as they are, so
+/// that no text is lost: only whitespace-only chunks are dropped.
+pub fn merge_small_chunks(
+    chunks: Vec<Chunk>,
+    min_size: usize,
+    size: usize,
+    unit: ChunkUnit,
+) -> Vec<Chunk> {
+    let chunks: Vec<Chunk> = chunks
+        .into_iter()
+        .filter(|c| !c.content.trim().is_empty())
         .collect();
-    let mut struct_chunks: Vec<Chunk> = vec![];
-    for c in string_chunks {
-        let chunk_struct = Chunk::from_content(c);
-        struct_chunks.push(chunk_struct);
+    if chunks.len() <= 1 {
+        return chunks;
+    }
+    let mut merged: Vec<Chunk> = vec![];
+    for chunk in chunks {
+        if unit.measure(&chunk.content) >= min_size {
+            merged.push(chunk);
+            continue;
+        }
+        let previous = merged
+            .last_mut()
+            .filter(|p| p.heading_path == chunk.heading_path);
+        if let Some(previous) = previous {
+            // the pieces of a split paragraph are verbatim and contiguous: they are concatenated
+            let adjacent = matches!((&previous.span, &chunk.span),
+                (Some(p), Some(c)) if p.start + previous.content.len() == c.start);
+            let separator = if adjacent { "" } else { PARAGRAPH_SEPARATOR };
+            // the heading path prefix is only kept once
+            let body = match &chunk.heading_path {
+                Some(path) => chunk.content[path.len()..].trim_start(),
+                None => &chunk.content,
+            };
+            if unit.measure_joined(&[&previous.content, separator, body]) <= size + min_size {
+                previous.content = format!("{}{}{}", previous.content, separator, body);
+                previous.span = match (&previous.span, &chunk.span) {
+                    (Some(p), Some(c)) => Some(p.start..c.end),
+                    _ => None,
+                };
+                // merged into the chunk of the previous page when it is too small on its own
+                previous.page_end = chunk.page_end.or(previous.page_end);
+                continue;
+            }
+        }
+        debug!(
+            chunk_index = chunk.chunk_index,
+            "Chunk is smaller than the minimum chunk size and cannot be merged, keeping it"
+        );
+        merged.push(chunk);
     }
+    for (i, chunk) in merged.iter_mut().enumerate() {
+        chunk.chunk_index = Some(i);
+    }
+    merged
+}
+
+/// The byte offsets of the chunk in the text, found by matching its words in order from
+/// `from`: chunking only changes the whitespace between the words (and skips Markdown
+/// headings before the chunk), so any other gap means that the chunk was not cut from there
+fn locate(text: &str, content: &str, from: usize) -> Option<Range<usize>> {
+    let mut start = None;
+    let mut end = from;
+    for word in content.split_whitespace() {
+        let offset = end + text.get(end..)?.find(word)?;
+        if start.is_some() && !text[end..offset].trim().is_empty() {
+            return None;
+        }
+        start.get_or_insert(offset);
+        end = offset + word.len();
+    }
+    start.map(|start| start..end)
+}
+
+fn into_chunks(text: &str, string_chunks: Vec<String>) -> Vec<Chunk> {
+    let mut from = 0;
+    let struct_chunks: Vec<Chunk> = string_chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let span = locate(text, &c, from);
+            if let Some(span) = &span {
+                from = span.end;
+            }
+            Chunk {
+                chunk_index: Some(i),
+                span,
+                ..Chunk::from_content(c)
+            }
+        })
+        .collect();
     println!("Created {:?} chunks", struct_chunks.len());
     struct_chunks
 }
@@ -35,12 +1034,15 @@ pub fn chunk_text(text: String, size: usize) -> Vec<Chunk> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::embedding::EmbeddingConfig;
 
     #[test]
     fn test_chunk_from_content() {
         let chunk = Chunk::from_content("test".to_string());
         assert_eq!(chunk.content, "test".to_string());
-        assert!(chunk.embedding.is_none());
+        assert!(chunk.sparse_embedding.is_none());
+        assert!(chunk.dense_embedding.is_none());
+        assert!(chunk.source.is_none());
     }
 
     #[test]
@@ -48,8 +1050,738 @@ mod test {
         // this config should produce only one chunk
         let text = "This is a one-chunk text.".to_string();
         let size: usize = 1024;
-        let chunks = chunk_text(text, size);
+        let chunks = chunk_text(text, size, ChunkUnit::Bytes).unwrap();
         assert_eq!(chunks.len(), 1);
         assert_eq!(chunks[0].content, "This is a one-chunk text.".to_string());
+        assert_eq!(chunks[0].chunk_index, Some(0));
+    }
+
+    #[test]
+    fn test_chunk_text_keeps_paragraphs() {
+        let first = "First paragraph.";
+        let second = "Second paragraph.";
+        let third = "Third paragraph.";
+        let text = format!("{}\n\n{}\n\n\n{}\n", first, second, third);
+        let chunks = chunk_text(text, 40, ChunkUnit::Bytes).unwrap();
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| c.content.as_str())
+                .collect::<Vec<&str>>(),
+            vec![format!("{}\n\n{}", first, second).as_str(), third]
+        );
+        // a paragraph longer than the chunk size is the only one to be split
+        let long = "word ".repeat(20);
+        let chunks = chunk_text(
+            format!("{}\n\n{}", first, long.trim()),
+            40,
+            ChunkUnit::Bytes,
+        )
+        .unwrap();
+        assert_eq!(chunks[0].content, first);
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|c| c.content.len() <= 40));
+    }
+
+    #[test]
+    fn test_chunk_text_empty_input() {
+        for text in ["", "\n\n", " \t\n"] {
+            let err = chunk_text(text.to_string(), 40, ChunkUnit::Bytes).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<ChunkingError>(),
+                Some(ChunkingError::EmptyInput)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_split_bytes_char_boundaries() {
+        let samples = [
+            "Les élèves ont étudié l'œuvre de Gödel à l'université. ".repeat(8),
+            "東京は日本の首都です。京都には多くの寺があります。".repeat(8),
+            "Happy 🎉 birthday 👩‍👩‍👧 with 🇫🇷 flags 🚀🚀🚀 ".repeat(8),
+            // e and n followed by combining acute accent and tilde
+            "Cafe\u{0301} man\u{0303}ana re\u{0301}sume\u{0301} ".repeat(8),
+        ];
+        for text in &samples {
+            for size in 1..=48 {
+                let pieces = split_bytes(text, size).unwrap();
+                assert_eq!(pieces.concat(), *text, "size {}", size);
+                assert!(
+                    pieces
+                        .iter()
+                        .all(|p| !p.is_empty() && (p.len() <= size || p.chars().count() == 1))
+                );
+            }
+        }
+        let paragraph = "数据".repeat(100);
+        let chunks = chunk_text(paragraph.clone(), 31, ChunkUnit::Bytes).unwrap();
+        assert!(chunks.iter().all(|c| !c.content.contains('\u{FFFD}')));
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| c.content.as_str())
+                .collect::<String>(),
+            paragraph
+        );
+    }
+
+    #[test]
+    fn test_chunk_text_spans_round_trip() {
+        // multi-byte characters, combining marks and grapheme clusters of several code points
+        let samples = [
+            "é",
+            "e\u{0301}",
+            "🎉",
+            "👩\u{200d}👩\u{200d}👧",
+            "東京",
+            "n\u{0303}",
+        ];
+        for offset in 0..8 {
+            for sample in samples {
+                let padding = "x".repeat(offset);
+                let text = format!(
+                    "{padding}{sample}{sample} a{sample}\n\n{sample}{padding}\n\n\n{padding} {sample}b"
+                );
+                for size in 1..=8 {
+                    let chunks = chunk_text(text.clone(), size, ChunkUnit::Bytes).unwrap();
+                    let mut end = 0;
+                    for chunk in &chunks {
+                        let span = chunk.span.clone().expect("Every chunk should be located");
+                        assert!(span.start >= end, "{:?}, size {}", text, size);
+                        assert!(text[end..span.start].trim().is_empty());
+                        // the paragraphs of a chunk are joined with a single blank line
+                        assert_eq!(
+                            text[span.clone()].replace("\n\n\n", "\n\n"),
+                            chunk.content.trim()
+                        );
+                        end = span.end;
+                    }
+                    // only whitespace is left out of the chunks
+                    assert!(text[end..].trim().is_empty());
+                }
+            }
+        }
+    }
+
+    const STRATEGIES: [ChunkStrategy; 5] = [
+        ChunkStrategy::Bytes,
+        ChunkStrategy::Sentence,
+        ChunkStrategy::Markdown,
+        ChunkStrategy::Recursive,
+        ChunkStrategy::Semantic,
+    ];
+
+    /// Chunk the text with the chunker of the strategy, without merging small chunks
+    async fn chunk_with(
+        strategy: ChunkStrategy,
+        size: usize,
+        unit: ChunkUnit,
+        text: &str,
+    ) -> Vec<Chunk> {
+        let config = ChunkingConfig {
+            min_size: Some(0),
+            ..ChunkingConfig::new(strategy, unit, size)
+        };
+        let embedder = EmbeddingConfig {
+            language_mode: "en".to_string(),
+            ..Default::default()
+        }
+        .build_embedder(None)
+        .unwrap();
+        let document = ParsedDocument::new("doc.md".to_string(), text.to_string());
+        config
+            .build_chunker(Some(embedder))
+            .unwrap()
+            .chunk(&document)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_chunkers_invariants() {
+        let sentence =
+            "Retrieval augmented generation grounds the answers of a model in documents. ";
+        let texts = [
+            format!(
+                "{}\n\n{}",
+                sentence.repeat(20),
+                "Supercalifragilisticexpialidocious ".repeat(30)
+            ),
+            format!(
+                "Les élèves étudient l'œuvre de Gödel. 東京は日本の首都です。\n\n{}\nHappy 🎉 birthday 👩‍👩‍👧! Cafe\u{0301} au lait.\n\n{}",
+                "数据".repeat(60),
+                "x".repeat(300)
+            ),
+        ];
+        let non_whitespace = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        for text in &texts {
+            for strategy in STRATEGIES {
+                for unit in [ChunkUnit::Bytes, ChunkUnit::Chars, ChunkUnit::Tokens] {
+                    let context = format!("{} chunks in {}", strategy, unit);
+                    let chunks = chunk_with(strategy, 40, unit, text).await;
+                    assert!(chunks.len() > 1, "{}", context);
+                    // the size is respected, and the text is fully covered, in order
+                    assert!(
+                        chunks.iter().all(|c| unit.measure(&c.content) <= 40),
+                        "{}",
+                        context
+                    );
+                    assert_eq!(
+                        non_whitespace(
+                            &chunks
+                                .iter()
+                                .map(|c| c.content.as_str())
+                                .collect::<String>()
+                        ),
+                        non_whitespace(text),
+                        "{}",
+                        context
+                    );
+                    // no character is cut in half
+                    assert!(
+                        chunks.iter().all(|c| !c.content.contains('\u{FFFD}')
+                            && c.span.clone().is_some_and(|s| text.get(s).is_some())
+                            && c.source.as_deref() == Some("doc.md")),
+                        "{}",
+                        context
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunk_spans() {
+        let text = "# Guide\n\nFirst paragraph.\n\n\nSecond  paragraph,\nwrapped.\n\n## Setup\n\nRun `cargo build`. Then run the tests.\n";
+        for strategy in STRATEGIES {
+            let chunks = chunk_with(strategy, 24, ChunkUnit::Bytes, text).await;
+            let mut previous_end = 0;
+            for (i, chunk) in chunks.iter().enumerate() {
+                assert_eq!(chunk.chunk_index, Some(i));
+                let span = chunk.span.clone().unwrap();
+                assert!(span.start >= previous_end);
+                previous_end = span.end;
+                let original = &text[span];
+                let content = match &chunk.heading_path {
+                    Some(path) => chunk.content[path.len()..].trim_start(),
+                    None => &chunk.content,
+                };
+                assert_eq!(
+                    original.split_whitespace().collect::<Vec<&str>>(),
+                    content.split_whitespace().collect::<Vec<&str>>(),
+                    "{} chunk {}",
+                    strategy,
+                    i
+                );
+            }
+        }
+        assert_eq!(locate("one two three", "two three", 0), Some(4..13));
+        assert_eq!(locate("one two three", "one three", 0), None);
+        assert_eq!(locate("one two", "", 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_page_chunker() {
+        let document = ParsedDocument::from_pages(
+            "report.pdf".to_string(),
+            vec![
+                "First page, with a first paragraph.\n\nAnd a second one.".to_string(),
+                "Second page.".to_string(),
+                "The third page has a longer paragraph.".to_string(),
+            ],
+        );
+        for strategy in STRATEGIES {
+            let config = ChunkingConfig {
+                min_size: Some(0),
+                ..ChunkingConfig::new(strategy, ChunkUnit::Bytes, 40)
+            };
+            let embedder = EmbeddingConfig {
+                language_mode: "en".to_string(),
+                ..Default::default()
+            }
+            .build_embedder(None)
+            .unwrap();
+            let chunks = config
+                .build_chunker(Some(embedder))
+                .unwrap()
+                .chunk(&document)
+                .await
+                .unwrap();
+            let pages: Vec<(Option<usize>, Option<usize>)> =
+                chunks.iter().map(|c| (c.page_start, c.page_end)).collect();
+            // the first page fits in a chunk, but is not merged with the second one
+            assert_eq!(pages.first(), Some(&(Some(1), Some(1))), "{}", strategy);
+            assert_eq!(pages.last(), Some(&(Some(3), Some(3))), "{}", strategy);
+            assert!(pages.contains(&(Some(2), Some(2))), "{}", strategy);
+            for chunk in &chunks {
+                let span = chunk.span.clone().unwrap();
+                assert_eq!(
+                    document.text[span]
+                        .split_whitespace()
+                        .collect::<Vec<&str>>(),
+                    chunk.content.split_whitespace().collect::<Vec<&str>>()
+                );
+            }
+        }
+        // the chunks too small on their own are merged across pages
+        let config = ChunkingConfig {
+            min_size: Some(20),
+            ..ChunkingConfig::new(ChunkStrategy::Bytes, ChunkUnit::Bytes, 100)
+        };
+        let chunks = config
+            .build_chunker(None)
+            .unwrap()
+            .chunk(&document)
+            .await
+            .unwrap();
+        assert_eq!(chunks[0].page_start, Some(1));
+        assert_eq!(chunks[0].page_end, Some(2));
+        // documents without pages have no page numbers
+        let chunks = config
+            .build_chunker(None)
+            .unwrap()
+            .chunk(&ParsedDocument::new(
+                "notes.md".to_string(),
+                "Notes".to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(chunks[0].page_start, None);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_merge_small_chunks() {
+        // one byte over the chunk size: the last byte is merged within the minimum size
+        let text = "aaaa bbbb cccc dddd e".to_string();
+        let chunks = chunk_text(text.clone(), 20, ChunkUnit::Bytes).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            merge_small_chunks(chunks.clone(), 0, 20, ChunkUnit::Bytes),
+            chunks
+        );
+        // the pieces being contiguous, they are concatenated
+        let merged = merge_small_chunks(chunks.clone(), 2, 20, ChunkUnit::Bytes);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].content, text);
+        assert_eq!(merged[0].span, Some(0..21));
+        // a small chunk that cannot be merged with the paragraph separator is kept
+        let chunks = vec![
+            Chunk::from_content("aaaa bbbb cccc dddd.".to_string()),
+            Chunk::from_content("End.".to_string()),
+        ];
+        let merged = merge_small_chunks(chunks, 5, 20, ChunkUnit::Bytes);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[1].content, "End.");
+        assert_eq!(merged[1].chunk_index, Some(1));
+        assert!(logs_contain(
+            "Chunk is smaller than the minimum chunk size and cannot be merged, keeping it"
+        ));
+        // documents shorter than the minimum are kept, unless they are only whitespace
+        let short = chunk_text("Tiny.".to_string(), 100, ChunkUnit::Bytes).unwrap();
+        assert_eq!(
+            merge_small_chunks(short.clone(), 10, 100, ChunkUnit::Bytes),
+            short
+        );
+        let blank = vec![Chunk::from_content(" \n ".to_string())];
+        assert!(merge_small_chunks(blank, 10, 100, ChunkUnit::Bytes).is_empty());
+        let chunks = vec![
+            Chunk::from_content("First paragraph.".to_string()),
+            Chunk::from_content("   ".to_string()),
+            Chunk::from_content("End.".to_string()),
+        ];
+        let merged = merge_small_chunks(chunks, 10, 100, ChunkUnit::Bytes);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].content, "First paragraph.\n\nEnd.");
+        assert_eq!(merged[0].chunk_index, Some(0));
+    }
+
+    #[test]
+    fn test_chunk_recursive_separator_priority() {
+        let text = "First line of the first paragraph.\nSecond line.\n\nSecond paragraph. It has two sentences.";
+        let contents =
+            |chunks: Vec<Chunk>| -> Vec<String> { chunks.into_iter().map(|c| c.content).collect() };
+        // paragraphs are preferred to lines, and lines to sentences
+        assert_eq!(
+            contents(chunk_recursive(text.to_string(), 60, ChunkUnit::Bytes, &[])),
+            vec![
+                "First line of the first paragraph.\nSecond line.",
+                "Second paragraph. It has two sentences."
+            ]
+        );
+        assert_eq!(
+            contents(chunk_recursive(text.to_string(), 40, ChunkUnit::Bytes, &[])),
+            vec![
+                "First line of the first paragraph.",
+                "Second line.",
+                "Second paragraph. It has two sentences."
+            ]
+        );
+        assert_eq!(
+            contents(chunk_recursive(text.to_string(), 30, ChunkUnit::Bytes, &[])),
+            vec![
+                "First line of the first",
+                "paragraph.",
+                "Second line.",
+                "Second paragraph.",
+                "It has two sentences."
+            ]
+        );
+        // with custom separators, only sentences are separated before hard cuts
+        assert_eq!(
+            contents(chunk_recursive(
+                text.to_string(),
+                40,
+                ChunkUnit::Bytes,
+                &["! ".to_string(), ". ".to_string()]
+            )),
+            vec![
+                "First line of the first paragraph.\nSecon",
+                "d line.\n\nSecond paragraph.",
+                "It has two sentences."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chunk_recursive_size_limit() {
+        let unbroken = "x".repeat(2_000);
+        let mixed = format!(
+            "{}\n\nShort paragraph.\n{}",
+            "é".repeat(500),
+            "ab ".repeat(200)
+        );
+        for text in [unbroken, mixed] {
+            for unit in [ChunkUnit::Bytes, ChunkUnit::Chars, ChunkUnit::Tokens] {
+                let chunks = chunk_recursive(text.clone(), 100, unit, &[]);
+                assert!(chunks.len() > 1);
+                assert!(chunks.iter().all(|c| unit.measure(&c.content) <= 100));
+                assert!(chunks.iter().all(|c| c.span.is_some()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunk_semantic() {
+        let cats = [
+            "Cats sleep for most of the day on the sofa.",
+            "A sleeping cat purrs when it is stroked.",
+            "Cats groom their fur with their rough tongue.",
+            "The cat chases a toy mouse around the sofa.",
+            "Old cats sleep even more than kittens.",
+        ];
+        let rockets = [
+            "The rocket engine burns liquid oxygen and fuel.",
+            "The rocket reached orbit ten minutes after launch.",
+            "Engineers inspect the engine before each launch.",
+            "A reusable rocket lands back after the launch.",
+            "The launch was delayed because of the engine.",
+        ];
+        let text = format!("{} {}", cats.join(" "), rockets.join(" "));
+        let config = EmbeddingConfig {
+            language_mode: "en".to_string(),
+            ..Default::default()
+        };
+        let embedder = config.build_embedder(None).unwrap();
+        let chunks = chunk_semantic(text.clone(), 1024, 100, ChunkUnit::Bytes, embedder.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| c.content.as_str())
+                .collect::<Vec<&str>>(),
+            vec![cats.join(" "), rockets.join(" ")]
+        );
+        assert_eq!(chunks[1].span, Some(cats.join(" ").len() + 1..text.len()));
+        // the size limit still applies
+        let chunks = chunk_semantic(text, 120, 10, ChunkUnit::Bytes, embedder.as_ref())
+            .await
+            .unwrap();
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|c| c.content.len() <= 120));
+        // short documents are chunked by sentence
+        let short = cats[..3].join(" ");
+        assert_eq!(
+            chunk_semantic(short.clone(), 60, 10, ChunkUnit::Bytes, embedder.as_ref())
+                .await
+                .unwrap(),
+            chunk_sentences(short, 60, ChunkUnit::Bytes)
+        );
+    }
+
+    #[test]
+    fn test_chunk_sentences() {
+        let text = "The first sentence is here. The second one follows it! \
+            Is the third a question? The fourth sentence, however, is a much longer one \
+            that does not fit in a chunk on its own and has to be split between its words.";
+        let chunks = chunk_sentences(text.to_string(), 60, ChunkUnit::Bytes);
+        assert_eq!(
+            chunks[0].content,
+            "The first sentence is here. The second one follows it!"
+        );
+        assert_eq!(chunks[1].content, "Is the third a question?");
+        assert!(chunks.iter().all(|c| c.content.len() <= 60));
+        // no chunk starts or ends in the middle of a word
+        let words: Vec<&str> = chunks
+            .iter()
+            .flat_map(|c| c.content.split_whitespace())
+            .collect();
+        assert_eq!(words, text.split_whitespace().collect::<Vec<&str>>());
+        let chunks = chunk_sentences("Supercalifragilistic.".to_string(), 8, ChunkUnit::Bytes);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| c.content.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["Supercal", "ifragili", "stic", "."]
+        );
+        assert!(chunk_sentences(" \n\n ".to_string(), 8, ChunkUnit::Bytes).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_markdown_nested_headings() {
+        let text = "# Chapter 1\n\nIntroduction.\n\n# Chapter 2\n\n## Installation\n\n\
+            Run the installer.\n\nThen restart.\n\n### Linux\n\nUse the package.\n\n\
+            ## Usage ##\n\nRun it.";
+        let chunks = chunk_markdown(text.to_string(), 1024, ChunkUnit::Bytes);
+        let contents: Vec<&str> = chunks.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "Chapter 1\n\nIntroduction.",
+                "Chapter 2 > Installation\n\nRun the installer.\n\nThen restart.",
+                "Chapter 2 > Installation > Linux\n\nUse the package.",
+                "Chapter 2 > Usage\n\nRun it.",
+            ]
+        );
+        assert_eq!(
+            chunks[2].heading_path,
+            Some("Chapter 2 > Installation > Linux".to_string())
+        );
+        assert_eq!(chunks[3].chunk_index, Some(3));
+        // oversized sections are split between sentences, and every chunk keeps the path
+        let long = "This sentence is in a long section. ".repeat(10);
+        let chunks = chunk_markdown(format!("# Title\n\n{}", long), 100, ChunkUnit::Bytes);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.content.len() <= 100));
+        assert!(
+            chunks
+                .iter()
+                .all(|c| c.content.starts_with("Title\n\nThis"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_min_size_keeps_short_markdown_sections() {
+        let text = format!(
+            "# Guide\n\n{}\n\n## License\n\nMIT",
+            "This sentence is in the guide. ".repeat(6).trim_end()
+        );
+        // the minimum size defaults to 30 bytes
+        let chunks = ChunkingConfig::new(ChunkStrategy::Markdown, ChunkUnit::Bytes, 300)
+            .build_chunker(None)
+            .unwrap()
+            .chunk(&ParsedDocument::new("README.md".to_string(), text))
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].content, "Guide > License\n\nMIT");
+        assert_eq!(chunks[1].heading_path, Some("Guide > License".to_string()));
+    }
+
+    #[test]
+    fn test_chunk_markdown_code_fences() {
+        let fence = "```bash\n# not a heading\necho one\n\necho two\n```";
+        let text = format!("# Setup\n\nInstall it:\n\n{}\n\nDone.", fence);
+        let chunks = chunk_markdown(text.clone(), 1024, ChunkUnit::Bytes);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            chunks[0].content,
+            format!("Setup\n\nInstall it:\n\n{}\n\nDone.", fence)
+        );
+        // a fence longer than the chunk size is kept whole
+        let chunks = chunk_markdown(text, 30, ChunkUnit::Bytes);
+        assert!(
+            chunks
+                .iter()
+                .any(|c| c.content == format!("Setup\n\n{}", fence))
+        );
+    }
+
+    #[test]
+    fn test_chunk_markdown_without_headings() {
+        let chunks = chunk_markdown(
+            "First paragraph.\n\nSecond #paragraph.".to_string(),
+            1024,
+            ChunkUnit::Bytes,
+        );
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].content, "First paragraph.\n\nSecond #paragraph.");
+        assert!(chunks[0].heading_path.is_none());
+        assert!(chunk_markdown(String::new(), 1024, ChunkUnit::Bytes).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_units() {
+        let text = "Les élèves étudient. ".repeat(5);
+        assert_eq!(ChunkUnit::Bytes.measure("élève"), 7);
+        assert_eq!(ChunkUnit::Chars.measure("élève"), 5);
+        assert_eq!(ChunkUnit::Tokens.measure("hello world"), 2);
+        let chunks = chunk_sentences(text.clone(), 25, ChunkUnit::Chars);
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|c| c.content == "Les élèves étudient."));
+        assert_eq!(chunk_sentences(text, 25, ChunkUnit::Bytes).len(), 5);
+    }
+
+    #[test]
+    fn test_chunking_config_metadata() {
+        let mut config = ChunkingConfig::new(ChunkStrategy::Sentence, ChunkUnit::Tokens, 400);
+        let metadata = config.to_metadata().unwrap();
+        assert_eq!(
+            metadata[CHUNKING_CONFIG_METADATA_KEY],
+            serde_json::json!({"strategy": "sentence", "unit": "tokens", "size": 400})
+        );
+        assert_eq!(
+            ChunkingConfig::from_metadata(&metadata).unwrap(),
+            Some(config.clone())
+        );
+        assert_eq!(
+            ChunkingConfig::from_metadata(&HashMap::new()).unwrap(),
+            None
+        );
+        config.strategy = ChunkStrategy::Recursive;
+        config.separators = vec!["\n".to_string()];
+        let metadata = config.to_metadata().unwrap();
+        assert_eq!(
+            metadata[CHUNKING_CONFIG_METADATA_KEY]["separators"],
+            serde_json::json!(["\n"])
+        );
+        assert_eq!(
+            ChunkingConfig::from_metadata(&metadata).unwrap(),
+            Some(config)
+        );
+        assert_eq!("chars".parse::<ChunkUnit>().unwrap(), ChunkUnit::Chars);
+        assert!("words".parse::<ChunkUnit>().is_err());
+    }
+
+    #[test]
+    fn test_chunk_strategy_from_str() {
+        assert_eq!(
+            "sentence".parse::<ChunkStrategy>().unwrap(),
+            ChunkStrategy::Sentence
+        );
+        assert_eq!(ChunkStrategy::default().to_string(), "bytes");
+        assert_eq!(
+            "recursive".parse::<ChunkStrategy>().unwrap(),
+            ChunkStrategy::Recursive
+        );
+        assert!("words".parse::<ChunkStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_chunk_merge() {
+        let mut first = Chunk::from_content("first".to_string());
+        first.source = Some("a.md".to_string());
+        first.sparse_embedding = Some(Embedding(vec![]));
+        let mut second = Chunk::from_content("second".to_string());
+        second.source = Some("a.md".to_string());
+        let merged = Chunk::merge(&[first.clone(), second.clone()], "\n---\n");
+        assert_eq!(merged.content, "first\n---\nsecond");
+        assert_eq!(merged.source, Some("a.md".to_string()));
+        assert!(merged.sparse_embedding.is_none());
+        second.source = Some("b.md".to_string());
+        assert!(Chunk::merge(&[first, second], " ").source.is_none());
+        assert_eq!(Chunk::merge(&[], " ").content, "");
+    }
+
+    #[test]
+    fn test_chunk_json_string() {
+        let chunk = Chunk::from_json_str(
+            r#"{"content": "hello", "source_file": "a.md", "chunk_index": 2, "embedding": [0.5, 1.0]}"#,
+        )
+        .unwrap();
+        assert_eq!(chunk.content, "hello");
+        assert_eq!(chunk.source, Some("a.md".to_string()));
+        assert_eq!(chunk.chunk_index, Some(2));
+        assert_eq!(chunk.dense_embedding, Some(vec![0.5, 1.0]));
+        assert!(chunk.sparse_embedding.is_none());
+        let json = chunk.to_json_string().unwrap();
+        assert!(!json.contains('\n'));
+        assert_eq!(Chunk::from_json_str(&json).unwrap(), chunk);
+        assert!(Chunk::from_json_str(r#"{"source_file": "a.md"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunks_jsonl() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("chunks.jsonl");
+        let path = path.to_str().unwrap();
+        let document = ParsedDocument::from_pages(
+            "report.pdf".to_string(),
+            vec![
+                "# Guide\n\nFirst page.".to_string(),
+                "Second page.".to_string(),
+            ],
+        );
+        let chunks = ChunkingConfig::new(ChunkStrategy::Markdown, ChunkUnit::Bytes, 20)
+            .build_chunker(None)
+            .unwrap()
+            .chunk(&document)
+            .await
+            .unwrap();
+        write_chunks_jsonl(path, &chunks.iter().collect::<Vec<&Chunk>>())
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap().lines().count(),
+            chunks.len()
+        );
+        assert_eq!(read_chunks_jsonl(path).await.unwrap(), chunks);
+        std::fs::write(path, "{\"content\": \"a\"}\n\nnot json\n").unwrap();
+        let err = read_chunks_jsonl(path).await.unwrap_err();
+        assert!(err.to_string().starts_with("Invalid chunk on line 3 of"));
+    }
+
+    #[test]
+    fn test_chunk_serde_round_trip() {
+        let chunk = Chunk {
+            content: "hello world".to_string(),
+            sparse_embedding: Some(Embedding(vec![
+                bm25::TokenEmbedding {
+                    index: 42,
+                    value: 0.5,
+                },
+                bm25::TokenEmbedding {
+                    index: 7,
+                    value: 1.25,
+                },
+            ])),
+            dense_embedding: Some(vec![0.1, 0.2, 0.3]),
+            source: Some("testfiles/test.md".to_string()),
+            chunk_index: Some(3),
+            span: Some(120..131),
+            page_start: Some(12),
+            page_end: Some(13),
+            heading_path: Some("Chapter 2 > Installation".to_string()),
+            language_override: Some("en".to_string()),
+            language: Some("en".to_string()),
+        };
+        let json = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(
+            json["sparse_embedding"],
+            serde_json::json!([[42, 0.5], [7, 1.25]])
+        );
+        assert_eq!(json["span"], serde_json::json!({"start": 120, "end": 131}));
+        let loaded: Chunk = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded, chunk);
+        // embeddings are optional when importing chunks
+        let without_embeddings: Chunk =
+            serde_json::from_str(r#"{"content": "bye world"}"#).unwrap();
+        assert_eq!(
+            without_embeddings,
+            Chunk::from_content("bye world".to_string())
+        );
     }
 }
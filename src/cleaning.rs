@@ -0,0 +1,194 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::parsing::ParsedDocument;
+
+/// Clean the text of a document before chunking: see `clean_text`. When `max_line_repeats`
+/// is set, the lines found on more pages than that (e.g. headers and footers) are dropped
+/// from the documents that have pages.
+pub fn clean_document(
+    document: &ParsedDocument,
+    max_line_repeats: Option<usize>,
+) -> ParsedDocument {
+    let Some(pages) = &document.pages else {
+        return ParsedDocument::new(document.source.clone(), clean_text(&document.text));
+    };
+    let mut pages: Vec<String> = pages.iter().map(|p| clean_text(p)).collect();
+    if let Some(max_pages) = max_line_repeats {
+        // the lines are compared once cleaned, and the blank lines left are collapsed
+        pages = drop_repeated_lines(&pages, max_pages)
+            .iter()
+            .map(|p| clean_text(p))
+            .collect();
+    }
+    ParsedDocument::from_pages(document.source.clone(), pages)
+}
+
+/// Strip the control characters (but newlines and tabs), collapse the runs of spaces inside
+/// the lines and of blank lines, and rejoin the words hyphenated at the end of a line
+/// ("infor-\nmation"). The indentation of the lines and the content of the Markdown code
+/// fences are kept as they are.
+pub fn clean_text(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect();
+    let mut lines: Vec<String> = vec![];
+    let mut in_fence = false;
+    // whether the last line can be joined with the next one
+    let mut joinable = false;
+    for line in text.split('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_fence = !in_fence;
+        }
+        if in_fence || trimmed.starts_with("```") {
+            lines.push(line.trim_end().to_string());
+            joinable = false;
+            continue;
+        }
+        if trimmed.is_empty() {
+            if lines.last().is_some_and(|l| !l.is_empty()) {
+                lines.push(String::new());
+            }
+            joinable = false;
+            continue;
+        }
+        let collapsed = trimmed.split_whitespace().collect::<Vec<&str>>().join(" ");
+        if let Some(previous) = lines.last_mut()
+            && joinable
+            && ends_with_hyphenated_word(previous)
+            && collapsed.starts_with(char::is_lowercase)
+        {
+            previous.pop();
+            previous.push_str(&collapsed);
+            continue;
+        }
+        let indentation = &line[..line.len() - trimmed.len()];
+        lines.push(format!("{}{}", indentation, collapsed));
+        joinable = true;
+    }
+    if lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+fn ends_with_hyphenated_word(line: &str) -> bool {
+    let mut chars = line.chars().rev();
+    chars.next() == Some('-') && chars.next().is_some_and(char::is_alphabetic)
+}
+
+/// Drop the lines found on more than `max_pages` pages. Blank lines are always kept.
+pub fn drop_repeated_lines(pages: &[String], max_pages: usize) -> Vec<String> {
+    let mut occurrences: HashMap<&str, usize> = HashMap::new();
+    for page in pages {
+        let lines: HashSet<&str> = page
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        for line in lines {
+            *occurrences.entry(line).or_default() += 1;
+        }
+    }
+    pages
+        .iter()
+        .map(|page| {
+            page.lines()
+                .filter(|l| occurrences.get(l.trim()).is_none_or(|n| *n <= max_pages))
+                .collect::<Vec<&str>>()
+                .join("\n")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clean_text_whitespace() {
+        let text =
+            "  A line   with\t\truns  of spaces.   \n\n\n\n   \nNext\u{0c} para\u{0}graph.\r\n\n";
+        assert_eq!(
+            clean_text(text),
+            "  A line with runs of spaces.\n\nNext paragraph."
+        );
+        // cleaning is idempotent
+        assert_eq!(clean_text(&clean_text(text)), clean_text(text));
+        assert_eq!(clean_text(""), "");
+        assert_eq!(clean_text("\n \n\t\n"), "");
+    }
+
+    #[test]
+    fn test_clean_text_hyphenation() {
+        assert_eq!(
+            clean_text("The infor-\nmation is in the docu-\n  ment."),
+            "The information is in the document."
+        );
+        // proper nouns, list items, numbers and paragraph breaks are not joined
+        let kept =
+            "A Franco-\nGerman treaty\n\nself-\n\ncontained\n- first-\n- second\n1990-\n2000";
+        assert_eq!(clean_text(kept), kept);
+    }
+
+    #[test]
+    fn test_clean_text_code_fences() {
+        let text =
+            "Run   it:\n\n```rust\nlet  x =  1;\n\n\n\n    let y = x-\n    value;\n```\nDone.";
+        assert_eq!(
+            clean_text(text),
+            "Run it:\n\n```rust\nlet  x =  1;\n\n\n\n    let y = x-\n    value;\n```\nDone."
+        );
+    }
+
+    #[test]
+    fn test_drop_repeated_lines() {
+        let pages: Vec<String> = (1..=4)
+            .map(|i| {
+                format!(
+                    "ACME Corp. Annual report\nContent of page {}\n\nConfidential",
+                    i
+                )
+            })
+            .collect();
+        let cleaned = drop_repeated_lines(&pages, 2);
+        assert_eq!(cleaned[0], "Content of page 1\n");
+        assert_eq!(drop_repeated_lines(&pages, 4), pages);
+        // a line repeated on a single page is kept
+        let pages = vec!["Intro\nIntro".to_string(), "Body".to_string()];
+        assert_eq!(drop_repeated_lines(&pages, 1), pages);
+    }
+
+    #[test]
+    fn test_clean_document() {
+        let document = ParsedDocument::from_pages(
+            "report.pdf".to_string(),
+            (1..=3)
+                .map(|i| {
+                    format!(
+                        "ACME  Corp.\nPage {} is about infor-\nmation.\n\nFooter  ",
+                        i
+                    )
+                })
+                .collect(),
+        );
+        let cleaned = clean_document(&document, Some(1));
+        assert_eq!(
+            cleaned.pages.as_ref().unwrap()[1],
+            "Page 2 is about information."
+        );
+        assert_eq!(cleaned.text, cleaned.pages.as_ref().unwrap().join("\n"));
+        assert!(cleaned.text.len() < document.text.len());
+        let cleaned = clean_document(&document, None);
+        assert!(
+            cleaned
+                .text
+                .starts_with("ACME Corp.\nPage 1 is about information.\n\nFooter\n")
+        );
+        let text = ParsedDocument::new("notes.md".to_string(), "Some   notes.\n\n\n".to_string());
+        let cleaned = clean_document(&text, Some(1));
+        assert_eq!(cleaned.text, "Some notes.");
+        assert!(cleaned.pages.is_none());
+    }
+}
@@ -1,32 +1,676 @@
-use bm25::{Embedder, EmbedderBuilder, Embedding, LanguageMode};
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use async_openai::{Client, config::OpenAIConfig, types::embeddings::CreateEmbeddingRequestArgs};
+use async_trait::async_trait;
+use bm25::{EmbedderBuilder, Embedding, Language, LanguageMode};
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::cl100k_base_singleton;
+use tracing::warn;
 
 use crate::chunking::Chunk;
 
-const DEFAULT_AVGDL: f32 = 5.75;
+// Fallback for collections loaded before the fitted avgdl was persisted alongside them
+pub const DEFAULT_AVGDL: f32 = 5.75;
+const DEFAULT_LANGUAGE_MODE: &str = "detect";
+// Language used when the language of a text cannot be detected
+const DEFAULT_FALLBACK_LANGUAGE: &str = "en";
+// Detection is unreliable on texts shorter than this many words
+const MIN_DETECTION_WORDS: usize = 3;
+// ISO 639-1 codes of the languages supported by the BM25 tokenizer
+const LANGUAGE_CODES: [&str; 17] = [
+    "ar", "da", "de", "el", "en", "es", "fr", "hu", "it", "nl", "no", "pt", "ro", "ru", "sv", "ta",
+    "tr",
+];
+const DEFAULT_K1: f32 = 1.2;
+const DEFAULT_B: f32 = 0.75;
+// Collection metadata key under which the embedding configuration is stored
+const EMBEDDING_CONFIG_METADATA_KEY: &str = "embedding_config";
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+const OPENAI_EMBEDDING_DIMENSIONS: u64 = 1536;
+// maximum number of inputs accepted by a single OpenAI embeddings request
+const OPENAI_MAX_BATCH_SIZE: usize = 2048;
+// list price of the OpenAI embedding model, in USD per million input tokens
+const OPENAI_EMBEDDING_PRICE_PER_MILLION_TOKENS: f64 = 0.02;
 
-pub fn embed_chunks(mut chunks: Vec<Chunk>) -> Vec<Chunk> {
-    println!("Starting to embed {:?} chunks", chunks.len());
-    let embedder: Embedder = EmbedderBuilder::with_avgdl(DEFAULT_AVGDL)
-        .language_mode(LanguageMode::Detect)
-        .build();
-    let mut i = 0;
-    while i < chunks.len() {
-        let embedding = embedder.embed(&chunks[i].content);
-        chunks[i].embedding = Some(embedding);
-        i += 1;
-        if i % 10 == 0 {
-            println!("Progress: {:?}/{:?}", i, chunks.len())
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingProvider {
+    #[default]
+    Bm25,
+    OpenAI,
+    /// BM25 sparse vectors and OpenAI dense vectors on the same points
+    Hybrid,
+}
+
+impl EmbeddingProvider {
+    /// Size of the dense vectors produced by the provider, or `None` if it only produces sparse vectors
+    pub fn dense_dimensions(&self) -> Option<u64> {
+        match self {
+            EmbeddingProvider::Bm25 => None,
+            EmbeddingProvider::OpenAI | EmbeddingProvider::Hybrid => {
+                Some(OPENAI_EMBEDDING_DIMENSIONS)
+            }
+        }
+    }
+
+    /// Name of the sparse vector in the collection, if the provider produces one
+    pub fn sparse_vector_name(&self) -> Option<&'static str> {
+        match self {
+            EmbeddingProvider::Bm25 | EmbeddingProvider::Hybrid => Some("text"),
+            EmbeddingProvider::OpenAI => None,
+        }
+    }
+
+    /// Name of the dense vector in the collection, if the provider produces one.
+    /// Single-vector collections always name their vector `text`.
+    pub fn dense_vector_name(&self) -> Option<&'static str> {
+        match self {
+            EmbeddingProvider::Bm25 => None,
+            EmbeddingProvider::OpenAI => Some("text"),
+            EmbeddingProvider::Hybrid => Some("dense"),
+        }
+    }
+
+    /// Estimated cost (in USD) of embedding the texts with the dense embedding API of the provider,
+    /// from their number of `cl100k_base` tokens, or `None` if it does not call one
+    pub fn estimate_api_cost_usd(&self, texts: &[&str]) -> Option<f64> {
+        self.dense_dimensions()?;
+        let encoding = cl100k_base_singleton();
+        let tokens: usize = texts
+            .iter()
+            .map(|t| encoding.encode_ordinary(t).len())
+            .sum();
+        Some(tokens as f64 * OPENAI_EMBEDDING_PRICE_PER_MILLION_TOKENS / 1_000_000.0)
+    }
+}
+
+impl FromStr for EmbeddingProvider {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bm25" => Ok(EmbeddingProvider::Bm25),
+            "openai" => Ok(EmbeddingProvider::OpenAI),
+            "hybrid" => Ok(EmbeddingProvider::Hybrid),
+            other => Err(anyhow::anyhow!(
+                "Unsupported embedding provider: {}. Supported embedding providers are: bm25, openai, hybrid",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EmbeddingProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmbeddingProvider::Bm25 => write!(f, "bm25"),
+            EmbeddingProvider::OpenAI => write!(f, "openai"),
+            EmbeddingProvider::Hybrid => write!(f, "hybrid"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmbeddingVector {
+    Sparse(Embedding),
+    Dense(Vec<f32>),
+    Hybrid { sparse: Embedding, dense: Vec<f32> },
+}
+
+/// Turns documents and queries into vectors. Documents and queries of the same collection
+/// must be embedded by the same implementation.
+#[async_trait]
+pub trait Embedder: Send + Sync + fmt::Debug {
+    async fn embed_documents(&self, texts: &[String]) -> anyhow::Result<Vec<EmbeddingVector>>;
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<EmbeddingVector>;
+
+    /// Embed documents, tokenizing those whose language is set as that language.
+    /// Language-agnostic embedders ignore the languages.
+    async fn embed_documents_in(
+        &self,
+        texts: &[String],
+        languages: &[Option<String>],
+    ) -> anyhow::Result<Vec<EmbeddingVector>> {
+        let _ = languages;
+        self.embed_documents(texts).await
+    }
+}
+
+#[derive(Debug)]
+pub struct Bm25Embedder {
+    config: EmbeddingConfig,
+    embedder: bm25::Embedder,
+    // only set when detecting the language: used for texts whose language cannot be detected
+    fallback: Option<bm25::Embedder>,
+}
+
+impl Bm25Embedder {
+    pub fn new(config: &EmbeddingConfig) -> anyhow::Result<Self> {
+        let fallback = match config.bm25_language_mode()? {
+            LanguageMode::Detect => Some(config.bm25_embedder(config.bm25_fallback_language()?)),
+            _ => None,
+        };
+        Ok(Self {
+            config: config.clone(),
+            embedder: config.bm25_embedder(config.bm25_language_mode()?),
+            fallback,
+        })
+    }
+}
+
+impl Bm25Embedder {
+    /// Embed several texts with the same embedder: building it once means that
+    /// the per-text cost is only tokenization
+    pub fn embed_texts(&self, texts: &[String]) -> Vec<Embedding> {
+        texts.iter().map(|t| self.embed_text(t)).collect()
+    }
+
+    fn embed_text(&self, text: &str) -> Embedding {
+        match &self.fallback {
+            Some(fallback) if !is_language_detectable(text) => fallback.embed(text),
+            _ => self.embedder.embed(text),
+        }
+    }
+
+    /// Embed several texts, tokenizing those whose language is set as that language
+    /// instead of the configured one
+    pub fn embed_texts_in(
+        &self,
+        texts: &[String],
+        languages: &[Option<String>],
+    ) -> anyhow::Result<Vec<Embedding>> {
+        let mut overrides: HashMap<&str, bm25::Embedder> = HashMap::new();
+        let mut embeddings: Vec<Embedding> = vec![];
+        for (i, text) in texts.iter().enumerate() {
+            let embedding = match languages.get(i).and_then(|l| l.as_deref()) {
+                Some(language) => {
+                    if !overrides.contains_key(language) {
+                        let embedder = self.config.bm25_embedder(parse_language_mode(language)?);
+                        overrides.insert(language, embedder);
+                    }
+                    overrides[language].embed(text)
+                }
+                None => self.embed_text(text),
+            };
+            embeddings.push(embedding);
+        }
+        Ok(embeddings)
+    }
+}
+
+#[async_trait]
+impl Embedder for Bm25Embedder {
+    async fn embed_documents(&self, texts: &[String]) -> anyhow::Result<Vec<EmbeddingVector>> {
+        Ok(self
+            .embed_texts(texts)
+            .into_iter()
+            .map(EmbeddingVector::Sparse)
+            .collect())
+    }
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<EmbeddingVector> {
+        Ok(EmbeddingVector::Sparse(self.embed_text(text)))
+    }
+
+    async fn embed_documents_in(
+        &self,
+        texts: &[String],
+        languages: &[Option<String>],
+    ) -> anyhow::Result<Vec<EmbeddingVector>> {
+        Ok(self
+            .embed_texts_in(texts, languages)?
+            .into_iter()
+            .map(EmbeddingVector::Sparse)
+            .collect())
+    }
+}
+
+/// Both BM25 sparse embeddings and OpenAI dense embeddings, for hybrid retrieval
+#[derive(Debug)]
+pub struct HybridEmbedder {
+    sparse: Bm25Embedder,
+    dense: OpenAIEmbedder,
+}
+
+impl HybridEmbedder {
+    async fn embed_hybrid(
+        &self,
+        texts: &[String],
+        sparse: Vec<Embedding>,
+    ) -> anyhow::Result<Vec<EmbeddingVector>> {
+        let dense = self.dense.embed_documents(texts).await?;
+        sparse
+            .into_iter()
+            .zip(dense)
+            .map(|(sparse, dense)| match dense {
+                EmbeddingVector::Dense(dense) => Ok(EmbeddingVector::Hybrid { sparse, dense }),
+                _ => Err(anyhow::anyhow!("OpenAI embeddings should be dense")),
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Embedder for HybridEmbedder {
+    async fn embed_documents(&self, texts: &[String]) -> anyhow::Result<Vec<EmbeddingVector>> {
+        self.embed_hybrid(texts, self.sparse.embed_texts(texts))
+            .await
+    }
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<EmbeddingVector> {
+        match self.embed_documents(&[text.to_string()]).await?.pop() {
+            Some(v) => Ok(v),
+            None => Err(anyhow::anyhow!("No embedding was produced for the query")),
+        }
+    }
+
+    async fn embed_documents_in(
+        &self,
+        texts: &[String],
+        languages: &[Option<String>],
+    ) -> anyhow::Result<Vec<EmbeddingVector>> {
+        self.embed_hybrid(texts, self.sparse.embed_texts_in(texts, languages)?)
+            .await
+    }
+}
+
+/// Dense embeddings from the OpenAI API. Rate-limited (429) requests are retried
+/// with exponential backoff by the OpenAI client.
+#[derive(Debug)]
+pub struct OpenAIEmbedder {
+    client: Client<OpenAIConfig>,
+    model: String,
+}
+
+impl OpenAIEmbedder {
+    /// Uses the `OPENAI_API_KEY` environment variable if no API key is provided
+    pub fn new(openai_api_key: Option<&str>) -> Self {
+        let config = match openai_api_key {
+            Some(k) => OpenAIConfig::new().with_api_key(k),
+            None => OpenAIConfig::new(),
+        };
+        Self {
+            client: Client::with_config(config),
+            model: OPENAI_EMBEDDING_MODEL.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAIEmbedder {
+    async fn embed_documents(&self, texts: &[String]) -> anyhow::Result<Vec<EmbeddingVector>> {
+        let mut vectors: Vec<EmbeddingVector> = vec![];
+        for batch in texts.chunks(OPENAI_MAX_BATCH_SIZE) {
+            let request = CreateEmbeddingRequestArgs::default()
+                .model(&self.model)
+                .input(batch.to_vec())
+                .build()?;
+            let mut response = self.client.embeddings().create(request).await?;
+            // the embeddings are not guaranteed to be returned in input order
+            response.data.sort_by_key(|e| e.index);
+            if response.data.len() != batch.len() {
+                return Err(anyhow::anyhow!(
+                    "OpenAI returned {} embeddings for {} inputs",
+                    response.data.len(),
+                    batch.len()
+                ));
+            }
+            vectors.extend(
+                response
+                    .data
+                    .into_iter()
+                    .map(|e| EmbeddingVector::Dense(e.embedding)),
+            );
+        }
+        Ok(vectors)
+    }
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<EmbeddingVector> {
+        match self
+            .embed_documents(&[text.to_string()])
+            .await?
+            .into_iter()
+            .next()
+        {
+            Some(v) => Ok(v),
+            None => Err(anyhow::anyhow!("OpenAI did not return any embedding")),
+        }
+    }
+}
+
+/// Parameters a collection was embedded with. They are stored alongside the collection,
+/// so that queries are embedded exactly like the documents they are matched against.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmbeddingConfig {
+    // collections created before providers were configurable are BM25 collections
+    #[serde(default)]
+    pub provider: EmbeddingProvider,
+    pub avgdl: f32,
+    pub language_mode: String,
+    pub k1: f32,
+    pub b: f32,
+    // ISO 639-1 code of the language used when the language of a chunk cannot be detected
+    #[serde(default = "default_fallback_language")]
+    pub fallback_language: String,
+}
+
+fn default_fallback_language() -> String {
+    DEFAULT_FALLBACK_LANGUAGE.to_string()
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            provider: EmbeddingProvider::default(),
+            avgdl: DEFAULT_AVGDL,
+            language_mode: DEFAULT_LANGUAGE_MODE.to_string(),
+            k1: DEFAULT_K1,
+            b: DEFAULT_B,
+            fallback_language: default_fallback_language(),
+        }
+    }
+}
+
+impl EmbeddingConfig {
+    fn bm25_language_mode(&self) -> anyhow::Result<LanguageMode> {
+        parse_language_mode(&self.language_mode)
+    }
+
+    fn bm25_fallback_language(&self) -> anyhow::Result<LanguageMode> {
+        parse_fixed_language(&self.fallback_language)
+    }
+
+    fn bm25_embedder(&self, language_mode: LanguageMode) -> bm25::Embedder {
+        EmbedderBuilder::with_avgdl(self.avgdl)
+            .language_mode(language_mode)
+            .k1(self.k1)
+            .b(self.b)
+            .build()
+    }
+
+    /// Build the embedder of the configured provider
+    pub fn build_embedder(
+        &self,
+        openai_api_key: Option<&str>,
+    ) -> anyhow::Result<Arc<dyn Embedder>> {
+        match self.provider {
+            EmbeddingProvider::Bm25 => Ok(Arc::new(Bm25Embedder::new(self)?)),
+            EmbeddingProvider::OpenAI => Ok(Arc::new(OpenAIEmbedder::new(openai_api_key))),
+            EmbeddingProvider::Hybrid => Ok(Arc::new(HybridEmbedder {
+                sparse: Bm25Embedder::new(self)?,
+                dense: OpenAIEmbedder::new(openai_api_key),
+            })),
         }
     }
-    chunks
+
+    /// Fit the average document length (in tokens) of the corpus, keeping the other parameters
+    pub fn fit_to_corpus(&self, corpus: &[&str]) -> anyhow::Result<Self> {
+        let embedder: bm25::Embedder =
+            EmbedderBuilder::with_fit_to_corpus(self.bm25_language_mode()?, corpus).build();
+        Ok(Self {
+            avgdl: embedder.avgdl(),
+            ..self.clone()
+        })
+    }
+
+    pub fn to_metadata(&self) -> anyhow::Result<HashMap<String, serde_json::Value>> {
+        Ok(HashMap::from([(
+            EMBEDDING_CONFIG_METADATA_KEY.to_string(),
+            serde_json::to_value(self)?,
+        )]))
+    }
+
+    /// Read the configuration from the collection metadata, if it was stored
+    pub fn from_metadata(
+        metadata: &HashMap<String, serde_json::Value>,
+    ) -> anyhow::Result<Option<Self>> {
+        match metadata.get(EMBEDDING_CONFIG_METADATA_KEY) {
+            Some(value) => {
+                let config: EmbeddingConfig = serde_json::from_value(value.clone())?;
+                // fail early on configurations this version cannot reproduce
+                config.bm25_language_mode()?;
+                config.bm25_fallback_language()?;
+                Ok(Some(config))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Embedding parameters explicitly chosen for a run. Unset parameters are taken from the
+/// configuration stored in the collection, or from the defaults for a new collection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EmbeddingOptions {
+    pub provider: Option<EmbeddingProvider>,
+    pub language_mode: Option<String>,
+    pub k1: Option<f32>,
+    pub b: Option<f32>,
+    pub fallback_language: Option<String>,
 }
 
-pub fn embed_text(text: String) -> Embedding {
-    let embedder: Embedder = EmbedderBuilder::with_avgdl(DEFAULT_AVGDL)
-        .language_mode(LanguageMode::Detect)
-        .build();
+impl EmbeddingOptions {
+    pub fn new(
+        provider: Option<String>,
+        language: Option<String>,
+        k1: Option<f32>,
+        b: Option<f32>,
+        fallback_language: Option<String>,
+    ) -> anyhow::Result<Self> {
+        if let Some(k1) = k1
+            && k1 < 0.0
+        {
+            return Err(anyhow::anyhow!("k1 should be non-negative, got {}", k1));
+        }
+        if let Some(b) = b
+            && !(0.0..=1.0).contains(&b)
+        {
+            return Err(anyhow::anyhow!("b should be between 0 and 1, got {}", b));
+        }
+        Ok(Self {
+            provider: provider.map(|p| p.parse()).transpose()?,
+            language_mode: language.map(|l| normalize_language(&l)).transpose()?,
+            k1,
+            b,
+            fallback_language: fallback_language
+                .map(|l| normalize_language_code(&l))
+                .transpose()?,
+        })
+    }
+
+    /// Configuration for a collection that does not store one yet
+    pub fn to_config(&self) -> EmbeddingConfig {
+        let default = EmbeddingConfig::default();
+        EmbeddingConfig {
+            provider: self.provider.unwrap_or(default.provider),
+            language_mode: self.language_mode.clone().unwrap_or(default.language_mode),
+            k1: self.k1.unwrap_or(default.k1),
+            b: self.b.unwrap_or(default.b),
+            avgdl: default.avgdl,
+            fallback_language: self
+                .fallback_language
+                .clone()
+                .unwrap_or(default.fallback_language),
+        }
+    }
+
+    /// Check that the explicitly chosen parameters do not conflict with the stored ones
+    pub fn check_compatible(&self, stored: &EmbeddingConfig) -> anyhow::Result<()> {
+        let mut conflicts: Vec<String> = vec![];
+        if let Some(provider) = self.provider
+            && provider != stored.provider
+        {
+            conflicts.push(format!(
+                "provider ('{}' requested, '{}' stored)",
+                provider, stored.provider
+            ));
+        }
+        if let Some(language_mode) = &self.language_mode
+            && *language_mode != stored.language_mode
+        {
+            conflicts.push(format!(
+                "language mode ('{}' requested, '{}' stored)",
+                language_mode, stored.language_mode
+            ));
+        }
+        if let Some(k1) = self.k1
+            && k1 != stored.k1
+        {
+            conflicts.push(format!("k1 ({} requested, {} stored)", k1, stored.k1));
+        }
+        if let Some(b) = self.b
+            && b != stored.b
+        {
+            conflicts.push(format!("b ({} requested, {} stored)", b, stored.b));
+        }
+        if let Some(fallback_language) = &self.fallback_language
+            && *fallback_language != stored.fallback_language
+        {
+            conflicts.push(format!(
+                "fallback language ('{}' requested, '{}' stored)",
+                fallback_language, stored.fallback_language
+            ));
+        }
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "The embedding configuration conflicts with the one the collection was built with: {}",
+                conflicts.join(", ")
+            ))
+        }
+    }
+}
 
-    embedder.embed(&text)
+/// Normalize a `--language` value: `auto` (or `detect`) detects the language of each chunk,
+/// an ISO 639-1 code (e.g. `en`) tokenizes every chunk as that language
+pub fn normalize_language(language: &str) -> anyhow::Result<String> {
+    let language = language.trim().to_lowercase();
+    let normalized = match language.as_str() {
+        "auto" | "detect" => DEFAULT_LANGUAGE_MODE.to_string(),
+        _ => language,
+    };
+    parse_language_mode(&normalized)?;
+    Ok(normalized)
+}
+
+/// Normalize an ISO 639-1 language code, such as the `--default-language` value
+pub fn normalize_language_code(language: &str) -> anyhow::Result<String> {
+    let normalized = language.trim().to_lowercase();
+    parse_fixed_language(&normalized)?;
+    Ok(normalized)
+}
+
+fn parse_fixed_language(language: &str) -> anyhow::Result<LanguageMode> {
+    match parse_language_mode(language)? {
+        LanguageMode::Detect => Err(anyhow::anyhow!(
+            "The fallback language should be an ISO 639-1 code, one of: {}",
+            LANGUAGE_CODES.join(", ")
+        )),
+        fixed => Ok(fixed),
+    }
+}
+
+/// ISO 639-1 code of the language of the text, if it can be detected reliably
+/// and is supported by the tokenizer
+pub fn detect_language(text: &str) -> Option<String> {
+    if !is_language_detectable(text) {
+        return None;
+    }
+    let detected = Language::try_from(whichlang::detect_language(text)).ok()?;
+    LANGUAGE_CODES
+        .into_iter()
+        .find(|c| matches!(parse_language_mode(c), Ok(LanguageMode::Fixed(l)) if l == detected))
+        .map(|c| c.to_string())
+}
+
+/// Whether the language of the text can be detected reliably, and is supported by the tokenizer
+fn is_language_detectable(text: &str) -> bool {
+    text.split_whitespace().count() >= MIN_DETECTION_WORDS
+        && Language::try_from(whichlang::detect_language(text)).is_ok()
+}
+
+fn parse_language_mode(language_mode: &str) -> anyhow::Result<LanguageMode> {
+    let language = match language_mode {
+        "detect" => return Ok(LanguageMode::Detect),
+        "ar" => Language::Arabic,
+        "da" => Language::Danish,
+        "de" => Language::German,
+        "el" => Language::Greek,
+        "en" => Language::English,
+        "es" => Language::Spanish,
+        "fr" => Language::French,
+        "hu" => Language::Hungarian,
+        "it" => Language::Italian,
+        "nl" => Language::Dutch,
+        "no" => Language::Norwegian,
+        "pt" => Language::Portuguese,
+        "ro" => Language::Romanian,
+        "ru" => Language::Russian,
+        "sv" => Language::Swedish,
+        "ta" => Language::Tamil,
+        "tr" => Language::Turkish,
+        other => {
+            return Err(anyhow::anyhow!(
+                "Unsupported language mode: {}. Supported language modes are: auto, {}",
+                other,
+                LANGUAGE_CODES.join(", ")
+            ));
+        }
+    };
+    Ok(LanguageMode::Fixed(language))
+}
+
+pub async fn embed_chunks(
+    mut chunks: Vec<Chunk>,
+    embedder: &dyn Embedder,
+) -> anyhow::Result<Vec<Chunk>> {
+    println!("Starting to embed {:?} chunks", chunks.len());
+    let total = chunks.len();
+    let mut done = 0;
+    // embed in batches, so that progress is reported and remote providers get bounded requests
+    for batch in chunks.chunks_mut(OPENAI_MAX_BATCH_SIZE) {
+        let texts: Vec<String> = batch.iter().map(|c| c.content.clone()).collect();
+        let languages: Vec<Option<String>> =
+            batch.iter().map(|c| c.language_override.clone()).collect();
+        let vectors = embedder.embed_documents_in(&texts, &languages).await?;
+        for (chunk, vector) in batch.iter_mut().zip(vectors) {
+            chunk.language = chunk
+                .language_override
+                .clone()
+                .or_else(|| detect_language(&chunk.content));
+            match vector {
+                EmbeddingVector::Sparse(sparse) => chunk.sparse_embedding = Some(sparse),
+                EmbeddingVector::Dense(dense) => chunk.dense_embedding = Some(dense),
+                EmbeddingVector::Hybrid { sparse, dense } => {
+                    chunk.sparse_embedding = Some(sparse);
+                    chunk.dense_embedding = Some(dense);
+                }
+            }
+        }
+        done += batch.len();
+        println!("Progress: {:?}/{:?}", done, total)
+    }
+    // e.g. whitespace-only chunks have no BM25 tokens, and could never be retrieved
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .filter(|(i, chunk)| {
+            let empty = chunk.dense_embedding.is_none()
+                && chunk
+                    .sparse_embedding
+                    .as_ref()
+                    .is_some_and(|e| e.0.is_empty());
+            if empty {
+                warn!(
+                    chunk_index = i,
+                    "Chunk produced an empty embedding, skipping upload"
+                );
+            }
+            !empty
+        })
+        .map(|(_, chunk)| chunk)
+        .collect())
 }
 
 #[cfg(test)]
@@ -34,14 +678,396 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_embed_chunks() {
+    fn test_estimate_api_cost() {
+        let texts = ["hello world", "bye world"];
+        // 4 tokens at $0.02 per million
+        let cost = EmbeddingProvider::OpenAI
+            .estimate_api_cost_usd(&texts)
+            .unwrap();
+        assert!((cost - 4.0 * 0.02 / 1_000_000.0).abs() < 1e-12);
+        assert_eq!(
+            EmbeddingProvider::Hybrid.estimate_api_cost_usd(&texts),
+            Some(cost)
+        );
+        assert_eq!(EmbeddingProvider::Bm25.estimate_api_cost_usd(&texts), None);
+        assert_eq!(
+            EmbeddingProvider::OpenAI.estimate_api_cost_usd(&[]),
+            Some(0.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embed_chunks() {
         let mut chunks: Vec<Chunk> = vec![
             Chunk::from_content("hello world".to_string()),
             Chunk::from_content("bye world".to_string()),
         ];
-        chunks = embed_chunks(chunks);
+        let embedder = EmbeddingConfig::default().build_embedder(None).unwrap();
+        chunks = embed_chunks(chunks, embedder.as_ref()).await.unwrap();
         for c in chunks {
-            assert!(c.embedding.is_some());
+            assert!(c.sparse_embedding.is_some());
+            assert!(c.dense_embedding.is_none());
         }
     }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_embed_chunks_skips_empty_embeddings() {
+        let chunks: Vec<Chunk> = vec![
+            Chunk::from_content("hello world".to_string()),
+            Chunk::from_content(" \n\t ".to_string()),
+            Chunk::from_content("bye world".to_string()),
+        ];
+        let embedder = EmbeddingConfig::default().build_embedder(None).unwrap();
+        let embedded = embed_chunks(chunks, embedder.as_ref()).await.unwrap();
+        assert_eq!(
+            embedded
+                .iter()
+                .map(|c| c.content.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["hello world", "bye world"]
+        );
+        assert!(logs_contain(
+            "Chunk produced an empty embedding, skipping upload chunk_index=1"
+        ));
+    }
+
+    #[test]
+    fn test_fit_to_corpus() {
+        let corpus = vec!["the quick brown fox jumps over the lazy dog", "hello world"];
+        let config = EmbeddingConfig::default().fit_to_corpus(&corpus).unwrap();
+        // stop words are removed by the tokenizer, so the average is below the raw word count
+        assert!(config.avgdl > 1.0 && config.avgdl < 5.5);
+        assert_eq!(config.language_mode, DEFAULT_LANGUAGE_MODE);
+        let same_config = EmbeddingConfig::default().fit_to_corpus(&corpus).unwrap();
+        assert_eq!(config, same_config);
+    }
+
+    #[tokio::test]
+    async fn test_embed_query_uses_avgdl() {
+        let text = "rust retrieval augmented generation";
+        let short_avgdl = Bm25Embedder::new(&EmbeddingConfig {
+            avgdl: 1.0,
+            ..Default::default()
+        })
+        .unwrap();
+        let long_avgdl = Bm25Embedder::new(&EmbeddingConfig {
+            avgdl: 100.0,
+            ..Default::default()
+        })
+        .unwrap();
+        let short_vector = short_avgdl.embed_query(text).await.unwrap();
+        let long_vector = long_avgdl.embed_query(text).await.unwrap();
+        assert_ne!(short_vector, long_vector);
+        let documents = short_avgdl
+            .embed_documents(&[text.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(documents, vec![short_vector]);
+    }
+
+    #[tokio::test]
+    async fn test_shared_embedder_matches_per_call_embedder() {
+        let config = EmbeddingConfig {
+            avgdl: 12.0,
+            ..Default::default()
+        };
+        let texts = vec![
+            "rust retrieval augmented generation".to_string(),
+            "the quick brown fox jumps over the lazy dog".to_string(),
+            "bonjour tout le monde".to_string(),
+        ];
+        let shared = Bm25Embedder::new(&config).unwrap();
+        let batch = shared.embed_texts(&texts);
+        for (text, embedding) in texts.iter().zip(batch) {
+            let per_call = Bm25Embedder::new(&config).unwrap();
+            assert_eq!(
+                per_call.embed_query(text).await.unwrap(),
+                EmbeddingVector::Sparse(embedding.clone())
+            );
+            // reusing the embedder across queries does not change its output
+            assert_eq!(
+                shared.embed_query(text).await.unwrap(),
+                EmbeddingVector::Sparse(embedding)
+            );
+        }
+    }
+
+    #[test]
+    fn test_embedding_provider() {
+        assert_eq!(
+            EmbeddingProvider::from_str("bm25").unwrap(),
+            EmbeddingProvider::Bm25
+        );
+        assert_eq!(
+            EmbeddingProvider::from_str("openai").unwrap(),
+            EmbeddingProvider::OpenAI
+        );
+        assert!(EmbeddingProvider::from_str("word2vec").is_err());
+        assert_eq!(EmbeddingProvider::OpenAI.to_string(), "openai");
+        assert_eq!(
+            EmbeddingProvider::from_str("hybrid").unwrap(),
+            EmbeddingProvider::Hybrid
+        );
+        assert_eq!(EmbeddingProvider::Hybrid.sparse_vector_name(), Some("text"));
+        assert_eq!(EmbeddingProvider::Hybrid.dense_vector_name(), Some("dense"));
+        assert_eq!(EmbeddingProvider::OpenAI.dense_vector_name(), Some("text"));
+        assert_eq!(EmbeddingProvider::Bm25.dense_dimensions(), None);
+        assert_eq!(
+            EmbeddingProvider::OpenAI.dense_dimensions(),
+            Some(OPENAI_EMBEDDING_DIMENSIONS)
+        );
+        // configurations stored before providers were introduced are BM25 ones
+        let legacy: EmbeddingConfig = serde_json::from_value(
+            serde_json::json!({"avgdl": 1.0, "language_mode": "detect", "k1": 1.2, "b": 0.75}),
+        )
+        .unwrap();
+        assert_eq!(legacy.provider, EmbeddingProvider::Bm25);
+        assert_eq!(legacy.fallback_language, DEFAULT_FALLBACK_LANGUAGE);
+    }
+
+    #[tokio::test]
+    async fn test_openai_embedder() {
+        if std::env::var("OPENAI_API_KEY").is_err() {
+            println!("Skipping test because OpenAI API key is not available");
+            return;
+        }
+        let embedder = OpenAIEmbedder::new(None);
+        let vectors = embedder
+            .embed_documents(&["hello world".to_string(), "bye world".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(vectors.len(), 2);
+        for v in vectors {
+            match v {
+                EmbeddingVector::Dense(d) => {
+                    assert_eq!(d.len() as u64, OPENAI_EMBEDDING_DIMENSIONS)
+                }
+                _ => panic!("OpenAI embeddings should be dense"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_metadata_round_trip() {
+        let config = EmbeddingConfig {
+            avgdl: 42.5,
+            ..Default::default()
+        };
+        let metadata = config.to_metadata().unwrap();
+        let stored = EmbeddingConfig::from_metadata(&metadata).unwrap();
+        assert_eq!(stored, Some(config));
+        assert_eq!(
+            EmbeddingConfig::from_metadata(&HashMap::new()).unwrap(),
+            None
+        );
+        let unsupported = HashMap::from([(
+            EMBEDDING_CONFIG_METADATA_KEY.to_string(),
+            serde_json::json!({"avgdl": 1.0, "language_mode": "klingon", "k1": 1.2, "b": 0.75}),
+        )]);
+        assert!(EmbeddingConfig::from_metadata(&unsupported).is_err());
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let stored = EmbeddingConfig {
+            avgdl: 300.0,
+            k1: 2.0,
+            ..Default::default()
+        };
+        // unset options are taken from the stored configuration
+        assert!(
+            EmbeddingOptions::default()
+                .check_compatible(&stored)
+                .is_ok()
+        );
+        let matching = EmbeddingOptions {
+            k1: Some(2.0),
+            ..Default::default()
+        };
+        assert!(matching.check_compatible(&stored).is_ok());
+        let conflicting = EmbeddingOptions {
+            k1: Some(1.2),
+            ..Default::default()
+        };
+        let err = conflicting.check_compatible(&stored).unwrap_err();
+        assert!(err.to_string().contains("k1"));
+        let other_provider = EmbeddingOptions {
+            provider: Some(EmbeddingProvider::OpenAI),
+            ..Default::default()
+        };
+        let err_1 = other_provider.check_compatible(&stored).unwrap_err();
+        assert!(err_1.to_string().contains("provider"));
+    }
+
+    #[test]
+    fn test_options_new() {
+        let options = EmbeddingOptions::new(
+            Some("bm25".to_string()),
+            Some("auto".to_string()),
+            Some(1.5),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(options.provider, Some(EmbeddingProvider::Bm25));
+        assert_eq!(options.language_mode, Some("detect".to_string()));
+        assert_eq!(options.k1, Some(1.5));
+        assert!(options.b.is_none());
+        assert!(EmbeddingOptions::new(None, None, Some(-1.0), None, None).is_err());
+        assert!(EmbeddingOptions::new(None, None, None, Some(1.5), None).is_err());
+        assert!(EmbeddingOptions::new(None, Some("xx".to_string()), None, None, None).is_err());
+        // the fallback language is used when detection fails, so it cannot be detected itself
+        assert!(EmbeddingOptions::new(None, None, None, None, Some("auto".to_string())).is_err());
+        let fallback =
+            EmbeddingOptions::new(None, None, None, None, Some("IT".to_string())).unwrap();
+        assert_eq!(fallback.fallback_language, Some("it".to_string()));
+    }
+
+    #[test]
+    fn test_options_to_config() {
+        assert_eq!(
+            EmbeddingOptions::default().to_config(),
+            EmbeddingConfig::default()
+        );
+        let options = EmbeddingOptions {
+            language_mode: Some("fr".to_string()),
+            b: Some(0.5),
+            ..Default::default()
+        };
+        let config = options.to_config();
+        assert_eq!(config.language_mode, "fr");
+        assert_eq!(config.b, 0.5);
+        assert_eq!(config.k1, DEFAULT_K1);
+    }
+
+    #[test]
+    fn test_normalize_language() {
+        assert_eq!(normalize_language("auto").unwrap(), "detect");
+        assert_eq!(normalize_language("EN").unwrap(), "en");
+        for code in LANGUAGE_CODES {
+            assert_eq!(normalize_language(code).unwrap(), code);
+        }
+        assert!(normalize_language("klingon").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fixed_language_is_independent_of_corpus() {
+        let text = "Le chat dort sur le canapé";
+        let english_corpus = vec![
+            "the quick brown fox jumps over the lazy dog",
+            "hello world, this is an english document",
+        ];
+        let mixed_corpus = vec!["Le chat dort sur le canapé", "bonjour tout le monde"];
+        let base = EmbeddingConfig {
+            language_mode: "en".to_string(),
+            ..Default::default()
+        };
+        let mut indices: Vec<Vec<u32>> = vec![];
+        for corpus in [english_corpus, mixed_corpus] {
+            let config = base.fit_to_corpus(&corpus).unwrap();
+            let embedder = Bm25Embedder::new(&config).unwrap();
+            match embedder.embed_query(text).await.unwrap() {
+                EmbeddingVector::Sparse(e) => {
+                    let mut chunk_indices: Vec<u32> = e.indices().copied().collect();
+                    chunk_indices.sort();
+                    indices.push(chunk_indices);
+                }
+                _ => panic!("BM25 embeddings should be sparse"),
+            }
+        }
+        assert_eq!(indices[0], indices[1]);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_language() {
+        let short_text = "running dogs";
+        let detect = Bm25Embedder::new(&EmbeddingConfig {
+            fallback_language: "en".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let english = Bm25Embedder::new(&EmbeddingConfig {
+            language_mode: "en".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        // too short to detect the language: tokenized as the fallback language
+        assert!(!is_language_detectable(short_text));
+        assert_eq!(
+            detect.embed_query(short_text).await.unwrap(),
+            english.embed_query(short_text).await.unwrap()
+        );
+        let italian_fallback = Bm25Embedder::new(&EmbeddingConfig {
+            fallback_language: "it".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_ne!(
+            italian_fallback.embed_query(short_text).await.unwrap(),
+            english.embed_query(short_text).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_language_override() {
+        let mut italian_chunk = Chunk::from_content("i cani corrono nel parco".to_string());
+        italian_chunk.language_override = Some("it".to_string());
+        let chunks = vec![
+            italian_chunk,
+            Chunk::from_content("i cani corrono nel parco".to_string()),
+        ];
+        let config = EmbeddingConfig {
+            language_mode: "en".to_string(),
+            ..Default::default()
+        };
+        let embedder = config.build_embedder(None).unwrap();
+        let embedded = embed_chunks(chunks, embedder.as_ref()).await.unwrap();
+        let italian = Bm25Embedder::new(&EmbeddingConfig {
+            language_mode: "it".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            embedded[0].sparse_embedding,
+            Some(
+                italian
+                    .embed_texts(&[embedded[0].content.clone()])
+                    .remove(0)
+            )
+        );
+        assert_ne!(embedded[0].sparse_embedding, embedded[1].sparse_embedding);
+        let mut invalid = Chunk::from_content("hello".to_string());
+        invalid.language_override = Some("klingon".to_string());
+        assert!(
+            embed_chunks(vec![invalid], embedder.as_ref())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detect_language() {
+        assert_eq!(
+            detect_language("Il gatto dorme tranquillamente sul divano del soggiorno"),
+            Some("it".to_string())
+        );
+        assert_eq!(
+            detect_language("The cat is sleeping peacefully on the living room couch"),
+            Some("en".to_string())
+        );
+        assert_eq!(detect_language("ciao"), None);
+        let mut overridden = Chunk::from_content(
+            "The cat is sleeping peacefully on the living room couch".to_string(),
+        );
+        overridden.language_override = Some("fr".to_string());
+        let chunks = vec![
+            Chunk::from_content("Il gatto dorme tranquillamente sul divano".to_string()),
+            overridden,
+        ];
+        let embedder = EmbeddingConfig::default().build_embedder(None).unwrap();
+        let embedded = embed_chunks(chunks, embedder.as_ref()).await.unwrap();
+        assert_eq!(embedded[0].language, Some("it".to_string()));
+        assert_eq!(embedded[1].language, Some("fr".to_string()));
+    }
 }
@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use scraper::{ElementRef, Html, Node, Selector};
+use tokio::fs;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+// Elements that never carry readable content: dropped with all of their children
+const BOILERPLATE_TAGS: [&str; 13] = [
+    "script", "style", "noscript", "nav", "header", "footer", "aside", "form", "iframe", "svg",
+    "template", "button", "select",
+];
+const BLOCK_TAGS: [&str; 24] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "br",
+    "li",
+    "ul",
+    "ol",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "pre",
+    "blockquote",
+    "table",
+    "tr",
+    "td",
+    "th",
+    "dd",
+    "dt",
+    "figcaption",
+];
+
+pub struct Fetcher {
+    pub timeout_secs: u64,
+    pub max_size: usize,
+    pub max_redirects: usize,
+}
+
+impl Fetcher {
+    pub fn new(
+        timeout_secs: Option<u64>,
+        max_size: Option<usize>,
+        max_redirects: Option<usize>,
+    ) -> Self {
+        Self {
+            timeout_secs: timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+            max_size: max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            max_redirects: max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS),
+        }
+    }
+
+    async fn fetch_url(&self, client: &reqwest::Client, url: &str) -> anyhow::Result<String> {
+        let mut response = client.get(url).send().await?.error_for_status()?;
+        if let Some(length) = response.content_length()
+            && length as usize > self.max_size
+        {
+            return Err(anyhow::anyhow!(
+                "Page size ({} bytes) exceeds the maximum allowed size ({} bytes)",
+                length,
+                self.max_size
+            ));
+        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("text/html")
+            .to_lowercase();
+        let mut bytes: Vec<u8> = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if bytes.len() + chunk.len() > self.max_size {
+                return Err(anyhow::anyhow!(
+                    "Page size exceeds the maximum allowed size ({} bytes)",
+                    self.max_size
+                ));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        if content_type.starts_with("application/pdf") {
+            Ok(pdf_extract::extract_text_from_mem(&bytes)?)
+        } else if content_type.starts_with("text/html")
+            || content_type.starts_with("application/xhtml")
+        {
+            Ok(extract_readable_text(&String::from_utf8_lossy(&bytes)))
+        } else if content_type.starts_with("text/") {
+            Ok(String::from_utf8_lossy(&bytes).to_string())
+        } else {
+            Err(anyhow::anyhow!(
+                "Unsupported content type: {}. Supported content types are: HTML, PDF and plain text",
+                content_type
+            ))
+        }
+    }
+
+    /// Fetch all the URLs, returning (url, text) pairs for the pages that could be fetched.
+    /// Failures are reported per URL and do not abort the run.
+    pub async fn fetch(&self, urls: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.timeout_secs))
+            .redirect(reqwest::redirect::Policy::limited(self.max_redirects))
+            .build()?;
+        let mut results: Vec<(String, String)> = vec![];
+        for url in urls {
+            println!("Fetching {}", url);
+            match self.fetch_url(&client, url).await {
+                Ok(text) => {
+                    if text.trim().is_empty() {
+                        eprintln!("No readable text found at {}, skipping...", url);
+                        continue;
+                    }
+                    println!("Text size: {:?} chars", text.len());
+                    results.push((url.clone(), text));
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch {}: {}", url, e);
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// Read a list of URLs from a file, one per line. Blank lines and lines starting with `#` are ignored.
+pub async fn read_urls_file(file_path: &str) -> anyhow::Result<Vec<String>> {
+    let content = fs::read_to_string(file_path).await?;
+    Ok(content
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect())
+}
+
+fn collect_text(element: ElementRef, out: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => out.push_str(text),
+            Node::Element(el) => {
+                let name = el.name();
+                if BOILERPLATE_TAGS.contains(&name) {
+                    continue;
+                }
+                if let Some(child_element) = ElementRef::wrap(child) {
+                    let is_block = BLOCK_TAGS.contains(&name);
+                    if is_block {
+                        out.push('\n');
+                    }
+                    collect_text(child_element, out);
+                    if is_block {
+                        out.push('\n');
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Readability-style extraction: picks the main content root (`article`, `main` or `body`),
+/// drops scripts, styles and navigation boilerplate, and normalizes whitespace.
+pub fn extract_readable_text(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let mut root = document.root_element();
+    for candidate in ["article", "main", "body"] {
+        let selector = Selector::parse(candidate).expect("Should be able to parse a tag selector");
+        if let Some(el) = document.select(&selector).next() {
+            root = el;
+            break;
+        }
+    }
+    let mut raw = String::new();
+    collect_text(root, &mut raw);
+    raw.lines()
+        .map(|l| l.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_correct_fetcher_init() {
+        let fetcher = Fetcher::new(None, None, None);
+        assert_eq!(fetcher.timeout_secs, DEFAULT_TIMEOUT_SECS);
+        assert_eq!(fetcher.max_size, DEFAULT_MAX_SIZE);
+        assert_eq!(fetcher.max_redirects, DEFAULT_MAX_REDIRECTS);
+        let fetcher_1 = Fetcher::new(Some(5), Some(1024), Some(0));
+        assert_eq!(fetcher_1.timeout_secs, 5);
+        assert_eq!(fetcher_1.max_size, 1024);
+        assert_eq!(fetcher_1.max_redirects, 0);
+    }
+
+    #[test]
+    fn test_extract_readable_text() {
+        let html = r#"<html><head><title>Title</title><style>p { color: red; }</style></head>
+        <body>
+            <nav><a href="/">Home</a> <a href="/about">About</a></nav>
+            <main>
+                <h1>Hello   world</h1>
+                <p>This is the <b>main</b> content.</p>
+                <script>console.log("should not appear");</script>
+            </main>
+            <footer>Copyright</footer>
+        </body></html>"#;
+        let text = extract_readable_text(html);
+        assert_eq!(text, "Hello world\nThis is the main content.");
+    }
+
+    #[test]
+    fn test_extract_readable_text_without_main() {
+        let html = "<html><body><header>Menu</header><div>Only content</div></body></html>";
+        let text = extract_readable_text(html);
+        assert_eq!(text, "Only content");
+    }
+
+    #[tokio::test]
+    async fn test_read_urls_file() {
+        let file_path = std::env::temp_dir().join("rag-rs-test-urls.txt");
+        fs::write(
+            &file_path,
+            "# comment\nhttps://example.com\n\n  https://example.org/page  \n",
+        )
+        .await
+        .unwrap();
+        let urls = read_urls_file(file_path.to_str().unwrap()).await.unwrap();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com".to_string(),
+                "https://example.org/page".to_string()
+            ]
+        );
+    }
+}
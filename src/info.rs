@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+
+const UNKNOWN: &str = "unknown";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DependencyVersion {
+    pub name: String,
+    pub version: String,
+}
+
+/// Version and build metadata, captured at compile time by `build.rs`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_commit: String,
+    pub rustc: String,
+    pub dependencies: Vec<DependencyVersion>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let dependencies = [
+            ("qdrant-client", option_env!("DEP_VERSION_QDRANT_CLIENT")),
+            ("async-openai", option_env!("DEP_VERSION_ASYNC_OPENAI")),
+            ("bm25", option_env!("DEP_VERSION_BM25")),
+            ("axum", option_env!("DEP_VERSION_AXUM")),
+        ];
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: option_env!("GIT_HASH").unwrap_or(UNKNOWN).to_string(),
+            rustc: option_env!("RUSTC_VERSION").unwrap_or(UNKNOWN).to_string(),
+            dependencies: dependencies
+                .into_iter()
+                .map(|(name, version)| DependencyVersion {
+                    name: name.to_string(),
+                    version: version.unwrap_or(UNKNOWN).to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn to_table(&self) -> String {
+        let mut rows: Vec<(String, String)> = vec![
+            ("rag-rs".to_string(), self.version.clone()),
+            ("git commit".to_string(), self.git_commit.clone()),
+            ("rustc".to_string(), self.rustc.clone()),
+        ];
+        rows.extend(
+            self.dependencies
+                .iter()
+                .map(|d| (d.name.clone(), d.version.clone())),
+        );
+        let width = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        rows.iter()
+            .map(|(k, v)| format!("{:<width$}  {}", k, v, width = width))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.dependencies.len(), 4);
+        let table = info.to_table();
+        for name in [
+            "rag-rs",
+            "git commit",
+            "rustc",
+            "qdrant-client",
+            "async-openai",
+            "bm25",
+            "axum",
+        ] {
+            assert!(table.contains(name));
+        }
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["git_commit"].is_string());
+    }
+}
@@ -1,20 +1,64 @@
 mod caching;
+mod checkpointing;
 mod chunking;
+mod cleaning;
 mod embedding;
+mod fetching;
+mod info;
+mod migrations;
 mod parsing;
 mod pipeline;
+mod retry;
+mod s3;
 mod serving;
 mod vectordb;
 
-use clap::{Parser, Subcommand};
+use std::{io::Write, path::Path, time::Duration};
 
-use crate::{pipeline::Pipeline, serving::RagServer};
+use clap::{Args, Parser, Subcommand};
+use tokio::io::AsyncReadExt;
+use tracing::Level;
+use tracing_subscriber::{filter::LevelFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{
+    caching::{Cache, CacheOptions, format_age, format_duration, parse_age, parse_size},
+    chunking::ChunkingConfig,
+    embedding::{EmbeddingConfig, EmbeddingOptions},
+    fetching::read_urls_file,
+    info::BuildInfo,
+    migrations::{MIGRATIONS, run_migration},
+    pipeline::{InMemorySource, Pipeline},
+    s3::S3Source,
+    serving::{RagServer, parse_collection_alias},
+    vectordb::{VectorDB, parse_payload_value},
+};
 
 #[derive(Parser)]
 struct CliArgs {
+    /// Activate debug logging, forcing compact (non-JSON) output.
+    #[arg(long, default_value_t = false)]
+    verbose: bool,
+
     #[command(subcommand)]
     cmd: Commands,
 }
+
+/// Connection options for the Qdrant vector store, shared by the subcommands
+#[derive(Args, Debug)]
+struct QdrantArgs {
+    /// URL for a Qdrant vector store instance.
+    /// If your Qdrant instance needs an API key, make sure that
+    /// it is available as `QDRANT_API_KEY` in your environment
+    #[arg(long)]
+    qdrant_url: String,
+
+    /// Connect to Qdrant with TLS (e.g. for Qdrant Cloud), verifying the server with the system root certificates.
+    #[arg(long, default_value_t = false)]
+    qdrant_tls: bool,
+}
+
+// parsed once: the size of the variants does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Parse, chunk and embed the documents in a given directory, and upload them to a
@@ -23,20 +67,125 @@ enum Commands {
     Load {
         // Parser options
         /// The path to the directory containing the files for the RAG pipeline
-        #[arg(short, long)]
-        directory: String,
+        #[arg(
+            short,
+            long,
+            required_unless_present_any = ["s3_uri", "file", "stdin", "from_chunks"],
+            conflicts_with_all = ["s3_uri", "stdin", "from_chunks"]
+        )]
+        directory: Option<String>,
+
+        /// Path to a single file to use for the RAG pipeline. Can be repeated.
+        #[arg(long)]
+        file: Vec<String>,
+
+        /// Read the document to use for the RAG pipeline from stdin. Requires `--source`.
+        #[arg(long, default_value_t = false, requires = "source")]
+        stdin: bool,
+
+        /// Source name of the document read from stdin (e.g. 'notes.md'). Its extension determines how the content is parsed.
+        #[arg(long, default_value = None, requires = "stdin")]
+        source: Option<String>,
+
+        /// S3 URI (e.g. 's3://bucket/prefix') of the objects to use for the RAG pipeline, as an alternative to `--directory`.
+        /// Credentials are loaded from the standard AWS credentials chain.
+        #[arg(long, default_value = None)]
+        s3_uri: Option<String>,
+
+        /// Custom endpoint for S3-compatible object stores (e.g. 'http://localhost:9000' for MinIO).
+        #[arg(long, default_value = None)]
+        s3_endpoint: Option<String>,
+
+        /// Maximum number of S3 objects to download. Defaults to 1000.
+        #[arg(long, default_value = None)]
+        s3_max_keys: Option<usize>,
+
+        // Fetching options
+        /// URL of a web page to fetch and ingest alongside the directory. Can be repeated.
+        #[arg(long)]
+        url: Vec<String>,
+
+        /// Path to a file containing the URLs of the web pages to fetch and ingest, one per line.
+        #[arg(long, default_value = None)]
+        urls_file: Option<String>,
+
+        /// Timeout (in seconds) for fetching each web page. Defaults to 30.
+        #[arg(long, default_value = None)]
+        fetch_timeout_secs: Option<u64>,
+
+        /// Maximum size (in bytes) of each fetched web page. Defaults to 10485760 (10 MB).
+        #[arg(long, default_value = None)]
+        fetch_max_size: Option<usize>,
+
+        /// Maximum number of redirects to follow when fetching a web page. Defaults to 5.
+        #[arg(long, default_value = None)]
+        fetch_max_redirects: Option<usize>,
+
+        // Cleaning options
+        /// Clean the parsed text before chunking: strip control characters, collapse runs of spaces and of blank lines,
+        /// and rejoin the words hyphenated at the end of a line. Use `--clean-text false` to chunk the text as parsed.
+        #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+        clean_text: bool,
+
+        /// Drop the lines found on more than this number of pages of a PDF (e.g. headers and footers) when cleaning the text.
+        #[arg(long, default_value = None)]
+        max_line_repeats: Option<usize>,
 
         // Chunking options
-        /// Chunking size
+        /// Maximum chunk size, in bytes (or in the `--chunk-unit`). Paragraphs are kept intact, and only the ones longer than the chunk size are split.
         #[arg(long, default_value_t = 1024)]
         chunk_size: usize,
 
-        // VectorDB options
-        /// URL for a Qdrant vector store instance.
-        /// If your Qdrant instance needs an API key, make sure that
-        /// it is available as `QDRANT_API_KEY` in your environment
+        /// What the chunk size is measured in: 'bytes', 'chars' or 'tokens' (of the `cl100k_base` encoding of the OpenAI models).
+        #[arg(long, default_value = "bytes")]
+        chunk_unit: String,
+
+        /// Minimum chunk size: smaller chunks (e.g. the last fragment of a document) are merged into the preceding one when it fits in the chunk size plus the minimum size, and kept otherwise. Defaults to 10% of the chunk size.
+        #[arg(long, default_value = None)]
+        min_chunk_size: Option<usize>,
+
+        /// How the text is split into chunks: 'bytes' (paragraphs grouped up to the chunk size), 'sentence'
+        /// (whole sentences grouped up to the chunk size, only splitting the sentences longer than it, between words),
+        /// 'markdown' (Markdown sections, prefixed with their heading path, and never split inside code fences),
+        /// 'recursive' (split on the first `--separator` that makes the text fit: recommended for prose)
+        /// or 'semantic' (experimental: adjacent sentences grouped by topic, comparing their embeddings).
+        #[arg(long, default_value = "bytes")]
+        chunk_strategy: String,
+
+        /// Separator of the 'recursive' chunk strategy, from the preferred one (`\n` and `\t` are unescaped). Can be repeated.
+        /// Defaults to paragraphs, lines, sentences and words: `\n\n`, `\n`, `. ` and ` `.
         #[arg(long)]
-        qdrant_url: String,
+        separator: Vec<String>,
+
+        // Embedding options
+        /// Embedding provider: 'bm25' (sparse vectors), 'openai' (dense `text-embedding-3-small` vectors,
+        /// requires `OPENAI_API_KEY` in your environment) or 'hybrid' (both, retrieved with reciprocal rank fusion).
+        /// Defaults to the one stored in the collection, or to 'bm25' for new collections.
+        #[arg(long, default_value = None)]
+        embedding_provider: Option<String>,
+
+        /// BM25 tokenization language: 'auto' (detected for every chunk) or an ISO 639-1 code ('en', 'fr', 'de', ...).
+        /// Defaults to the one stored in the collection, or to 'auto' for new collections.
+        #[arg(long, default_value = None)]
+        language: Option<String>,
+
+        /// ISO 639-1 code of the language used for chunks whose language cannot be detected with '--language auto'.
+        /// Defaults to the one stored in the collection, or to 'en' for new collections.
+        #[arg(long, default_value = None)]
+        default_language: Option<String>,
+
+        /// BM25 term frequency saturation (k1). Defaults to the one stored in the collection, or to 1.2 for new collections.
+        #[arg(long, default_value = None)]
+        bm25_k1: Option<f32>,
+
+        /// BM25 document length normalization (b), between 0 and 1.
+        /// Defaults to the one stored in the collection, or to 0.75 for new collections.
+        #[arg(long, default_value = None)]
+        bm25_b: Option<f32>,
+
+        // VectorDB options
+        #[command(flatten)]
+        qdrant: QdrantArgs,
 
         /// Name of the collection for the Qdrant vector store.
         #[arg(long)]
@@ -46,21 +195,152 @@ enum Commands {
         #[arg(long, default_value = None)]
         cache_dir: Option<String>,
 
-        /// Chunk size for cached writes. Defaults to 1024 bytes.
-        #[arg(long, default_value = None)]
+        /// Chunk size (in bytes, greater than 0) of the buffer of the cache reads and writes. Defaults to 1024 bytes.
+        #[arg(long, default_value = None, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
         cache_chunk_size: Option<usize>,
 
-        /// Deactivate read/write from cache
+        /// Maximum size (in MB) of the files to parse. Larger files are skipped. Defaults to 50.
+        #[arg(long, default_value = None)]
+        max_file_size_mb: Option<u64>,
+
+        /// Extract the text of PDFs without a text layer (e.g. scans) with OCR.
+        /// Requires the `pdftoppm` (poppler) and `tesseract` binaries.
+        #[arg(long, default_value_t = false)]
+        ocr_enabled: bool,
+
+        /// Deactivate read/write from cache. The text extracted from PDFs is cached by file content.
         #[arg(long, default_value_t = false)]
         no_cache: bool,
+
+        /// File holding the key to encrypt the cached content with (AES-256-GCM), as 64 hexadecimal characters,
+        /// or a passphrase the key is derived from (PBKDF2).
+        #[arg(long, default_value = None, conflicts_with = "no_cache")]
+        cache_encryption_key_file: Option<String>,
+
+        /// Maximum size of the cache directory, e.g. '500MB' (units: B, KB, MB, GB).
+        /// The least recently used entries are evicted once it is exceeded.
+        #[arg(long, default_value = None, value_parser = parse_size, conflicts_with = "no_cache")]
+        cache_max_size: Option<u64>,
+
+        /// Time after which the cached entries expire and are extracted again, e.g. '7d' (units: s, m, h, d, w).
+        #[arg(long, default_value = None, value_parser = parse_age, conflicts_with = "no_cache")]
+        cache_ttl: Option<Duration>,
+
+        /// Add the documents to the collection even if it already has points. Without it,
+        /// nothing is uploaded to a collection that already has points.
+        #[arg(long, default_value_t = false)]
+        append: bool,
+
+        // Checkpointing options
+        /// Resume an interrupted run, skipping the documents that were already uploaded to the collection
+        #[arg(long, default_value_t = false, conflicts_with = "no_checkpoint")]
+        resume: bool,
+
+        /// Deactivate the recording of the pipeline progress in the cache directory
+        /// and in the `.rag-rs-checkpoint.jsonl` file of the directory
+        #[arg(long, default_value_t = false)]
+        no_checkpoint: bool,
+
+        /// Load again the files listed as completed in the `.rag-rs-checkpoint.jsonl` file
+        /// left in the directory by an interrupted run
+        #[arg(long, default_value_t = false, conflicts_with_all = ["resume", "no_checkpoint"])]
+        ignore_checkpoint: bool,
+
+        // Chunk export options
+        /// Stop after chunking, and write the chunks (with their metadata, without embeddings) to this JSONL file
+        /// instead of embedding and uploading them. The collection is not accessed.
+        #[arg(long, default_value = None)]
+        export_chunks: Option<String>,
+
+        /// Embed and upload the chunks of this JSONL file (e.g. written with `--export-chunks`),
+        /// instead of parsing and chunking documents.
+        #[arg(
+            long,
+            default_value = None,
+            conflicts_with_all = ["file", "stdin", "s3_uri", "url", "urls_file", "export_chunks"]
+        )]
+        from_chunks: Option<String>,
+
+        /// Print an estimate of the cost of the embedding API calls at the end of the run.
+        #[arg(long, default_value_t = false)]
+        show_cost_estimate: bool,
+    },
+    /// List, inspect and clear the cache of the parsed files.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// Print the version, build metadata and the versions of the key dependencies.
+    Info {
+        /// Print the information as JSON instead of a human-readable table.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Print the number of points and the embedding configuration stored in a collection.
+    Status {
+        #[command(flatten)]
+        qdrant: QdrantArgs,
+
+        /// Name of the collection for the Qdrant vector store.
+        #[arg(long)]
+        collection_name: String,
+    },
+    /// Back up a collection with a snapshot stored on the Qdrant server.
+    Backup {
+        #[command(flatten)]
+        qdrant: QdrantArgs,
+
+        /// Name of the collection for the Qdrant vector store.
+        #[arg(long)]
+        collection_name: String,
+
+        /// List the existing snapshots of the collection instead of creating a new one.
+        #[arg(long, default_value_t = false)]
+        list: bool,
+    },
+    /// Upgrade the payload of the points of a collection, without parsing the documents again.
+    Migrate {
+        #[command(flatten)]
+        qdrant: QdrantArgs,
+
+        /// Name of the collection for the Qdrant vector store.
+        #[arg(long)]
+        collection_name: String,
+
+        /// Name of the migration to run. Run with `--list` to print the available migrations.
+        #[arg(long, required_unless_present = "list")]
+        migration: Option<String>,
+
+        /// List the available migrations instead of running one.
+        #[arg(long, default_value_t = false)]
+        list: bool,
+    },
+    /// Set a payload field of the chunks of a source file (e.g. a document version), without
+    /// parsing and embedding it again.
+    SetPayload {
+        #[command(flatten)]
+        qdrant: QdrantArgs,
+
+        /// Name of the collection for the Qdrant vector store.
+        #[arg(long)]
+        collection_name: String,
+
+        /// Source file of the chunks to update, as stored in their `source` payload field.
+        #[arg(long)]
+        source_file: String,
+
+        /// Name of the payload field to set.
+        #[arg(long)]
+        key: String,
+
+        /// Value of the payload field, as JSON (e.g. `2`, `"draft"` or `["hr", "legal"]`).
+        #[arg(long, value_parser = parse_payload_value)]
+        value: serde_json::Value,
     },
     /// Serve the RAG application as an API server.
     Serve {
-        // URL for a Qdrant vector store instance.
-        /// If your Qdrant instance needs an API key, make sure that
-        /// it is available as `QDRANT_API_KEY` in your environment
-        #[arg(long)]
-        qdrant_url: String,
+        #[command(flatten)]
+        qdrant: QdrantArgs,
 
         /// Name of the collection for the Qdrant vector store.
         #[arg(long)]
@@ -85,6 +365,14 @@ enum Commands {
         #[arg(long, default_value = None)]
         rate_limit_per_minute: Option<u32>,
 
+        /// Maximum size (in bytes) of the request bodies. Larger requests are rejected with a 413 status code. Defaults to 16384 (16 KB).
+        #[arg(long, default_value = None)]
+        max_request_body_bytes: Option<usize>,
+
+        /// Maximum number of retries of the OpenAI requests failing with a transient error (rate limit or server error), at most 100. Defaults to 3.
+        #[arg(long, default_value = None, value_parser = clap::value_parser!(u32).range(..=100))]
+        openai_max_retries: Option<u32>,
+
         /// Allowed CORS origin (e.g. 'https://mydomain.com'). Defaults to '*' (all origins allowed) if not provided.
         /// While this argument has no effect for local development, it is advisable to set it for production deployments.
         #[arg(long, default_value = None)]
@@ -98,46 +386,497 @@ enum Commands {
         /// Wether or not to activate JSON logging. Defaults to false (uses compact logging by default).
         #[arg(long, default_value_t = false)]
         log_json: bool,
+
+        // Embedding options
+        /// Embedding provider the collection was loaded with: 'bm25', 'openai' or 'hybrid'.
+        /// If set, the server refuses to start when it does not match the one stored in the collection.
+        #[arg(long, default_value = None)]
+        embedding_provider: Option<String>,
+
+        /// BM25 tokenization language the collection was loaded with: 'auto' or an ISO 639-1 code.
+        /// If set, it must match the one stored in the collection.
+        #[arg(long, default_value = None)]
+        language: Option<String>,
+
+        /// BM25 k1 parameter the collection was loaded with. If set, it must match the one stored in the collection.
+        #[arg(long, default_value = None)]
+        bm25_k1: Option<f32>,
+
+        /// BM25 b parameter the collection was loaded with. If set, it must match the one stored in the collection.
+        #[arg(long, default_value = None)]
+        bm25_b: Option<f32>,
+
+        // Proxy options
+        /// Rate limit by the client IP in the `X-Forwarded-For` (or `X-Real-IP`) header instead of the socket address.
+        /// Only use it when the server is reachable exclusively through a trusted reverse proxy.
+        #[arg(long, default_value_t = false)]
+        trust_proxy: bool,
+
+        /// Maximum number of addresses accepted in `X-Forwarded-For` when `--trust-proxy` is set. Defaults to 2.
+        #[arg(long, default_value = None, requires = "trust_proxy")]
+        max_forwarded_hops: Option<usize>,
+
+        // Admin options
+        /// Token required by the admin endpoints (e.g. `POST /collections/{name}/reload`), sent as
+        /// `Authorization: Bearer <token>`. It is not advised to pass the token as an option
+        /// to the CLI command: you should set it as the `RAG_RS_ADMIN_TOKEN` environment variable.
+        /// The admin endpoints are disabled without a token.
+        #[arg(long, default_value = None)]
+        admin_token: Option<String>,
+
+        /// Other collection that queries can be routed to with the `collection` field, as
+        /// 'alias=collection_name' (e.g. 'legal=legal-docs'). Can be repeated.
+        #[arg(long, value_parser = parse_collection_alias)]
+        collection_aliases: Vec<(String, String)>,
+
+        /// Other collection that queries can be routed to by name with the `collection` field, loaded for each request
+        /// (e.g. one collection per tenant). Can be repeated, and '*' allows any collection of the Qdrant instance.
+        #[arg(long)]
+        allowed_collections: Vec<String>,
+
+        /// Number of recent queries kept in memory and returned by the `GET /queries/history` admin endpoint. Defaults to 100.
+        #[arg(long, default_value = None)]
+        query_history_size: Option<usize>,
+
+        // Cache options
+        /// Maximum size of the cache directory of the reload jobs, e.g. '500MB' (units: B, KB, MB, GB).
+        /// The least recently used entries are evicted once it is exceeded.
+        #[arg(long, default_value = None, value_parser = parse_size)]
+        cache_max_size: Option<u64>,
+
+        /// Time after which the cached entries of the reload jobs expire, e.g. '7d' (units: s, m, h, d, w).
+        #[arg(long, default_value = None, value_parser = parse_age)]
+        cache_ttl: Option<Duration>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommands {
+    /// List the entries of the cache, with their sizes and ages.
+    Ls {
+        /// Directory of the cache. Defaults to `.rag-rs-cache/`
+        #[arg(long, default_value = None)]
+        cache_dir: Option<String>,
+
+        /// Print the entries as JSON instead of a human-readable list.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Remove the entries of the cache, and their content.
+    Clear {
+        /// Directory of the cache. Defaults to `.rag-rs-cache/`
+        #[arg(long, default_value = None)]
+        cache_dir: Option<String>,
+
+        /// Only remove the entries older than this age, e.g. '30d' (units: s, m, h, d, w).
+        #[arg(long, default_value = None, value_parser = parse_age)]
+        older_than: Option<Duration>,
+
+        /// Do not ask for confirmation.
+        #[arg(long, default_value_t = false)]
+        yes: bool,
+    },
+    /// Check the integrity of the entries of the cache, and remove the corrupted ones.
+    Verify {
+        /// Directory of the cache. Defaults to `.rag-rs-cache/`
+        #[arg(long, default_value = None)]
+        cache_dir: Option<String>,
+
+        /// Print the report as JSON.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Remove a single entry of the cache, or all the entries starting with a prefix.
+    Rm {
+        /// Key of the entry, as printed by `cache ls`.
+        #[arg(required_unless_present = "prefix", conflicts_with = "prefix")]
+        key: Option<String>,
+
+        /// Remove all the entries whose keys start with this prefix (e.g. 'pdf-pages-ocr-')
+        /// instead of a single entry.
+        #[arg(long, default_value = None)]
+        prefix: Option<String>,
+
+        /// Directory of the cache. Defaults to `.rag-rs-cache/`
+        #[arg(long, default_value = None)]
+        cache_dir: Option<String>,
     },
 }
 
+fn init_load_logging(verbose: bool) {
+    let level = if verbose { Level::DEBUG } else { Level::INFO };
+    tracing_subscriber::registry()
+        .with(LevelFilter::from_level(level))
+        .with(fmt::layer().compact())
+        .init();
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = CliArgs::parse();
     match args.cmd {
         Commands::Load {
             directory,
+            file,
+            stdin,
+            source,
+            s3_uri,
+            s3_endpoint,
+            s3_max_keys,
+            url,
+            urls_file,
+            fetch_timeout_secs,
+            fetch_max_size,
+            fetch_max_redirects,
+            clean_text,
+            max_line_repeats,
             chunk_size,
-            qdrant_url,
+            chunk_unit,
+            min_chunk_size,
+            chunk_strategy,
+            separator,
+            embedding_provider,
+            language,
+            default_language,
+            bm25_k1,
+            bm25_b,
+            qdrant,
             collection_name,
             cache_dir,
             cache_chunk_size,
             no_cache,
+            cache_encryption_key_file,
+            cache_max_size,
+            cache_ttl,
+            max_file_size_mb,
+            ocr_enabled,
+            append,
+            resume,
+            no_checkpoint,
+            ignore_checkpoint,
+            export_chunks,
+            from_chunks,
+            show_cost_estimate,
         } => {
-            let pipeline = Pipeline::new(
+            init_load_logging(args.verbose);
+            let mut pipeline = Pipeline::new(
                 directory,
                 chunk_size,
-                qdrant_url,
+                qdrant.qdrant_url,
                 collection_name,
                 !no_cache,
                 cache_dir,
                 cache_chunk_size,
             );
-            pipeline.run().await?;
+            let mut urls = url;
+            if let Some(urls_file) = urls_file {
+                urls.extend(read_urls_file(&urls_file).await?);
+            }
+            pipeline.urls = urls;
+            pipeline.files = file;
+            pipeline.checkpoint = !no_checkpoint;
+            pipeline.qdrant_tls = qdrant.qdrant_tls;
+            pipeline.append = append;
+            pipeline.resume = resume;
+            pipeline.ignore_checkpoint = ignore_checkpoint;
+            pipeline.export_chunks = export_chunks;
+            pipeline.from_chunks = from_chunks;
+            pipeline.max_file_size_mb = max_file_size_mb;
+            pipeline.ocr_enabled = ocr_enabled;
+            pipeline.cache_options = CacheOptions {
+                max_size_bytes: cache_max_size,
+                ttl: cache_ttl,
+            };
+            if let Some(key_file) = cache_encryption_key_file {
+                let cache = Cache::new(pipeline.cache_directory.clone(), None, None);
+                pipeline.cache_encryption_key =
+                    Some(cache.load_encryption_key(Path::new(&key_file)).await?);
+            }
+            pipeline.clean_text = clean_text;
+            pipeline.max_line_repeats = max_line_repeats;
+            pipeline.chunk_strategy = chunk_strategy.parse()?;
+            pipeline.chunk_unit = chunk_unit.parse()?;
+            pipeline.separators = separator
+                .iter()
+                .map(|s| s.replace("\\n", "\n").replace("\\t", "\t"))
+                .collect();
+            pipeline.min_chunk_size = min_chunk_size;
+            if stdin && let Some(source) = source {
+                let mut bytes: Vec<u8> = vec![];
+                tokio::io::stdin().read_to_end(&mut bytes).await?;
+                pipeline.sources = vec![Box::new(InMemorySource::new(vec![(source, bytes)]))];
+            }
+            if let Some(s3_uri) = s3_uri {
+                pipeline.s3_source = Some(S3Source::new(&s3_uri, s3_endpoint, s3_max_keys)?);
+            }
+            pipeline.fetch_timeout_secs = fetch_timeout_secs;
+            pipeline.fetch_max_size = fetch_max_size;
+            pipeline.fetch_max_redirects = fetch_max_redirects;
+            pipeline.embedding_options = EmbeddingOptions::new(
+                embedding_provider,
+                language,
+                bm25_k1,
+                bm25_b,
+                default_language,
+            )?;
+            let result = pipeline.run().await?;
+            if !no_cache {
+                println!(
+                    "Parse cache: {} hits, {} misses",
+                    result.cache_hits, result.cache_misses
+                );
+            }
+            if clean_text {
+                let removed: usize = result.cleaned_bytes.iter().map(|(_, n)| n).sum();
+                println!("Text cleaning removed {} bytes:", removed);
+                for (source, bytes) in result.cleaned_bytes.iter().filter(|(_, n)| *n > 0) {
+                    println!("- {}: {} bytes", source, bytes);
+                }
+            }
+            if show_cost_estimate {
+                match result.estimated_embedding_api_cost_usd {
+                    Some(cost) => println!("Estimated embedding API cost: ${:.4}", cost),
+                    None => println!("Estimated embedding API cost: $0 (no embedding API used)"),
+                }
+            }
+            if !result.skipped_files.is_empty() {
+                println!("Skipped {:?} files:", result.skipped_files.len());
+                for skipped in result.skipped_files {
+                    println!("- {}: {}", skipped.path.display(), skipped.reason);
+                }
+            }
+        }
+        Commands::Cache { command } => match command {
+            CacheCommands::Ls { cache_dir, json } => {
+                let cache = Cache::new(cache_dir, None, None);
+                let entries = cache.list()?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&entries)?);
+                } else {
+                    let total: usize = entries.iter().map(|e| e.size).sum();
+                    println!(
+                        "{} entries ({} bytes) in {}",
+                        entries.len(),
+                        total,
+                        cache.directory
+                    );
+                    for entry in entries {
+                        let path = entry
+                            .original_path
+                            .map(|p| format!(", from {}", p))
+                            .unwrap_or_default();
+                        println!(
+                            "- {}: {} bytes, {} old{}",
+                            entry.key,
+                            entry.size,
+                            format_age(entry.created_at),
+                            path
+                        );
+                    }
+                }
+            }
+            CacheCommands::Clear {
+                cache_dir,
+                older_than,
+                yes,
+            } => {
+                let cache = Cache::new(cache_dir, None, None);
+                if !yes {
+                    let entries = match older_than {
+                        Some(age) => format!("the entries older than {}", format_duration(age)),
+                        None => "all the entries".to_string(),
+                    };
+                    print!("Remove {} of {}? [y/N] ", entries, cache.directory);
+                    std::io::stdout().flush()?;
+                    let mut answer = String::new();
+                    std::io::stdin().read_line(&mut answer)?;
+                    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                        println!("Nothing was removed");
+                        return Ok(());
+                    }
+                }
+                let removed = match older_than {
+                    Some(age) => cache.clear_older_than(age).await?,
+                    None => cache.clear().await?,
+                };
+                println!("Removed {} entries from {}", removed, cache.directory);
+            }
+            CacheCommands::Verify { cache_dir, json } => {
+                let cache = Cache::new(cache_dir, None, None);
+                let report = cache.verify().await?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    println!(
+                        "Checked {} entries in {}: removed {} corrupted entries, reclaiming {} bytes",
+                        report.checked,
+                        cache.directory,
+                        report.corrupted_removed,
+                        report.bytes_reclaimed
+                    );
+                }
+            }
+            CacheCommands::Rm {
+                key,
+                prefix,
+                cache_dir,
+            } => {
+                let cache = Cache::new(cache_dir, None, None);
+                let Some(key) = key else {
+                    let prefix = prefix.expect("Clap requires the key or the prefix");
+                    let keys = cache.list_entries_by_prefix(&prefix)?;
+                    let removed = cache.evict_namespace(&prefix).await?;
+                    println!(
+                        "Removed {} entries starting with {} from {}",
+                        removed, prefix, cache.directory
+                    );
+                    for key in keys {
+                        println!("- {}", key);
+                    }
+                    return Ok(());
+                };
+                if cache.remove(&key).await? {
+                    println!("Removed {} from {}", key, cache.directory);
+                } else {
+                    return Err(anyhow::anyhow!("No entry {} in {}", key, cache.directory));
+                }
+            }
+        },
+        Commands::Info { json } => {
+            let info = BuildInfo::current();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                println!("{}", info.to_table());
+            }
+        }
+        Commands::Status {
+            qdrant,
+            collection_name,
+        } => {
+            let vectordb = VectorDB::new(
+                qdrant.qdrant_url,
+                collection_name.clone(),
+                qdrant.qdrant_tls,
+            );
+            let points = vectordb.check_collection_ready().await?;
+            println!("Collection {} contains {} points", collection_name, points);
+            let metadata = vectordb.get_metadata().await?;
+            match EmbeddingConfig::from_metadata(&metadata)? {
+                Some(config) => println!(
+                    "Embedding configuration:\n{}",
+                    serde_json::to_string_pretty(&config)?
+                ),
+                None => println!("No embedding configuration is stored in the collection"),
+            }
+            if let Some(config) = ChunkingConfig::from_metadata(&metadata)? {
+                println!(
+                    "Chunked with the {} strategy, in chunks of at most {} {}",
+                    config.strategy, config.size, config.unit
+                );
+            }
+        }
+        Commands::Backup {
+            qdrant,
+            collection_name,
+            list,
+        } => {
+            let vectordb = VectorDB::new(
+                qdrant.qdrant_url,
+                collection_name.clone(),
+                qdrant.qdrant_tls,
+            );
+            if list {
+                let snapshots = vectordb.list_snapshots().await?;
+                println!(
+                    "Collection {} has {} snapshots",
+                    collection_name,
+                    snapshots.len()
+                );
+                for snapshot in snapshots {
+                    println!("- {}", snapshot);
+                }
+            } else {
+                let snapshot = vectordb.create_snapshot().await?;
+                println!("Created snapshot {} of {}", snapshot, collection_name);
+            }
+        }
+        Commands::Migrate {
+            qdrant,
+            collection_name,
+            migration,
+            list,
+        } => {
+            if list {
+                for migration in MIGRATIONS {
+                    println!("- {}: {}", migration.name, migration.description);
+                }
+            } else if let Some(migration) = migration {
+                let vectordb = VectorDB::new(
+                    qdrant.qdrant_url,
+                    collection_name.clone(),
+                    qdrant.qdrant_tls,
+                );
+                vectordb.check_collection_ready().await?;
+                let updated = run_migration(&vectordb, &migration).await?;
+                println!(
+                    "Migration {} updated {} points of {}",
+                    migration, updated, collection_name
+                );
+            }
+        }
+        Commands::SetPayload {
+            qdrant,
+            collection_name,
+            source_file,
+            key,
+            value,
+        } => {
+            let vectordb = VectorDB::new(
+                qdrant.qdrant_url,
+                collection_name.clone(),
+                qdrant.qdrant_tls,
+            );
+            vectordb.check_collection_ready().await?;
+            let updated = vectordb.update_payload(&source_file, &key, value).await?;
+            println!(
+                "Set {} on {} points of {} in {}",
+                key, updated, source_file, collection_name
+            );
         }
         Commands::Serve {
-            qdrant_url,
+            qdrant,
             collection_name,
             openai_api_key,
             port,
             host,
             rate_limit_per_minute,
+            max_request_body_bytes,
+            openai_max_retries,
             cors,
             log_level,
             log_json,
+            embedding_provider,
+            language,
+            bm25_k1,
+            bm25_b,
+            trust_proxy,
+            max_forwarded_hops,
+            admin_token,
+            collection_aliases,
+            allowed_collections,
+            query_history_size,
+            cache_max_size,
+            cache_ttl,
         } => {
-            let server = RagServer::new(
-                qdrant_url,
+            // verbose mode is meant for command-line debugging: force compact debug logs
+            let (log_level, log_json) = if args.verbose {
+                (Some("debug".to_string()), false)
+            } else {
+                (log_level, log_json)
+            };
+            let mut server = RagServer::new(
+                qdrant.qdrant_url,
                 openai_api_key,
                 collection_name,
                 port,
@@ -147,6 +886,40 @@ async fn main() -> anyhow::Result<()> {
                 log_level,
                 log_json,
             );
+            server.embedding_options =
+                EmbeddingOptions::new(embedding_provider, language, bm25_k1, bm25_b, None)?;
+            server.qdrant_tls = qdrant.qdrant_tls;
+            server.trust_proxy = trust_proxy;
+            if let Some(max_forwarded_hops) = max_forwarded_hops {
+                server.max_forwarded_hops = max_forwarded_hops;
+            }
+            if let Some(max_request_body_bytes) = max_request_body_bytes {
+                server.max_request_body_bytes = max_request_body_bytes;
+            }
+            if let Some(openai_max_retries) = openai_max_retries {
+                server.openai_max_retries = openai_max_retries;
+            }
+            server.admin_token = admin_token
+                .or_else(|| std::env::var("RAG_RS_ADMIN_TOKEN").ok())
+                .filter(|t| !t.is_empty());
+            server.collection_aliases = collection_aliases.into_iter().collect();
+            server.allowed_collections = allowed_collections;
+            if let Some(query_history_size) = query_history_size {
+                server.query_history_size = query_history_size;
+            }
+            server.cache_options = CacheOptions {
+                max_size_bytes: cache_max_size,
+                ttl: cache_ttl,
+            };
+            // stop gracefully on Ctrl+C, letting in-flight requests complete
+            let running = server.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok()
+                    && let Err(e) = running.shutdown()
+                {
+                    eprintln!("Could not shut down the server gracefully: {}", e);
+                }
+            });
             server.serve().await?;
         }
     }
@@ -0,0 +1,106 @@
+use crate::vectordb::VectorDB;
+
+/// The payload field holding the source file of each chunk
+const SOURCE_PAYLOAD_FIELD: &str = "source";
+
+/// A named upgrade of the payload stored in a collection, run by `rag-rs migrate`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migration {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    name: "add-source-file-unknown",
+    description: "Set the source file of the chunks loaded without one to \"unknown\"",
+}];
+
+/// Run the migration named `name` on the collection, returning the number of updated points
+pub async fn run_migration(vectordb: &VectorDB, name: &str) -> anyhow::Result<u64> {
+    match name {
+        "add-source-file-unknown" => migration_add_source_file_unknown(vectordb).await,
+        other => Err(anyhow::anyhow!(
+            "Unknown migration: {}. Available migrations are: {}",
+            other,
+            MIGRATIONS
+                .iter()
+                .map(|m| m.name)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Chunks loaded from stdin or by older versions may lack a source file, so that they
+/// cannot be filtered or updated by source: give them an explicit `"unknown"` one.
+pub async fn migration_add_source_file_unknown(vectordb: &VectorDB) -> anyhow::Result<u64> {
+    vectordb
+        .fill_missing_payload(SOURCE_PAYLOAD_FIELD, serde_json::json!("unknown"))
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chunking::Chunk,
+        embedding::{EmbeddingConfig, EmbeddingProvider, embed_chunks},
+    };
+
+    #[tokio::test]
+    async fn test_run_unknown_migration() {
+        let vectordb = VectorDB::new(String::new(), String::new(), false);
+        let err = run_migration(&vectordb, "drop-everything")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown migration: drop-everything. Available migrations are: add-source-file-unknown"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migration_add_source_file_unknown() {
+        let qdrant_url_var = std::env::var("QDRANT_URL");
+        let qdrant_url = match qdrant_url_var {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                println!("Skipping test because Qdrant is not available");
+                return;
+            }
+        };
+        let vectordb = VectorDB::new(qdrant_url, "test-migrate-collection".to_string(), false);
+        vectordb
+            .create_collection(EmbeddingProvider::Bm25)
+            .await
+            .unwrap();
+        let mut with_source = Chunk::from_content("hello world".to_string());
+        with_source.source = Some("hello.md".to_string());
+        let chunks = vec![with_source, Chunk::from_content("bye world".to_string())];
+        let embedder = EmbeddingConfig::default().build_embedder(None).unwrap();
+        let chunks = embed_chunks(chunks, embedder.as_ref()).await.unwrap();
+        let base_id = vectordb.upload_base_id(true).await.unwrap().unwrap();
+        vectordb
+            .upload_embeddings(chunks, EmbeddingProvider::Bm25, base_id)
+            .await
+            .unwrap();
+        assert!(
+            run_migration(&vectordb, "add-source-file-unknown")
+                .await
+                .unwrap()
+                >= 1
+        );
+        // every point has a source file now
+        assert_eq!(
+            migration_add_source_file_unknown(&vectordb).await.unwrap(),
+            0
+        );
+        assert!(
+            vectordb
+                .update_payload("unknown", "migrated", serde_json::json!(true))
+                .await
+                .unwrap()
+                >= 1
+        );
+    }
+}
@@ -1,14 +1,127 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
 
-use tokio::fs;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, process::Command};
+use tracing::{info, warn};
 
-use crate::caching::Cache;
+use crate::{
+    caching::{Cache, CacheError, CacheOptions},
+    checkpointing::COMPLETION_LOG_FILE,
+};
+
+pub const SUPPORTED_EXTENSIONS: [&str; 3] = ["pdf", "md", "txt"];
+const DEFAULT_MAX_FILE_SIZE_MB: u64 = 50;
+// Resolution of the page images rendered for OCR
+const OCR_RESOLUTION_DPI: &str = "300";
+// Kinds of the cached pages, versioned since the JSON arrays of the first versions
+const PDF_PAGES_KIND: &str = "pdf-pages-v2";
+const PDF_PAGES_OCR_KIND: &str = "pdf-pages-ocr-v2";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Text parsed from a document, along with the text of each of its pages for the formats
+/// that have pages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParsedDocument {
+    pub source: String,
+    pub text: String,
+    pub pages: Option<Vec<String>>,
+}
+
+impl ParsedDocument {
+    pub fn new(source: String, text: String) -> Self {
+        Self {
+            source,
+            text,
+            pages: None,
+        }
+    }
+
+    pub fn from_pages(source: String, pages: Vec<String>) -> Self {
+        Self {
+            source,
+            text: pages.join("\n"),
+            pages: Some(pages),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParserError {
+    #[error("Could not read {path:?}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Could not extract the text of {path:?}")]
+    PdfExtraction {
+        path: PathBuf,
+        #[source]
+        source: pdf_extract::OutputError,
+    },
+    #[error("{name} is not valid UTF-8 text")]
+    InvalidText {
+        name: String,
+        #[source]
+        source: std::string::FromUtf8Error,
+    },
+    #[error("Could not access the cached content of {path:?}")]
+    Cache {
+        path: PathBuf,
+        #[source]
+        source: CacheError,
+    },
+    #[error("Could not run {program}, is it installed?")]
+    MissingProgram {
+        program: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{program} failed: {stderr}")]
+    CommandFailed { program: String, stderr: String },
+}
+
+impl ParserError {
+    fn io(path: &Path) -> impl FnOnce(std::io::Error) -> Self {
+        move |source| Self::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+/// A parsing backend, turning files into documents
+#[async_trait]
+pub trait DocumentParser: Send + Sync {
+    /// Parse the files, which were already validated. Files in formats the backend does not
+    /// support are left out of the documents.
+    async fn parse_files(&self, files: Vec<PathBuf>) -> anyhow::Result<Vec<ParsedDocument>>;
+}
 
 pub struct Parser {
     pub directory_path: String,
     pub cached: bool,
     pub cache_directory: Option<String>,
     pub cache_chunk_size: Option<usize>,
+    pub cache_namespace: Option<String>,
+    /// Encrypt the cached content with this key, see `Cache::encryption_key`
+    pub cache_encryption_key: Option<[u8; 32]>,
+    pub cache_options: CacheOptions,
+    pub max_file_size_mb: u64,
+    /// Extract the text of PDFs without a text layer (e.g. scans) with OCR
+    pub ocr_enabled: bool,
+    cache_hits: AtomicUsize,
+    cache_misses: AtomicUsize,
 }
 
 impl Parser {
@@ -17,104 +130,326 @@ impl Parser {
         cached: bool,
         cache_directory: Option<String>,
         cache_chunk_size: Option<usize>,
+        cache_namespace: Option<String>,
+        max_file_size_mb: Option<u64>,
     ) -> Self {
         Self {
             directory_path,
             cache_directory,
             cache_chunk_size,
+            cache_namespace,
+            cache_encryption_key: None,
+            cache_options: CacheOptions::default(),
             cached,
+            max_file_size_mb: max_file_size_mb.unwrap_or(DEFAULT_MAX_FILE_SIZE_MB),
+            ocr_enabled: false,
+            cache_hits: AtomicUsize::new(0),
+            cache_misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of PDFs whose text was read from the cache, and of PDFs that were extracted
+    pub fn cache_stats(&self) -> (usize, usize) {
+        (
+            self.cache_hits.load(Ordering::Relaxed),
+            self.cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Check the file size and its MIME type (detected from the content, not just from the
+    /// extension) before parsing. Returns the reason for skipping the file, if any.
+    pub async fn validate_file(&self, path: &Path) -> Result<Option<SkippedFile>, ParserError> {
+        let skipped = |reason: String| {
+            warn!(event = "FileSkipped", path = ?path, "Skipping file: {}", reason);
+            Ok(Some(SkippedFile {
+                path: path.to_path_buf(),
+                reason,
+            }))
+        };
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
+            return skipped(
+                "unsupported file format. Supported file formats are: .pdf, .txt and .md"
+                    .to_string(),
+            );
+        }
+        let size = fs::metadata(path)
+            .await
+            .map_err(ParserError::io(path))?
+            .len();
+        if size > self.max_file_size_mb * 1024 * 1024 {
+            return skipped(format!(
+                "file size ({} bytes) exceeds the maximum allowed size ({} MB)",
+                size, self.max_file_size_mb
+            ));
         }
+        let detected = infer::get_from_path(path)
+            .map_err(ParserError::io(path))?
+            .map(|t| t.mime_type());
+        let valid_mime = if extension == "pdf" {
+            detected == Some("application/pdf")
+        } else {
+            // plain text has no magic number: any detected type means binary content
+            detected.is_none()
+        };
+        if !valid_mime {
+            return skipped(format!(
+                "detected MIME type ({}) does not match the .{} extension",
+                detected.unwrap_or("unknown"),
+                extension
+            ));
+        }
+        Ok(None)
     }
 
-    async fn extract_text_from_pdf(&self, file_path: PathBuf) -> anyhow::Result<String> {
+    fn cache(&self) -> Cache {
+        let mut cache = Cache::new(
+            self.cache_directory.clone(),
+            self.cache_chunk_size,
+            self.cache_namespace.clone(),
+        );
+        cache.encryption_key = self.cache_encryption_key;
+        cache.options = self.cache_options;
+        cache
+    }
+
+    async fn extract_pages_from_pdf(&self, file_path: PathBuf) -> Result<Vec<String>, ParserError> {
+        let bytes = fs::read(&file_path)
+            .await
+            .map_err(ParserError::io(&file_path))?;
+        // keyed by content, so that renamed files are not extracted again and edited ones are
+        let cache_key = match self.ocr_enabled {
+            true => Cache::content_key(PDF_PAGES_OCR_KIND, &bytes),
+            false => Cache::content_key(PDF_PAGES_KIND, &bytes),
+        };
         if self.cached {
-            let cache = Cache::new(self.cache_directory.clone(), self.cache_chunk_size);
-            if let Ok(s) = cache
-                .read_file_content(
-                    file_path
-                        .to_str()
-                        .expect("Should be able to convert path to string"),
-                )
+            if let Ok(pages) = self
+                .cache()
+                .read_deserialized::<Vec<String>>(&cache_key)
                 .await
             {
-                return Ok(s);
-            };
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(pages);
+            }
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut out = pdf_extract::extract_text_from_mem_by_pages(&bytes).map_err(|source| {
+            ParserError::PdfExtraction {
+                path: file_path.clone(),
+                source,
+            }
+        })?;
+        if self.ocr_enabled && out.iter().all(|p| p.trim().is_empty()) {
+            warn!(
+                event = "OcrFallback",
+                path = ?file_path,
+                "No text layer found, extracting the text with OCR: the text quality may be lower"
+            );
+            out = parse_scanned_pdf(&file_path).await?;
         }
-        let bytes = fs::read(file_path.clone()).await?;
-        let out = pdf_extract::extract_text_from_mem(&bytes)?;
         if self.cached {
-            let cache = Cache::new(self.cache_directory.clone(), self.cache_chunk_size);
-            cache
-                .write_file_content(
-                    file_path
-                        .to_str()
-                        .expect("Should be able to convert path to string"),
-                    out.clone(),
-                )
-                .await?;
+            let pages = Arc::new(out);
+            self.cache()
+                .write_serialized(&cache_key, pages.clone(), Some(&file_path))
+                .await
+                .map_err(|source| ParserError::Cache {
+                    path: file_path.clone(),
+                    source,
+                })?;
+            out = Arc::unwrap_or_clone(pages);
         }
         Ok(out)
     }
 
     // This is as expensive as reading/writing from cache, no need for caching here
-    async fn read_file(&self, file_path: PathBuf) -> anyhow::Result<String> {
-        let content = fs::read_to_string(file_path).await?;
+    async fn read_file(&self, file_path: PathBuf) -> Result<String, ParserError> {
+        let content = fs::read_to_string(&file_path)
+            .await
+            .map_err(ParserError::io(&file_path))?;
         Ok(content)
     }
 
-    pub async fn parse(&self) -> anyhow::Result<Vec<String>> {
-        let mut entries = fs::read_dir(&self.directory_path).await?;
-        let mut results: Vec<String> = vec![];
-        while let Some(entry) = entries.next_entry().await? {
+    /// Parse a single file, returning `None` if its format is not supported
+    pub async fn parse_file(&self, path: PathBuf) -> Result<Option<ParsedDocument>, ParserError> {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let source = path.to_string_lossy().to_string();
+        let result = if extension == "pdf" {
+            info!(event = "PdfExtraction", path = ?path, "Extracting text from PDF");
+            ParsedDocument::from_pages(source, self.extract_pages_from_pdf(path.clone()).await?)
+        } else if extension == "md" || extension == "txt" {
+            info!(event = "TextRead", path = ?path, "Reading text");
+            ParsedDocument::new(source, self.read_file(path.clone()).await?)
+        } else {
+            warn!(
+                event = "FileSkipped",
+                path = ?path,
+                "Unsupported file format. Supported file formats are: .pdf, .txt and .md"
+            );
+            return Ok(None);
+        };
+        info!(
+            event = "TextParsed",
+            path = ?path,
+            chars = result.text.len(),
+            pages = result.pages.as_ref().map(|p| p.len()),
+            "Parsed text"
+        );
+        Ok(Some(result))
+    }
+
+    /// Parse in-memory content (e.g. read from stdin). The format is inferred from the
+    /// extension of `source`: PDF content is extracted, anything else is read as UTF-8 text.
+    pub fn parse_bytes(&self, source: &str, bytes: Vec<u8>) -> Result<ParsedDocument, ParserError> {
+        let result = if source.ends_with(".pdf") {
+            info!(event = "PdfExtraction", source, "Extracting text from PDF");
+            let pages = pdf_extract::extract_text_from_mem_by_pages(&bytes).map_err(|e| {
+                ParserError::PdfExtraction {
+                    path: PathBuf::from(source),
+                    source: e,
+                }
+            })?;
+            ParsedDocument::from_pages(source.to_string(), pages)
+        } else {
+            info!(event = "TextRead", source, "Reading text");
+            let text = String::from_utf8(bytes).map_err(|e| ParserError::InvalidText {
+                name: source.to_string(),
+                source: e,
+            })?;
+            ParsedDocument::new(source.to_string(), text)
+        };
+        info!(
+            event = "TextParsed",
+            source,
+            chars = result.text.len(),
+            pages = result.pages.as_ref().map(|p| p.len()),
+            "Parsed text"
+        );
+        Ok(result)
+    }
+
+    /// The files of the directory that can be parsed, along with the skipped ones
+    pub async fn list_files(&self) -> Result<(Vec<PathBuf>, Vec<SkippedFile>), ParserError> {
+        let directory = Path::new(&self.directory_path);
+        let mut entries = fs::read_dir(directory)
+            .await
+            .map_err(ParserError::io(directory))?;
+        let mut files: Vec<PathBuf> = vec![];
+        let mut skipped_files: Vec<SkippedFile> = vec![];
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(ParserError::io(directory))?
+        {
             let path = entry.path();
-            if !path.is_file() {
+            // the progress log of interrupted runs is not a document
+            if !path.is_file() || path.file_name().is_some_and(|n| n == COMPLETION_LOG_FILE) {
                 continue;
             }
-            let result = if path
-                .extension()
-                .expect("Should be able to get file extension")
-                == "pdf"
-            {
-                println!("Extracting text from {:?}", path);
-                self.extract_text_from_pdf(path).await?
-            } else if path
-                .extension()
-                .expect("Should be able to get file extension")
-                == "md"
-                || path
-                    .extension()
-                    .expect("Should be able to get file extension")
-                    == "txt"
-            {
-                println!("Reading text from {:?}", path);
-                self.read_file(path).await?
-            } else {
-                eprintln!(
-                    "Unsupported file format: {:?}. Supported file formats are: .pdf, .txt and .md",
-                    path
-                );
+            if let Some(skipped) = self.validate_file(&path).await? {
+                skipped_files.push(skipped);
                 continue;
-            };
-            println!("Text size: {:?} chars", result.len());
-            results.push(result);
+            }
+            files.push(path);
+        }
+
+        Ok((files, skipped_files))
+    }
+
+    pub async fn parse(&self) -> Result<(Vec<ParsedDocument>, Vec<SkippedFile>), ParserError> {
+        let (files, skipped_files) = self.list_files().await?;
+        let mut results: Vec<ParsedDocument> = vec![];
+        for path in files {
+            if let Some(result) = self.parse_file(path).await? {
+                results.push(result);
+            }
         }
 
+        Ok((results, skipped_files))
+    }
+}
+
+/// The local extraction: text files are read as they are, PDFs with pdf-extract (or OCR)
+#[async_trait]
+impl DocumentParser for Parser {
+    async fn parse_files(&self, files: Vec<PathBuf>) -> anyhow::Result<Vec<ParsedDocument>> {
+        let mut results: Vec<ParsedDocument> = vec![];
+        for path in files {
+            if let Some(result) = self.parse_file(path).await? {
+                results.push(result);
+            }
+        }
         Ok(results)
     }
 }
 
+/// OCR the pages of an image-based PDF: they are rendered with `pdftoppm` (poppler) and read
+/// with `tesseract`, and both binaries have to be installed
+pub async fn parse_scanned_pdf(path: &Path) -> Result<Vec<String>, ParserError> {
+    let pages_directory = tempfile::tempdir().map_err(ParserError::io(path))?;
+    run_command(
+        Command::new("pdftoppm")
+            .args(["-r", OCR_RESOLUTION_DPI, "-png"])
+            .arg(path)
+            .arg(pages_directory.path().join("page")),
+    )
+    .await?;
+    let mut pages: Vec<PathBuf> = vec![];
+    let mut entries = fs::read_dir(pages_directory.path())
+        .await
+        .map_err(ParserError::io(pages_directory.path()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(ParserError::io(pages_directory.path()))?
+    {
+        pages.push(entry.path());
+    }
+    // page numbers are zero-padded, so that the file names sort in page order
+    pages.sort();
+    let mut texts: Vec<String> = vec![];
+    for page in pages {
+        texts.push(run_command(Command::new("tesseract").arg(&page).arg("stdout")).await?);
+    }
+    Ok(texts)
+}
+
+async fn run_command(command: &mut Command) -> Result<String, ParserError> {
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+    let output = match command.output().await {
+        Ok(o) => o,
+        Err(source) => return Err(ParserError::MissingProgram { program, source }),
+    };
+    if !output.status.success() {
+        return Err(ParserError::CommandFailed {
+            program,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[tokio::test]
     async fn test_extract_from_pdf() {
-        let parser = Parser::new("testfiles/".to_string(), true, None, None);
+        let parser = Parser::new("testfiles/".to_string(), true, None, None, None, None);
         let now = tokio::time::Instant::now();
         let result = parser
-            .extract_text_from_pdf(PathBuf::from("testfiles/sample.pdf"))
-            .await;
+            .extract_pages_from_pdf(PathBuf::from("testfiles/sample.pdf"))
+            .await
+            .map(|pages| pages.join("\n"));
         let first_elapsed = now.elapsed();
+        let (hits, misses) = parser.cache_stats();
+        // the cache may be filled by another test
+        assert_eq!(hits + misses, 1);
         match result {
             Ok(s) => {
                 // should contain some text from the file
@@ -122,53 +457,179 @@ mod test {
                 assert!(s.contains("Sample PDF"));
             }
             Err(e) => {
-                println!("An error occurred during the extraction: {}", e.to_string());
-                assert!(false);
+                println!("An error occurred during the extraction: {}", e);
+                panic!();
             }
         }
         let now1 = tokio::time::Instant::now();
         let result1 = parser
-            .extract_text_from_pdf(PathBuf::from("testfiles/sample.pdf"))
+            .extract_pages_from_pdf(PathBuf::from("testfiles/sample.pdf"))
             .await;
         let second_elapsed = now1.elapsed();
         assert!(result1.is_ok());
+        assert_eq!(parser.cache_stats().0, hits + 1);
         // cache access should make the extraction from the PDF file faster the second time
         assert!(second_elapsed < first_elapsed);
     }
 
+    #[tokio::test]
+    async fn test_cache_stale_path() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache_directory = directory.path().join("cache").to_string_lossy().to_string();
+        let parser = Parser::new(
+            directory.path().to_string_lossy().to_string(),
+            true,
+            Some(cache_directory),
+            None,
+            None,
+            None,
+        );
+        let path = directory.path().join("report.pdf");
+        let original = std::fs::read("testfiles/sample.pdf").unwrap();
+        std::fs::write(&path, &original).unwrap();
+        parser.extract_pages_from_pdf(path.clone()).await.unwrap();
+        assert_eq!(parser.cache_stats(), (0, 1));
+        // a file edited at the same path is not read from the stale entry
+        let mut edited = original.clone();
+        edited.extend_from_slice(b"\n%edited\n");
+        std::fs::write(&path, &edited).unwrap();
+        parser.extract_pages_from_pdf(path.clone()).await.unwrap();
+        assert_eq!(parser.cache_stats(), (0, 2));
+        // while a renamed file is
+        let renamed = directory.path().join("renamed.pdf");
+        std::fs::write(&renamed, &original).unwrap();
+        parser.extract_pages_from_pdf(renamed).await.unwrap();
+        assert_eq!(parser.cache_stats(), (1, 2));
+        let cache = parser.cache();
+        let key = Cache::content_key(PDF_PAGES_KIND, &original);
+        let entries = cache.list().unwrap();
+        assert_eq!(entries.len(), 2);
+        let entry = entries.iter().find(|e| e.key == key).unwrap();
+        assert_eq!(
+            entry.original_path,
+            Some(path.to_string_lossy().to_string())
+        );
+    }
+
     #[tokio::test]
     async fn test_read_file() {
-        let parser = Parser::new("testfiles/".to_string(), true, None, None);
+        let parser = Parser::new("testfiles/".to_string(), true, None, None, None, None);
         let result = parser.read_file(PathBuf::from("testfiles/test.txt")).await;
         match result {
             Ok(s) => {
                 assert!(s.contains("This is a test!"));
             }
             Err(e) => {
-                println!(
-                    "An error occurred while reading the file: {}",
-                    e.to_string()
-                );
-                assert!(false);
+                println!("An error occurred while reading the file: {}", e);
+                panic!();
             }
         }
     }
 
     #[tokio::test]
     async fn test_parse() {
-        let parser = Parser::new("testfiles/".to_string(), true, None, None);
+        let parser = Parser::new("testfiles/".to_string(), true, None, None, None, None);
         let results = parser.parse().await;
         match results {
-            Ok(v) => {
+            Ok((v, skipped)) => {
                 assert_eq!(v.len(), 2);
+                // only PDFs have pages
+                let pdf = v
+                    .iter()
+                    .find(|d| d.source == "testfiles/sample.pdf")
+                    .unwrap();
+                assert_eq!(pdf.text, pdf.pages.as_ref().unwrap().join("\n"));
+                let txt = v.iter().find(|d| d.source == "testfiles/test.txt").unwrap();
+                assert!(txt.pages.is_none());
+                assert_eq!(skipped.len(), 1);
+                assert_eq!(skipped[0].path, PathBuf::from("testfiles/unsupported.json"));
             }
             Err(e) => {
-                println!(
-                    "An error occurred while parsing testfiles/: {}",
-                    e.to_string()
-                );
-                assert!(false);
+                println!("An error occurred while parsing testfiles/: {}", e);
+                panic!();
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_parse_file() {
+        let parser = Parser::new("testfiles/".to_string(), true, None, None, None, None);
+        let result = parser
+            .parse_file(PathBuf::from("testfiles/test.txt"))
+            .await
+            .unwrap();
+        assert!(result.unwrap().text.contains("This is a test!"));
+        let unsupported = parser
+            .parse_file(PathBuf::from("testfiles/unsupported.json"))
+            .await
+            .unwrap();
+        assert!(unsupported.is_none());
+        let missing = parser
+            .parse_file(PathBuf::from("testfiles/does-not-exist.txt"))
+            .await;
+        assert!(matches!(missing, Err(ParserError::Io { .. })));
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        let parser = Parser::new("testfiles/".to_string(), true, None, None, None, None);
+        let result = parser.parse_bytes("notes.md", b"# Notes".to_vec()).unwrap();
+        assert_eq!(
+            result,
+            ParsedDocument::new("notes.md".to_string(), "# Notes".to_string())
+        );
+        let pdf_bytes = std::fs::read("testfiles/sample.pdf").unwrap();
+        let pdf_result = parser.parse_bytes("sample.pdf", pdf_bytes).unwrap();
+        assert!(pdf_result.text.contains("Sample PDF"));
+        assert!(!pdf_result.pages.unwrap().is_empty());
+        let binary = parser.parse_bytes("binary.txt", vec![0xff, 0xfe]);
+        assert_eq!(
+            binary.unwrap_err().to_string(),
+            "binary.txt is not valid UTF-8 text"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_file() {
+        let parser = Parser::new("testfiles/".to_string(), true, None, None, None, None);
+        let valid_pdf = parser
+            .validate_file(Path::new("testfiles/sample.pdf"))
+            .await
+            .unwrap();
+        assert!(valid_pdf.is_none());
+        let valid_txt = parser
+            .validate_file(Path::new("testfiles/test.txt"))
+            .await
+            .unwrap();
+        assert!(valid_txt.is_none());
+        // a text file disguised as a PDF should be rejected
+        let directory = tempfile::tempdir().unwrap();
+        let fake_pdf = directory.path().join("fake.pdf");
+        fs::write(&fake_pdf, "not a pdf").await.unwrap();
+        let skipped = parser.validate_file(&fake_pdf).await.unwrap();
+        assert!(skipped.is_some());
+        let small_parser = Parser::new("testfiles/".to_string(), true, None, None, None, Some(0));
+        let too_large = small_parser
+            .validate_file(Path::new("testfiles/test.txt"))
+            .await
+            .unwrap();
+        assert!(too_large.unwrap().reason.contains("exceeds"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_scanned_pdf() {
+        let missing = run_command(&mut Command::new("rag-rs-missing-binary")).await;
+        assert!(missing.unwrap_err().to_string().contains("is it installed"));
+        if ["pdftoppm", "tesseract"]
+            .iter()
+            .any(|b| std::process::Command::new(b).arg("-v").output().is_err())
+        {
+            println!("Skipping test because pdftoppm or tesseract are not installed");
+            return;
+        }
+        let pages = parse_scanned_pdf(Path::new("testfiles/sample.pdf"))
+            .await
+            .unwrap();
+        assert!(pages[0].contains("Sample PDF"));
+    }
 }
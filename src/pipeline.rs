@@ -1,21 +1,180 @@
-use crate::{chunking::chunk_text, embedding::embed_chunks, parsing::Parser, vectordb::VectorDB};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use tracing::warn;
+
+use crate::{
+    caching::{Cache, CacheOptions},
+    checkpointing::{Checkpoint, CompletionLog, Stage, content_hash},
+    chunking::{
+        Chunk, ChunkStrategy, ChunkUnit, Chunker, ChunkingConfig, ChunkingError, read_chunks_jsonl,
+        write_chunks_jsonl,
+    },
+    cleaning::clean_document,
+    embedding::{EmbeddingConfig, EmbeddingOptions, embed_chunks},
+    fetching::Fetcher,
+    parsing::{DocumentParser, ParsedDocument, Parser, SkippedFile},
+    s3::S3Source,
+    vectordb::{VectorDB, validate_collection_name},
+};
+
+#[derive(Debug, Default)]
+pub struct PipelineResult {
+    pub skipped_files: Vec<SkippedFile>,
+    /// Number of PDFs whose text was read from the parse cache
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+    /// Bytes removed from each document by the text cleaning
+    pub cleaned_bytes: Vec<(String, usize)>,
+    /// Estimated cost of the calls to the dense embedding API, if the provider uses one
+    pub estimated_embedding_api_cost_usd: Option<f64>,
+}
+
+/// An input of the pipeline, loading parsed documents from anywhere
+#[async_trait]
+pub trait DocumentSource: Send + Sync {
+    /// Load the documents, along with the files that were skipped
+    async fn load_documents(&self) -> anyhow::Result<(Vec<ParsedDocument>, Vec<SkippedFile>)>;
+}
+
+/// The supported files in the directory of the parser
+pub struct LocalDirectorySource {
+    parser: Arc<Parser>,
+    // parses the files listed by the parser, instead of the parser itself
+    backend: Option<Arc<dyn DocumentParser>>,
+}
+
+impl LocalDirectorySource {
+    pub fn new(parser: Arc<Parser>) -> Self {
+        Self {
+            parser,
+            backend: None,
+        }
+    }
+
+    /// Parse the files of the directory with another backend than the local extraction
+    pub fn with_backend(parser: Arc<Parser>, backend: Arc<dyn DocumentParser>) -> Self {
+        Self {
+            parser,
+            backend: Some(backend),
+        }
+    }
+}
+
+#[async_trait]
+impl DocumentSource for LocalDirectorySource {
+    async fn load_documents(&self) -> anyhow::Result<(Vec<ParsedDocument>, Vec<SkippedFile>)> {
+        let context = || {
+            format!(
+                "Could not parse the files in directory {}",
+                self.parser.directory_path
+            )
+        };
+        let Some(backend) = &self.backend else {
+            return self.parser.parse().await.with_context(context);
+        };
+        let (files, skipped) = self.parser.list_files().await.with_context(context)?;
+        let documents = backend.parse_files(files).await.with_context(context)?;
+        Ok((documents, skipped))
+    }
+}
+
+/// Documents held in memory (e.g. read from stdin), as pairs of source name and content.
+/// The format of each document is inferred from the extension of its source name.
+pub struct InMemorySource {
+    documents: Vec<(String, Vec<u8>)>,
+}
+
+impl InMemorySource {
+    pub fn new(documents: Vec<(String, Vec<u8>)>) -> Self {
+        Self { documents }
+    }
+}
+
+#[async_trait]
+impl DocumentSource for InMemorySource {
+    async fn load_documents(&self) -> anyhow::Result<(Vec<ParsedDocument>, Vec<SkippedFile>)> {
+        let parser = Parser::new(String::new(), false, None, None, None, None);
+        let mut documents: Vec<ParsedDocument> = vec![];
+        for (source, bytes) in &self.documents {
+            documents.push(
+                parser
+                    .parse_bytes(source, bytes.clone())
+                    .with_context(|| format!("Could not parse {}", source))?,
+            );
+        }
+        Ok((documents, vec![]))
+    }
+}
 
 pub struct Pipeline {
     // Parsing options
-    pub directory_path: String,
+    pub directory_path: Option<String>,
+    pub s3_source: Option<S3Source>,
+    pub files: Vec<String>,
+    /// Other inputs, e.g. the content read from stdin
+    pub sources: Vec<Box<dyn DocumentSource>>,
     pub cached: bool,
     pub cache_directory: Option<String>,
     pub cache_chunk_size: Option<usize>,
+    pub cache_encryption_key: Option<[u8; 32]>,
+    /// Maximum size and TTL of the cache
+    pub cache_options: CacheOptions,
+    pub max_file_size_mb: Option<u64>,
+    pub ocr_enabled: bool,
+    /// Backend parsing the files of the directory and the `files`, instead of the local
+    /// extraction (e.g. a mock in tests)
+    pub parser: Option<Arc<dyn DocumentParser>>,
+    // Checkpointing options
+    pub checkpoint: bool,
+    pub resume: bool,
+    /// Do not skip the files listed in the completion log of the directory
+    pub ignore_checkpoint: bool,
+    /// Add the documents to a collection that already has points, instead of skipping the upload
+    pub append: bool,
+    /// Delete the chunks already uploaded for each document before uploading it, so that loading
+    /// a document again replaces its chunks instead of adding them twice
+    pub replace: bool,
+    // Fetching options
+    pub urls: Vec<String>,
+    pub fetch_timeout_secs: Option<u64>,
+    pub fetch_max_size: Option<usize>,
+    pub fetch_max_redirects: Option<usize>,
+    // Cleaning options
+    /// Clean the parsed text before chunking, see `clean_text`
+    pub clean_text: bool,
+    /// Lines found on more pages of a document (e.g. headers and footers) are dropped when cleaning
+    pub max_line_repeats: Option<usize>,
     // Chunking options
     pub chunk_size: usize,
+    pub chunk_strategy: ChunkStrategy,
+    pub chunk_unit: ChunkUnit,
+    /// Separators of the recursive strategy, from the preferred one. Defaults to paragraphs,
+    /// lines, sentences and words
+    pub separators: Vec<String>,
+    /// Smaller chunks are merged into the preceding one when they fit. Defaults to 10% of the chunk size
+    pub min_chunk_size: Option<usize>,
+    /// Chunker of the documents, instead of the one built from the chunking options
+    pub chunker: Option<Box<dyn Chunker>>,
+    // Chunk export options
+    /// Write the chunks to this JSONL file instead of embedding and uploading them
+    pub export_chunks: Option<String>,
+    /// Embed and upload the chunks of this JSONL file (e.g. written with `export_chunks`),
+    /// instead of parsing and chunking documents
+    pub from_chunks: Option<String>,
+    // Embedding options
+    pub embedding_options: EmbeddingOptions,
     // VectorDB options
     qdrant_url: String,
+    /// Connect to Qdrant with TLS, see `DefaultQdrantClientFactory`
+    pub qdrant_tls: bool,
     pub collection_name: String,
 }
 
 impl Pipeline {
     pub fn new(
-        directory_path: String,
+        directory_path: Option<String>,
         chunk_size: usize,
         qdrant_url: String,
         collection_name: String,
@@ -26,36 +185,444 @@ impl Pipeline {
         Self {
             directory_path,
             chunk_size,
+            clean_text: true,
+            max_line_repeats: None,
+            chunk_strategy: ChunkStrategy::default(),
+            chunk_unit: ChunkUnit::default(),
+            separators: vec![],
+            min_chunk_size: None,
+            chunker: None,
+            export_chunks: None,
+            from_chunks: None,
             qdrant_url,
+            qdrant_tls: false,
             collection_name,
             cache_directory,
             cache_chunk_size,
             cached,
+            cache_encryption_key: None,
+            cache_options: CacheOptions::default(),
+            s3_source: None,
+            files: vec![],
+            sources: vec![],
+            max_file_size_mb: None,
+            ocr_enabled: false,
+            parser: None,
+            checkpoint: true,
+            resume: false,
+            ignore_checkpoint: false,
+            append: false,
+            replace: false,
+            urls: vec![],
+            fetch_timeout_secs: None,
+            fetch_max_size: None,
+            fetch_max_redirects: None,
+            embedding_options: EmbeddingOptions::default(),
         }
     }
 
-    pub async fn run(&self) -> anyhow::Result<()> {
-        let parser = Parser::new(
-            self.directory_path.clone(),
+    pub async fn run(&self) -> anyhow::Result<PipelineResult> {
+        if self.directory_path.is_none()
+            && self.s3_source.is_none()
+            && self.files.is_empty()
+            && self.sources.is_empty()
+            && self.urls.is_empty()
+            && self.from_chunks.is_none()
+        {
+            return Err(anyhow::anyhow!(
+                "At least one input source (directory, S3 URI, file, stdin, URL or chunks file) should be provided"
+            ));
+        }
+        if self.from_chunks.is_some()
+            && (self.directory_path.is_some()
+                || self.s3_source.is_some()
+                || !self.files.is_empty()
+                || !self.sources.is_empty()
+                || !self.urls.is_empty()
+                || self.export_chunks.is_some())
+        {
+            return Err(anyhow::anyhow!(
+                "The chunks of a chunks file cannot be combined with other input sources, or exported"
+            ));
+        }
+        validate_collection_name(&self.collection_name)?;
+        // fail before downloading or parsing anything else
+        if self.s3_source.is_none()
+            && let Some(directory_path) = &self.directory_path
+            && tokio::fs::metadata(directory_path).await.is_err()
+        {
+            return Err(anyhow::anyhow!(
+                "Directory '{}' does not exist or is not accessible",
+                directory_path
+            ));
+        }
+        // keeps the downloaded S3 objects alive until the end of the run
+        let mut s3_directory: Option<tempfile::TempDir> = None;
+        let mut s3_keys: Option<HashMap<PathBuf, String>> = None;
+        let directory_path = match (&self.s3_source, &self.directory_path) {
+            (Some(s3_source), _) => {
+                let tmp_dir = tempfile::tempdir()?;
+                s3_keys = Some(s3_source.download_to(tmp_dir.path()).await?);
+                let path = tmp_dir.path().to_string_lossy().to_string();
+                s3_directory = Some(tmp_dir);
+                Some(path)
+            }
+            (None, directory_path) => directory_path.clone(),
+        };
+        let mut parser = Parser::new(
+            directory_path.clone().unwrap_or_default(),
             self.cached,
             self.cache_directory.clone(),
             self.cache_chunk_size,
+            Some(format!("{}-{}", self.collection_name, self.chunk_size)),
+            self.max_file_size_mb,
+        );
+        parser.ocr_enabled = self.ocr_enabled;
+        parser.cache_encryption_key = self.cache_encryption_key;
+        parser.cache_options = self.cache_options;
+        // shared with the directory source, to report the cache statistics of the whole run
+        let parser = Arc::new(parser);
+        let backend: Arc<dyn DocumentParser> = match &self.parser {
+            Some(p) => p.clone(),
+            None => parser.clone(),
+        };
+        let mut pipeline_result = PipelineResult::default();
+        let vectordb = VectorDB::new(
+            self.qdrant_url.clone(),
+            self.collection_name.clone(),
+            self.qdrant_tls,
         );
-        let vectordb = VectorDB::new(self.qdrant_url.clone(), self.collection_name.clone());
-        let results = parser.parse().await?;
-        vectordb.create_collection().await?;
-        for result in results {
-            let mut chunks = chunk_text(result, self.chunk_size);
-            chunks = embed_chunks(chunks);
-            vectordb.upload_embeddings(chunks).await?;
+        let mut results: Vec<ParsedDocument> = vec![];
+        let source_of = |path: &PathBuf| match s3_keys.as_ref().and_then(|keys| keys.get(path)) {
+            Some(key) => key.clone(),
+            None => path.to_string_lossy().to_string(),
+        };
+        if directory_path.is_some() {
+            let source = match &self.parser {
+                Some(p) => LocalDirectorySource::with_backend(parser.clone(), p.clone()),
+                None => LocalDirectorySource::new(parser.clone()),
+            };
+            let mut loaded = source.load_documents().await;
+            if self.s3_source.is_some() {
+                loaded = loaded.context("Could not parse the objects downloaded from S3");
+            }
+            let (parsed, skipped) = loaded?;
+            results.extend(parsed.into_iter().map(|d| ParsedDocument {
+                source: source_of(&PathBuf::from(&d.source)),
+                ..d
+            }));
+            pipeline_result
+                .skipped_files
+                .extend(skipped.into_iter().map(|f| SkippedFile {
+                    path: PathBuf::from(source_of(&f.path)),
+                    reason: f.reason,
+                }));
         }
-        Ok(())
+        for file in &self.files {
+            let path = PathBuf::from(file);
+            let context = || format!("Could not parse file {}", file);
+            if let Some(skipped) = parser.validate_file(&path).await.with_context(context)? {
+                pipeline_result.skipped_files.push(skipped);
+                continue;
+            }
+            let documents = backend
+                .parse_files(vec![path])
+                .await
+                .with_context(context)?;
+            results.extend(documents.into_iter().map(|d| ParsedDocument {
+                source: file.clone(),
+                ..d
+            }));
+        }
+        for source in &self.sources {
+            let (documents, skipped) = source.load_documents().await?;
+            results.extend(documents);
+            pipeline_result.skipped_files.extend(skipped);
+        }
+        (pipeline_result.cache_hits, pipeline_result.cache_misses) = parser.cache_stats();
+        if !self.urls.is_empty() {
+            let fetcher = Fetcher::new(
+                self.fetch_timeout_secs,
+                self.fetch_max_size,
+                self.fetch_max_redirects,
+            );
+            let pages = fetcher.fetch(&self.urls).await?;
+            results.extend(
+                pages
+                    .into_iter()
+                    .map(|(url, text)| ParsedDocument::new(url, text)),
+            );
+        }
+        let mut checkpoint = if self.checkpoint {
+            let cache_directory = Cache::new(self.cache_directory.clone(), None, None).directory;
+            if self.resume {
+                Some(Checkpoint::load(&cache_directory, &self.collection_name).await?)
+            } else {
+                Some(Checkpoint::new(&cache_directory, &self.collection_name))
+            }
+        } else {
+            None
+        };
+        // only local directories get a completion log, downloaded S3 objects live in a temporary one
+        let mut completion_log = match (&self.directory_path, &self.s3_source) {
+            (Some(directory), None) if self.checkpoint => {
+                if self.ignore_checkpoint {
+                    let log = CompletionLog::new(directory);
+                    // start from scratch instead of appending to the previous entries
+                    log.remove().await?;
+                    Some(CompletionLog::new(directory))
+                } else {
+                    Some(CompletionLog::load(directory).await?)
+                }
+            }
+            _ => None,
+        };
+        // exporting the chunks does not need the collection
+        let exporting = self.export_chunks.is_some();
+        // the collection has to be embedded with the same parameters across runs
+        let stored_config = if !exporting && vectordb.exists().await? {
+            EmbeddingConfig::from_metadata(&vectordb.get_metadata().await?)?
+        } else {
+            None
+        };
+        if let Some(stored) = &stored_config {
+            self.embedding_options.check_compatible(stored)?;
+        }
+        let requested_config = self.embedding_options.to_config();
+        if !exporting {
+            vectordb
+                .create_collection(
+                    stored_config
+                        .as_ref()
+                        .map_or(requested_config.provider, |c| c.provider),
+                )
+                .await?;
+        }
+        let chunking_config = ChunkingConfig {
+            min_size: self.min_chunk_size,
+            separators: self.separators.clone(),
+            ..ChunkingConfig::new(self.chunk_strategy, self.chunk_unit, self.chunk_size)
+        };
+        let built_chunker;
+        let chunker = match &self.chunker {
+            Some(chunker) => chunker.as_ref(),
+            None => {
+                // semantic chunking compares the sentences with the embedder of the collection
+                let sentence_embedder = match self.chunk_strategy {
+                    ChunkStrategy::Semantic => Some(
+                        stored_config
+                            .clone()
+                            .unwrap_or_else(|| requested_config.clone())
+                            .build_embedder(None)?,
+                    ),
+                    _ => None,
+                };
+                built_chunker = chunking_config.build_chunker(sentence_embedder)?;
+                built_chunker.as_ref()
+            }
+        };
+        let mut documents: Vec<(String, String, Vec<Chunk>)> = vec![];
+        // the documents left by an interrupted run are added to the ones it uploaded: a missing
+        // checkpoint is empty, and resuming from it uploads nothing to a collection with points
+        let mut continuing = checkpoint.as_ref().is_some_and(Checkpoint::has_uploads);
+        for document in results {
+            let source = document.source.clone();
+            let hash = content_hash(&document.text);
+            if let Some(log) = completion_log.as_ref()
+                && log.is_done(&source, &hash)
+            {
+                println!(
+                    "Skipping {} because it was completed by an interrupted run",
+                    source
+                );
+                continuing = true;
+                continue;
+            }
+            if let Some(checkpoint) = checkpoint.as_mut() {
+                if self.resume && checkpoint.is_uploaded(&source, &hash) {
+                    println!("Skipping {} because it was already uploaded", source);
+                    continue;
+                }
+                checkpoint.mark(&source, &hash, Stage::Parsed).await?;
+            }
+            let document = match self.clean_text {
+                true => {
+                    let cleaned = clean_document(&document, self.max_line_repeats);
+                    let removed = document.text.len().saturating_sub(cleaned.text.len());
+                    pipeline_result
+                        .cleaned_bytes
+                        .push((source.clone(), removed));
+                    cleaned
+                }
+                false => document,
+            };
+            let chunks = match chunker.chunk(&document).await {
+                Ok(chunks) => chunks,
+                Err(e) if matches!(e.downcast_ref(), Some(ChunkingError::EmptyInput)) => {
+                    warn!(source, "Document is empty, skipping it");
+                    pipeline_result.skipped_files.push(SkippedFile {
+                        path: PathBuf::from(&source),
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+                Err(e) => return Err(e.context(format!("Could not chunk {}", source))),
+            };
+            documents.push((source, hash, chunks));
+        }
+        if let Some(path) = &self.from_chunks {
+            let chunks = read_chunks_jsonl(path).await?;
+            println!("Read {} chunks from {}", chunks.len(), path);
+            documents.extend(group_by_source(chunks));
+        }
+        if let Some(path) = &self.export_chunks {
+            let chunks: Vec<&Chunk> = documents
+                .iter()
+                .flat_map(|(_, _, chunks)| chunks.iter())
+                .collect();
+            write_chunks_jsonl(path, &chunks).await?;
+            println!("Exported {} chunks to {}", chunks.len(), path);
+            drop(s3_directory);
+            return Ok(pipeline_result);
+        }
+        // checked before embedding, which may call a paid API
+        let Some(mut last_id) = vectordb.upload_base_id(self.append || continuing).await? else {
+            println!(
+                "Collection {} already has points, skipping the upload: use `--append` to add the documents to it",
+                self.collection_name
+            );
+            drop(s3_directory);
+            return Ok(pipeline_result);
+        };
+        // the chunking of the last run, to report how the collection was chunked
+        if self.from_chunks.is_none() {
+            vectordb
+                .set_metadata(chunking_config.to_metadata()?)
+                .await?;
+        }
+        let embedding_config = match stored_config {
+            Some(stored) => {
+                println!(
+                    "Using the embedding configuration stored in the collection: {:?}",
+                    stored
+                );
+                stored
+            }
+            None if requested_config.provider.sparse_vector_name().is_some() => {
+                let corpus: Vec<&str> = documents
+                    .iter()
+                    .flat_map(|(_, _, chunks)| chunks.iter().map(|c| c.content.as_str()))
+                    .collect();
+                let fitted = requested_config.fit_to_corpus(&corpus)?;
+                println!("Fitted average document length: {}", fitted.avgdl);
+                vectordb.set_metadata(fitted.to_metadata()?).await?;
+                fitted
+            }
+            None => {
+                vectordb
+                    .set_metadata(requested_config.to_metadata()?)
+                    .await?;
+                requested_config
+            }
+        };
+        let contents: Vec<&str> = documents
+            .iter()
+            .flat_map(|(_, _, chunks)| chunks.iter().map(|c| c.content.as_str()))
+            .collect();
+        pipeline_result.estimated_embedding_api_cost_usd =
+            embedding_config.provider.estimate_api_cost_usd(&contents);
+        let embedder = embedding_config.build_embedder(None)?;
+        for (source, hash, chunks) in documents {
+            let chunks = embed_chunks(chunks, embedder.as_ref()).await?;
+            if let Some(checkpoint) = checkpoint.as_mut() {
+                checkpoint.mark(&source, &hash, Stage::Embedded).await?;
+            }
+            // the document may have been uploaded with another content before the interruption
+            if self.replace || continuing {
+                let deleted = vectordb.delete_source_points(&source).await?;
+                if deleted > 0 {
+                    println!("Replacing the {} chunks of {}", deleted, source);
+                }
+            }
+            // appended to the documents uploaded before in this run
+            last_id = vectordb
+                .upload_embeddings(chunks, embedding_config.provider, last_id)
+                .await?;
+            if let Some(checkpoint) = checkpoint.as_mut() {
+                checkpoint.mark(&source, &hash, Stage::Uploaded).await?;
+            }
+            if let Some(log) = completion_log.as_mut() {
+                log.mark_done(&source, &hash).await?;
+            }
+        }
+        if let Some(log) = completion_log {
+            log.remove().await?;
+        }
+        drop(s3_directory);
+        Ok(pipeline_result)
     }
 }
 
+/// Group imported chunks by source, in the order of their first chunk, along with the hash of
+/// their content for the checkpoints
+fn group_by_source(chunks: Vec<Chunk>) -> Vec<(String, String, Vec<Chunk>)> {
+    let mut groups: Vec<(String, Vec<Chunk>)> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for chunk in chunks {
+        let source = chunk.source.clone().unwrap_or_default();
+        let position = *positions.entry(source.clone()).or_insert_with(|| {
+            groups.push((source, vec![]));
+            groups.len() - 1
+        });
+        groups[position].1.push(chunk);
+    }
+    groups
+        .into_iter()
+        .map(|(source, chunks)| {
+            let content: Vec<&str> = chunks.iter().map(|c| c.content.as_str()).collect();
+            (source, content_hash(content.join("\n")), chunks)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
-    use crate::pipeline::Pipeline;
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use async_trait::async_trait;
+
+    use crate::checkpointing::content_hash;
+    use crate::chunking::read_chunks_jsonl;
+    use crate::parsing::{DocumentParser, ParsedDocument, Parser};
+    use crate::pipeline::{
+        DocumentSource, InMemorySource, LocalDirectorySource, Pipeline, group_by_source,
+    };
+    use crate::vectordb::VectorDB;
+
+    /// Records the files it is given, and returns the same text for each of them
+    #[derive(Default)]
+    struct MockParser {
+        files: Mutex<Vec<PathBuf>>,
+    }
+
+    #[async_trait]
+    impl DocumentParser for MockParser {
+        async fn parse_files(&self, files: Vec<PathBuf>) -> anyhow::Result<Vec<ParsedDocument>> {
+            self.files.lock().unwrap().extend(files.clone());
+            Ok(files
+                .into_iter()
+                .map(|f| {
+                    ParsedDocument::new(
+                        f.to_string_lossy().to_string(),
+                        "Parsed by the mock".to_string(),
+                    )
+                })
+                .collect())
+        }
+    }
 
     #[tokio::test]
     async fn test_pipeline_run() {
@@ -68,15 +635,334 @@ mod test {
             }
         };
         let pipeline = Pipeline::new(
-            "testfiles/".to_string(),
+            Some("testfiles/".to_string()),
+            1024_usize,
+            qdrant_url,
+            "test-collection".to_string(),
+            true,
+            None,
+            None,
+        );
+        let result = pipeline.run().await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_append() {
+        let qdrant_url = match std::env::var("QDRANT_URL") {
+            Ok(s) => s,
+            Err(_) => {
+                println!("Skipping test because Qdrant is not available");
+                return;
+            }
+        };
+        let collection_name = "test-append-collection";
+        let client = qdrant_client::Qdrant::from_url(&qdrant_url)
+            .build()
+            .unwrap();
+        if client.collection_exists(collection_name).await.unwrap() {
+            client.delete_collection(collection_name).await.unwrap();
+        }
+        let mut pipeline = Pipeline::new(
+            None,
             1024_usize,
             qdrant_url,
+            collection_name.to_string(),
+            false,
+            None,
+            None,
+        );
+        pipeline.checkpoint = false;
+        pipeline.sources = vec![Box::new(InMemorySource::new(vec![
+            ("a.md".to_string(), b"# A\n\nFirst notes".to_vec()),
+            ("b.md".to_string(), b"# B\n\nSecond notes".to_vec()),
+        ]))];
+        let vectordb = VectorDB::new(
+            pipeline.qdrant_url.clone(),
+            collection_name.to_string(),
+            false,
+        );
+        pipeline.run().await.unwrap();
+        let points = vectordb.check_collection_ready().await.unwrap();
+        assert!(points >= 2);
+        // loading again is a no-op
+        pipeline.run().await.unwrap();
+        assert_eq!(vectordb.check_collection_ready().await.unwrap(), points);
+        pipeline.append = true;
+        pipeline.run().await.unwrap();
+        assert_eq!(vectordb.check_collection_ready().await.unwrap(), 2 * points);
+        // the chunks of both copies are replaced by the new ones
+        pipeline.replace = true;
+        pipeline.run().await.unwrap();
+        assert_eq!(vectordb.check_collection_ready().await.unwrap(), points);
+        client.delete_collection(collection_name).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_run_without_sources() {
+        let pipeline = Pipeline::new(
+            None,
+            1024_usize,
+            "http://localhost:6334".to_string(),
             "test-collection".to_string(),
             true,
             None,
             None,
         );
         let result = pipeline.run().await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_run_single_file_and_stdin() {
+        let qdrant_url_var = std::env::var("QDRANT_URL");
+        let qdrant_url = match qdrant_url_var {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                println!("Skipping test because Qdrant is not available");
+                return;
+            }
+        };
+        let mut pipeline = Pipeline::new(
+            None,
+            1024_usize,
+            qdrant_url,
+            "test-single-file-collection".to_string(),
+            true,
+            None,
+            None,
+        );
+        pipeline.files = vec![
+            "testfiles/sample.pdf".to_string(),
+            "testfiles/unsupported.json".to_string(),
+        ];
+        pipeline.sources = vec![Box::new(InMemorySource::new(vec![
+            ("notes.md".to_string(), b"# Notes".to_vec()),
+            // skipped instead of failing the run
+            ("empty.txt".to_string(), b"\n".to_vec()),
+        ]))];
+        let result = pipeline.run().await;
         assert!(result.is_ok());
+        let skipped = result.unwrap().skipped_files;
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[1].path, PathBuf::from("empty.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_export_chunks() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("chunks.jsonl");
+        let mut pipeline = Pipeline::new(
+            Some("testfiles/".to_string()),
+            1024_usize,
+            // not accessed when exporting
+            "http://localhost:1".to_string(),
+            "test-export-collection".to_string(),
+            false,
+            None,
+            None,
+        );
+        pipeline.checkpoint = false;
+        pipeline.export_chunks = Some(path.to_string_lossy().to_string());
+        pipeline.run().await.unwrap();
+        let chunks = read_chunks_jsonl(&path.to_string_lossy()).await.unwrap();
+        assert!(!chunks.is_empty());
+        assert!(chunks.iter().all(|c| c.source.is_some()
+            && c.sparse_embedding.is_none()
+            && c.dense_embedding.is_none()));
+        let groups = group_by_source(chunks.clone());
+        assert_eq!(
+            groups.iter().map(|(_, _, c)| c.len()).sum::<usize>(),
+            chunks.len()
+        );
+        assert!(
+            groups
+                .iter()
+                .all(|(source, _, c)| c.iter().all(|c| c.source.as_ref() == Some(source)))
+        );
+        pipeline.from_chunks = pipeline.export_chunks.clone();
+        let err = pipeline.run().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The chunks of a chunks file cannot be combined with other input sources, or exported"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunks_round_trip() {
+        let qdrant_url = match std::env::var("QDRANT_URL") {
+            Ok(s) => s,
+            Err(_) => {
+                println!("Skipping test because Qdrant is not available");
+                return;
+            }
+        };
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory
+            .path()
+            .join("chunks.jsonl")
+            .to_string_lossy()
+            .to_string();
+        let mut loaded = Pipeline::new(
+            Some("testfiles/".to_string()),
+            1024_usize,
+            qdrant_url.clone(),
+            "test-parsed-chunks-collection".to_string(),
+            false,
+            None,
+            None,
+        );
+        loaded.checkpoint = false;
+        loaded.run().await.unwrap();
+        loaded.export_chunks = Some(path.clone());
+        loaded.run().await.unwrap();
+        let mut imported = Pipeline::new(
+            None,
+            1024_usize,
+            qdrant_url.clone(),
+            "test-imported-chunks-collection".to_string(),
+            false,
+            None,
+            None,
+        );
+        imported.checkpoint = false;
+        imported.from_chunks = Some(path);
+        imported.run().await.unwrap();
+        let client = qdrant_client::Qdrant::from_url(&qdrant_url)
+            .build()
+            .unwrap();
+        let mut contents: Vec<Vec<String>> = vec![];
+        for collection in [
+            "test-parsed-chunks-collection",
+            "test-imported-chunks-collection",
+        ] {
+            let points = client
+                .scroll(
+                    qdrant_client::qdrant::ScrollPointsBuilder::new(collection)
+                        .limit(10_000)
+                        .with_payload(true),
+                )
+                .await
+                .unwrap()
+                .result;
+            let mut hashes: Vec<String> = points
+                .iter()
+                .map(|p| content_hash(p.payload["content"].to_string()))
+                .collect();
+            hashes.sort();
+            contents.push(hashes);
+            client.delete_collection(collection).await.unwrap();
+        }
+        assert!(!contents[0].is_empty());
+        assert_eq!(contents[0], contents[1]);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_source() {
+        let source = InMemorySource::new(vec![
+            ("notes.md".to_string(), b"# Notes".to_vec()),
+            (
+                "sample.pdf".to_string(),
+                std::fs::read("testfiles/sample.pdf").unwrap(),
+            ),
+        ]);
+        let (documents, skipped) = source.load_documents().await.unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(documents[0].text, "# Notes");
+        assert_eq!(documents[1].source, "sample.pdf");
+        assert!(documents[1].pages.is_some());
+        let invalid = InMemorySource::new(vec![("binary.txt".to_string(), vec![0xff])]);
+        let err = invalid.load_documents().await.unwrap_err();
+        assert_eq!(err.to_string(), "Could not parse binary.txt");
+    }
+
+    #[tokio::test]
+    async fn test_local_directory_source_backend() {
+        let parser = Arc::new(Parser::new(
+            "testfiles/".to_string(),
+            false,
+            None,
+            None,
+            None,
+            None,
+        ));
+        let mock = Arc::new(MockParser::default());
+        let source = LocalDirectorySource::with_backend(parser, mock.clone());
+        let (documents, skipped) = source.load_documents().await.unwrap();
+        // unsupported files are skipped before reaching the backend
+        assert!(skipped.iter().any(|f| f.path.ends_with("unsupported.json")));
+        assert!(!mock.files.lock().unwrap().is_empty());
+        assert_eq!(documents.len(), mock.files.lock().unwrap().len());
+        assert!(documents.iter().all(|d| d.text == "Parsed by the mock"));
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_run_with_mock_parser() {
+        let qdrant_url_var = std::env::var("QDRANT_URL");
+        let qdrant_url = match qdrant_url_var {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                println!("Skipping test because Qdrant is not available");
+                return;
+            }
+        };
+        let mut pipeline = Pipeline::new(
+            Some("testfiles/".to_string()),
+            1024_usize,
+            qdrant_url,
+            "test-mock-parser-collection".to_string(),
+            false,
+            None,
+            None,
+        );
+        let mock = Arc::new(MockParser::default());
+        pipeline.parser = Some(mock.clone());
+        pipeline.files = vec!["testfiles/sample.pdf".to_string()];
+        pipeline.checkpoint = false;
+        let result = pipeline.run().await;
+        assert!(result.is_ok());
+        assert!(
+            mock.files
+                .lock()
+                .unwrap()
+                .contains(&PathBuf::from("testfiles/sample.pdf"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_run_missing_directory() {
+        let pipeline = Pipeline::new(
+            Some("testfiles/does-not-exist/".to_string()),
+            1024_usize,
+            "http://localhost:6334".to_string(),
+            "test-collection".to_string(),
+            true,
+            None,
+            None,
+        );
+        let err = pipeline.run().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Directory 'testfiles/does-not-exist/' does not exist or is not accessible"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_run_missing_file() {
+        let mut pipeline = Pipeline::new(
+            None,
+            1024_usize,
+            "http://localhost:6334".to_string(),
+            "test-collection".to_string(),
+            true,
+            None,
+            None,
+        );
+        pipeline.files = vec!["testfiles/does-not-exist.txt".to_string()];
+        let err = pipeline.run().await.unwrap_err();
+        assert!(
+            format!("{:#}", err)
+                .starts_with("Could not parse file testfiles/does-not-exist.txt: Could not read")
+        );
     }
 }
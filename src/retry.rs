@@ -0,0 +1,142 @@
+use std::{fmt, future::Future, time::Duration};
+
+use tracing::warn;
+
+/// Wait before the first retry with exponential backoff, doubled after each attempt
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between two retries with exponential backoff
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How an operation is retried after an error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryPolicy {
+    /// Retry after this delay, e.g. the one requested by a rate limit
+    After(Duration),
+    /// Retry with exponential backoff
+    Backoff,
+    /// The error is permanent
+    Never,
+}
+
+/// Run `operation` until it succeeds, retrying at most `max_retries` times after the errors
+/// that `policy` deems transient. Each retry is logged as a warning.
+pub async fn with_retry<T, E, F, Fut>(
+    max_retries: u32,
+    policy: impl Fn(&E) -> RetryPolicy,
+    mut operation: F,
+) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        let err = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        let delay = match policy(&err) {
+            _ if attempt >= max_retries => return Err(err),
+            RetryPolicy::Never => return Err(err),
+            RetryPolicy::After(delay) => delay,
+            RetryPolicy::Backoff => backoff(attempt),
+        };
+        attempt += 1;
+        warn!(
+            attempt,
+            max_retries,
+            delay_ms = delay.as_millis() as u64,
+            "Retrying after a transient error: {}",
+            err
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Wait before the retry following `attempt` retries, capped at `MAX_BACKOFF`
+fn backoff(attempt: u32) -> Duration {
+    2_u32
+        .checked_pow(attempt)
+        .and_then(|factor| BASE_BACKOFF.checked_mul(factor))
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    #[tracing_test::traced_test]
+    async fn test_with_retry() {
+        let attempts = AtomicU32::new(0);
+        let start = tokio::time::Instant::now();
+        let result: Result<u32, String> = with_retry(
+            3,
+            |_| RetryPolicy::Backoff,
+            || async {
+                match attempts.fetch_add(1, Ordering::Relaxed) {
+                    n if n < 2 => Err(format!("failure {}", n)),
+                    n => Ok(n),
+                }
+            },
+        )
+        .await;
+        assert_eq!(result, Ok(2));
+        // 1s, then 2s of backoff
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+        assert!(logs_contain("Retrying after a transient error: failure 1"));
+        // the retries are bounded
+        attempts.store(0, Ordering::Relaxed);
+        let result: Result<(), String> = with_retry(
+            2,
+            |_| RetryPolicy::After(Duration::from_millis(10)),
+            || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err("rate limited".to_string())
+            },
+        )
+        .await;
+        assert_eq!(result, Err("rate limited".to_string()));
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        // permanent errors are not retried
+        attempts.store(0, Ordering::Relaxed);
+        let result: Result<(), String> = with_retry(
+            3,
+            |_| RetryPolicy::Never,
+            || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err("invalid api key".to_string())
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff_is_capped() {
+        assert_eq!(backoff(0), BASE_BACKOFF);
+        assert_eq!(backoff(4), Duration::from_secs(16));
+        assert_eq!(backoff(5), MAX_BACKOFF);
+        // would overflow the multiplier
+        assert_eq!(backoff(40), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+        let attempts = AtomicU32::new(0);
+        let start = tokio::time::Instant::now();
+        let result: Result<(), String> = with_retry(
+            40,
+            |_| RetryPolicy::Backoff,
+            || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err("server error".to_string())
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 41);
+        // 1 + 2 + 4 + 8 + 16 seconds, and then 30 seconds for each of the 35 other retries
+        assert_eq!(start.elapsed(), Duration::from_secs(31 + 35 * 30));
+    }
+}
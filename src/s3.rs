@@ -0,0 +1,183 @@
+use std::{collections::HashMap, path::Path, path::PathBuf};
+
+use aws_sdk_s3::Client;
+use tokio::fs;
+
+use crate::parsing::SUPPORTED_EXTENSIONS;
+
+const DEFAULT_MAX_KEYS: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct S3Source {
+    pub bucket: String,
+    pub prefix: String,
+    pub endpoint: Option<String>,
+    pub max_keys: usize,
+}
+
+impl S3Source {
+    pub fn new(
+        uri: &str,
+        endpoint: Option<String>,
+        max_keys: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let (bucket, prefix) = parse_s3_uri(uri)?;
+        Ok(Self {
+            bucket,
+            prefix,
+            endpoint,
+            max_keys: max_keys.unwrap_or(DEFAULT_MAX_KEYS),
+        })
+    }
+
+    async fn build_client(&self) -> Client {
+        let sdk_config = aws_config::load_from_env().await;
+        let mut config = aws_sdk_s3::config::Builder::from(&sdk_config);
+        if let Some(endpoint) = &self.endpoint {
+            // MinIO and most S3-compatible stores need path-style addressing
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+        Client::from_conf(config.build())
+    }
+
+    async fn list_keys(&self, client: &Client) -> anyhow::Result<Vec<String>> {
+        let mut keys: Vec<String> = vec![];
+        let mut pages = client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            for object in page?.contents() {
+                let key = match object.key() {
+                    Some(k) => k,
+                    None => continue,
+                };
+                if !has_supported_extension(key) {
+                    eprintln!(
+                        "Unsupported file format: {:?}. Supported file formats are: .pdf, .txt and .md",
+                        key
+                    );
+                    continue;
+                }
+                if keys.len() == self.max_keys {
+                    eprintln!(
+                        "WARNING: reached the maximum number of S3 keys ({}), ignoring the remaining objects",
+                        self.max_keys
+                    );
+                    return Ok(keys);
+                }
+                keys.push(key.to_string());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Download all the supported objects under the prefix into `directory`.
+    /// Returns a map from the downloaded file path to the `s3://bucket/key` URI of the object.
+    pub async fn download_to(&self, directory: &Path) -> anyhow::Result<HashMap<PathBuf, String>> {
+        let client = self.build_client().await;
+        let keys = self.list_keys(&client).await?;
+        println!(
+            "Downloading {:?} objects from s3://{}/{}",
+            keys.len(),
+            self.bucket,
+            self.prefix
+        );
+        let mut downloaded: HashMap<PathBuf, String> = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            // objects are flattened into the directory, since the parser does not recurse
+            let file_name = key.rsplit('/').next().unwrap_or(key);
+            let file_path = directory.join(format!("{}-{}", i, file_name));
+            let object = client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await?;
+            let mut reader = object.body.into_async_read();
+            let mut file = fs::File::create(&file_path).await?;
+            tokio::io::copy(&mut reader, &mut file).await?;
+            downloaded.insert(file_path, format!("s3://{}/{}", self.bucket, key));
+        }
+        Ok(downloaded)
+    }
+}
+
+fn has_supported_extension(key: &str) -> bool {
+    match Path::new(key).extension() {
+        Some(ext) => SUPPORTED_EXTENSIONS.iter().any(|&e| ext == e),
+        None => false,
+    }
+}
+
+/// Split an `s3://bucket/prefix` URI into its bucket and prefix
+pub fn parse_s3_uri(uri: &str) -> anyhow::Result<(String, String)> {
+    let rest = match uri.strip_prefix("s3://") {
+        Some(r) => r,
+        None => {
+            return Err(anyhow::anyhow!(
+                "Invalid S3 URI: {}. It should be in the form s3://bucket/prefix",
+                uri
+            ));
+        }
+    };
+    let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Invalid S3 URI: {}. Missing bucket name",
+            uri
+        ));
+    }
+    Ok((bucket.to_string(), prefix.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_s3_uri() {
+        let (bucket, prefix) = parse_s3_uri("s3://my-bucket/docs/2024/").unwrap();
+        assert_eq!(bucket, "my-bucket");
+        assert_eq!(prefix, "docs/2024/");
+        let (bucket_1, prefix_1) = parse_s3_uri("s3://my-bucket").unwrap();
+        assert_eq!(bucket_1, "my-bucket");
+        assert_eq!(prefix_1, "");
+        assert!(parse_s3_uri("https://my-bucket/docs").is_err());
+        assert!(parse_s3_uri("s3:///docs").is_err());
+    }
+
+    #[test]
+    fn test_correct_s3_source_init() {
+        let source = S3Source::new("s3://bucket/prefix", None, None).unwrap();
+        assert_eq!(source.bucket, "bucket");
+        assert_eq!(source.prefix, "prefix");
+        assert_eq!(source.max_keys, DEFAULT_MAX_KEYS);
+        assert!(source.endpoint.is_none());
+    }
+
+    #[test]
+    fn test_has_supported_extension() {
+        assert!(has_supported_extension("docs/report.pdf"));
+        assert!(has_supported_extension("notes.md"));
+        assert!(!has_supported_extension("data/table.json"));
+        assert!(!has_supported_extension("docs/"));
+    }
+
+    #[tokio::test]
+    async fn test_download_to() {
+        let s3_uri = match std::env::var("S3_TEST_URI") {
+            Ok(s) => s,
+            Err(_) => {
+                println!("Skipping test because S3 is not available");
+                return;
+            }
+        };
+        let source = S3Source::new(&s3_uri, std::env::var("S3_ENDPOINT").ok(), None).unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let result = source.download_to(directory.path()).await;
+        assert!(result.is_ok());
+    }
+}
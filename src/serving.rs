@@ -1,18 +1,54 @@
-use crate::{embedding::embed_text, vectordb::VectorDB};
-use async_openai::{Client, config::OpenAIConfig, types::responses::CreateResponseArgs};
-use axum::http::header::CONTENT_TYPE;
+use crate::{
+    caching::CacheOptions,
+    checkpointing::content_hash,
+    chunking::Chunk,
+    embedding::{
+        Embedder, EmbeddingConfig, EmbeddingOptions, EmbeddingVector, detect_language,
+        normalize_language_code,
+    },
+    info::BuildInfo,
+    parsing::SkippedFile,
+    pipeline::Pipeline,
+    retry::{RetryPolicy, with_retry},
+    vectordb::{
+        SearchOptions, SearchResult, VectorDB, payload_conditions, validate_collection_name,
+    },
+};
+use async_openai::{
+    Client, config::OpenAIConfig, error::OpenAIError, types::responses::CreateResponseArgs,
+};
+use axum::http::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
 use axum::http::method::Method;
-use axum::{Json, Router, extract::State, response::IntoResponse, routing::post};
-use http::HeaderValue;
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{DefaultBodyLimit, Path, Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use backoff::ExponentialBackoff;
+use http::{HeaderMap, HeaderValue, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
-use tower_governor::{GovernorLayer, governor::GovernorConfigBuilder};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tower_governor::{
+    GovernorError, GovernorLayer,
+    governor::GovernorConfigBuilder,
+    key_extractor::{KeyExtractor, PeerIpKeyExtractor},
+};
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_http::trace::TraceLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
-use tracing::{Level, debug, info, instrument};
+use tracing::{Instrument, Level, Span, debug, error, info, info_span, instrument, warn};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt;
 
@@ -21,36 +57,350 @@ const DEFAULT_HOST: &str = "0.0.0.0";
 const DEFAULT_RATE_LIMIT: u32 = 100;
 const DEFAULT_SEARCH_LIMIT: u64 = 10;
 const DEFAULT_OPENAI_MODEL: &str = "gpt-4.1";
+const DEFAULT_MAX_FORWARDED_HOPS: usize = 2;
+const DEFAULT_RELOAD_CHUNK_SIZE: usize = 1024;
+const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 16 * 1024;
+const DEFAULT_QUERY_HISTORY_SIZE: usize = 100;
+const DEFAULT_OPENAI_MAX_RETRIES: u32 = 3;
+/// Entry of the allowed collections allowing any collection
+const ALL_COLLECTIONS: &str = "*";
+/// Delay before retrying a rate limited OpenAI request: async-openai does not expose the
+/// `Retry-After` header of its errors
+const OPENAI_RATE_LIMIT_DELAY: Duration = Duration::from_secs(1);
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_REAL_IP: &str = "x-real-ip";
+/// `Cache-Control` of the GET responses polled by load balancers and monitoring tools
+const GET_RESPONSE_MAX_AGE: &str = "max-age=5";
 
+#[derive(Clone)]
 pub struct RagServer {
     qdrant_url: String,
+    /// Connect to Qdrant with TLS, see `DefaultQdrantClientFactory`
+    pub qdrant_tls: bool,
     openai_api_key: String,
     pub collection_name: String,
     pub port: u16,
     pub host: IpAddr,
     pub rate_limit_per_minute: u32,
+    /// Requests with larger bodies are rejected with a 413 status code
+    pub max_request_body_bytes: usize,
+    /// Retries of the OpenAI requests failing with a rate limit or a server error
+    pub openai_max_retries: u32,
     pub cors: Option<String>,
     pub log_level: Level,
     pub log_json: bool,
+    /// Embedding parameters expected by the server, checked against the ones stored in the collection
+    pub embedding_options: EmbeddingOptions,
+    /// Rate limit by the client IP reported by a reverse proxy instead of the socket address
+    pub trust_proxy: bool,
+    /// Maximum number of addresses accepted in `X-Forwarded-For` when trusting the proxy
+    pub max_forwarded_hops: usize,
+    /// Token required by the admin endpoints, which are disabled without it
+    pub admin_token: Option<String>,
+    /// Other collections that queries can be routed to, by alias
+    pub collection_aliases: HashMap<String, String>,
+    /// Other collections that queries can be routed to by name, loaded for each request.
+    /// `*` allows any collection.
+    pub allowed_collections: Vec<String>,
+    /// Number of recent queries returned by `GET /queries/history`
+    pub query_history_size: usize,
+    /// Maximum size and TTL of the cache of the reload jobs
+    pub cache_options: CacheOptions,
+    // shared between clones, so that a server running in another task can be stopped
+    shutdown_sender: Arc<Mutex<Option<oneshot::Sender<()>>>>,
 }
 
+/// Vector search without a generated response, e.g. for custom UIs or evaluations
 #[derive(Deserialize, Serialize, Debug)]
+struct SearchRequest {
+    query: String,
+    limit: Option<u64>,
+    /// Only return the results with at least this score
+    #[serde(default)]
+    score_threshold: Option<f32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct SearchHit {
+    content: String,
+    score: f32,
+    source_file: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct SearchResponse {
+    results: Vec<SearchHit>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
 struct RagRequest {
     query: String,
     limit: Option<u64>,
     openai_model: Option<String>,
+    /// Return the scores and stored vectors of the retrieved chunks, for debugging
+    #[serde(default)]
+    include_vectors: Option<bool>,
+    /// Only retrieve chunks in this language (ISO 639-1 code)
+    #[serde(default)]
+    language: Option<String>,
+    /// Push the chunks matching these queries (e.g. unwanted topics) down the results
+    #[serde(default)]
+    negative_queries: Option<Vec<String>>,
+    /// Alias of the collection to query, instead of the default one
+    #[serde(default)]
+    collection: Option<String>,
+    /// Only retrieve chunks whose payload matches these fields, e.g. `{"source_file": "report.pdf"}`
+    #[serde(default)]
+    filter: Option<HashMap<String, serde_json::Value>>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 struct RagResponse {
     response: String,
     retrieved: Vec<String>,
+    /// Sources of the retrieved chunks, with their pages, e.g. "report.pdf, p. 12–13"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    citations: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    search_results: Option<Vec<SearchResult>>,
+    /// Detected language of the query, returned along with the search results
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query_language: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+/// Request in the OpenAI Chat Completions format, for LLM frontends (e.g. Open WebUI)
+#[derive(Deserialize, Serialize, Debug)]
+struct ChatCompletionRequest {
+    /// OpenAI model generating the response
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ChatCompletionChoice {
+    index: usize,
+    message: ChatMessage,
+    finish_reason: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ChatCompletionResponse {
+    id: String,
+    object: String,
+    created: u64,
+    model: String,
+    choices: Vec<ChatCompletionChoice>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct VersionResponse {
+    version: String,
+    git_commit: String,
+    rustc: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ReloadRequest {
+    /// Directory to load into the collection, on the machine running the server
+    directory: String,
+    chunk_size: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ReloadResponse {
+    job_id: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+struct Job {
+    collection_name: String,
+    #[serde(flatten)]
+    status: JobStatus,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum JobStatus {
+    Running,
+    Completed { skipped_files: Vec<SkippedFile> },
+    Failed { error: String },
 }
 
+/// A query answered by the server, as returned by `GET /queries/history`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+struct QueryRecord {
+    query: String,
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+    latency_ms: u64,
+    model: String,
+    retrieved_count: usize,
+}
+
+/// The most recent queries, kept in memory: the oldest one is dropped once it is full
 #[derive(Clone, Debug)]
-struct AppState {
+struct QueryHistory {
+    size: usize,
+    records: Arc<Mutex<VecDeque<QueryRecord>>>,
+}
+
+impl QueryHistory {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(size))),
+        }
+    }
+
+    fn push(&self, record: QueryRecord) {
+        if self.size == 0 {
+            return;
+        }
+        let mut records = self
+            .records
+            .lock()
+            .expect("Should be able to lock the query history");
+        if records.len() == self.size {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// The recorded queries, from the oldest to the newest
+    fn records(&self) -> Vec<QueryRecord> {
+        self.records
+            .lock()
+            .expect("Should be able to lock the query history")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// State of the admin endpoints: the background jobs are kept in memory, by id
+#[derive(Clone, Debug)]
+struct AdminState {
+    qdrant_url: String,
+    qdrant_tls: bool,
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    // shared with the query endpoint, which records the queries
+    query_history: QueryHistory,
+    cache_options: CacheOptions,
+}
+
+/// A collection along with the embedder for the parameters it was loaded with
+#[derive(Clone, Debug)]
+struct CollectionState {
     vectordb: VectorDB,
+    embedder: Arc<dyn Embedder>,
+}
+
+#[derive(Clone, Debug)]
+struct AppState {
+    collection: CollectionState,
+    aliases: HashMap<String, CollectionState>,
     openai_client: Client<OpenAIConfig>,
+    query_history: QueryHistory,
+    openai_max_retries: u32,
+    collection_loader: Option<CollectionLoader>,
+}
+
+/// Builds the state of the collections named by the requests, see `allowed_collections`
+#[derive(Clone)]
+struct CollectionLoader {
+    allowed: Vec<String>,
+    qdrant_url: String,
+    qdrant_tls: bool,
+    openai_api_key: String,
+    embedding_options: EmbeddingOptions,
+}
+
+// the API key is left out of the request spans
+impl std::fmt::Debug for CollectionLoader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CollectionLoader")
+            .field("allowed", &self.allowed)
+            .field("qdrant_url", &self.qdrant_url)
+            .field("qdrant_tls", &self.qdrant_tls)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CollectionLoader {
+    fn allows(&self, collection_name: &str) -> bool {
+        self.allowed
+            .iter()
+            .any(|c| c == ALL_COLLECTIONS || c == collection_name)
+    }
+
+    /// Check that the collection can be queried, and build the embedder for its queries
+    async fn load(&self, collection_name: &str) -> anyhow::Result<CollectionState> {
+        validate_collection_name(collection_name)?;
+        let vectordb = VectorDB::new(
+            self.qdrant_url.clone(),
+            collection_name.to_string(),
+            self.qdrant_tls,
+        );
+        let coll_loaded = vectordb.check_collection_ready().await?;
+        if coll_loaded == 0 {
+            return Err(anyhow::anyhow!(
+                "Collection {} does not contain any vectors",
+                collection_name
+            ));
+        }
+        // queries must be embedded with the same parameters used while loading the collection
+        let metadata = vectordb.get_metadata().await?;
+        let embedding_config = match EmbeddingConfig::from_metadata(&metadata)? {
+            Some(stored) => {
+                self.embedding_options.check_compatible(&stored)?;
+                stored
+            }
+            None => {
+                let fallback = self.embedding_options.to_config();
+                warn!(
+                    "Collection {} does not store its embedding configuration, falling back to {:?}",
+                    collection_name, fallback
+                );
+                fallback
+            }
+        };
+        Ok(CollectionState {
+            vectordb,
+            embedder: embedding_config.build_embedder(Some(&self.openai_api_key))?,
+        })
+    }
+}
+
+impl AppState {
+    /// A collection named by a request, loaded when it is one of the allowed collections
+    async fn load_allowed_collection(
+        &self,
+        collection_name: &str,
+    ) -> Option<anyhow::Result<CollectionState>> {
+        let loader = self
+            .collection_loader
+            .as_ref()
+            .filter(|l| l.allows(collection_name))?;
+        Some(loader.load(collection_name).await)
+    }
+
+    /// The served collection with this name, or with this alias
+    fn collection_named(&self, name: &str) -> Option<&CollectionState> {
+        if self.collection.vectordb.collection_name == name {
+            return Some(&self.collection);
+        }
+        self.aliases.get(name).or_else(|| {
+            self.aliases
+                .values()
+                .find(|c| c.vectordb.collection_name == name)
+        })
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -61,20 +411,81 @@ struct RagError {
 
 impl IntoResponse for RagError {
     fn into_response(self) -> axum::response::Response {
-        Json(self).into_response()
+        let status = u16::try_from(self.status_code)
+            .ok()
+            .and_then(|s| StatusCode::from_u16(s).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, Json(self)).into_response()
     }
 }
 
 impl RagResponse {
-    fn new(response: String, retrieved: Vec<String>) -> Self {
+    fn new(
+        response: String,
+        results: Vec<SearchResult>,
+        include_vectors: bool,
+        query_language: Option<String>,
+    ) -> Self {
+        let mut citations: Vec<String> = vec![];
+        for c in results.iter().filter_map(citation) {
+            if !citations.contains(&c) {
+                citations.push(c);
+            }
+        }
         Self {
             response,
-            retrieved,
+            retrieved: results.iter().map(|r| r.content.clone()).collect(),
+            citations,
+            search_results: include_vectors.then_some(results),
+            query_language: query_language.filter(|_| include_vectors),
+        }
+    }
+}
+
+/// The source of a search result, along with its pages when its document has pages
+fn citation(result: &SearchResult) -> Option<String> {
+    let source = result.source.as_ref()?;
+    Some(match (result.page_start, result.page_end) {
+        (Some(start), Some(end)) if start != end => format!("{}, p. {}–{}", source, start, end),
+        (Some(page), _) => format!("{}, p. {}", source, page),
+        _ => source.clone(),
+    })
+}
+
+impl Default for RagServer {
+    /// Defaults for every option. `qdrant_url`, `openai_api_key` and `collection_name` are
+    /// empty and must be overridden before serving.
+    fn default() -> Self {
+        Self {
+            qdrant_url: String::new(),
+            qdrant_tls: false,
+            openai_api_key: String::new(),
+            collection_name: String::new(),
+            port: DEFAULT_PORT,
+            host: IpAddr::V4(
+                Ipv4Addr::from_str(DEFAULT_HOST).expect("You should provide a valid IPv4 address"),
+            ),
+            rate_limit_per_minute: DEFAULT_RATE_LIMIT,
+            max_request_body_bytes: DEFAULT_MAX_REQUEST_BODY_BYTES,
+            openai_max_retries: DEFAULT_OPENAI_MAX_RETRIES,
+            cors: None,
+            log_level: Level::INFO,
+            log_json: false,
+            embedding_options: EmbeddingOptions::default(),
+            trust_proxy: false,
+            max_forwarded_hops: DEFAULT_MAX_FORWARDED_HOPS,
+            admin_token: None,
+            collection_aliases: HashMap::new(),
+            allowed_collections: vec![],
+            query_history_size: DEFAULT_QUERY_HISTORY_SIZE,
+            cache_options: CacheOptions::default(),
+            shutdown_sender: Arc::new(Mutex::new(None)),
         }
     }
 }
 
 impl RagServer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         qdrant_url: String,
         openai_api_key: Option<String>,
@@ -115,30 +526,57 @@ impl RagServer {
         };
         Self {
             qdrant_url,
+            qdrant_tls: false,
             collection_name,
             host: server_host,
             port: server_port,
             cors,
             rate_limit_per_minute: server_rate_limit,
+            max_request_body_bytes: DEFAULT_MAX_REQUEST_BODY_BYTES,
+            openai_max_retries: DEFAULT_OPENAI_MAX_RETRIES,
             openai_api_key: api_key,
             log_level: app_log_level,
             log_json,
+            embedding_options: EmbeddingOptions::default(),
+            trust_proxy: false,
+            max_forwarded_hops: DEFAULT_MAX_FORWARDED_HOPS,
+            admin_token: None,
+            collection_aliases: HashMap::new(),
+            allowed_collections: vec![],
+            query_history_size: DEFAULT_QUERY_HISTORY_SIZE,
+            cache_options: CacheOptions::default(),
+            shutdown_sender: Arc::new(Mutex::new(None)),
         }
     }
 
     pub async fn serve(&self) -> anyhow::Result<()> {
-        let vectordb = VectorDB::new(self.qdrant_url.clone(), self.collection_name.clone());
-        let coll_loaded = vectordb.check_collection_ready().await?;
-        if coll_loaded == 0 {
-            return Err(anyhow::anyhow!(
-                "Vector database does not contain any vectors"
-            ));
+        let level_filter = LevelFilter::from_level(self.log_level);
+        let subscriber = tracing_subscriber::registry()
+            .with(level_filter)
+            .with((!self.log_json).then(|| fmt::layer().compact()))
+            .with((self.log_json).then(|| fmt::layer().json()));
+        // a subscriber may already be installed, e.g. when serving from tests
+        let _ = subscriber.try_init();
+        let loader = self.collection_loader();
+        let mut aliases: HashMap<String, CollectionState> = HashMap::new();
+        for (alias, collection_name) in &self.collection_aliases {
+            aliases.insert(alias.clone(), loader.load(collection_name).await?);
         }
+        let query_history = QueryHistory::new(self.query_history_size);
         let state = AppState {
-            vectordb,
+            collection: loader.load(&self.collection_name).await?,
+            aliases,
+            // the requests are retried by `rag`, instead of the client backing off for minutes
             openai_client: Client::with_config(
                 OpenAIConfig::new().with_api_key(&self.openai_api_key),
-            ),
+            )
+            .with_backoff(ExponentialBackoff {
+                max_elapsed_time: Some(Duration::ZERO),
+                ..Default::default()
+            }),
+            query_history: query_history.clone(),
+            openai_max_retries: self.openai_max_retries,
+            collection_loader: (!self.allowed_collections.is_empty()).then_some(loader),
         };
         let cors_layer = if self.cors.is_some()
             && let Some(cors) = &self.cors
@@ -148,16 +586,19 @@ impl RagServer {
                     cors.parse::<HeaderValue>()
                         .expect("Should be able to parse URL into a header value."),
                 )
-                .allow_methods(vec![Method::POST])
-                .allow_headers(vec![CONTENT_TYPE])
+                .allow_methods(vec![Method::GET, Method::POST])
+                .allow_headers(vec![CONTENT_TYPE, AUTHORIZATION])
         } else {
             CorsLayer::new()
                 .allow_origin(Any)
-                .allow_methods(Method::POST)
-                .allow_headers(vec![CONTENT_TYPE])
+                .allow_methods(vec![Method::GET, Method::POST])
+                .allow_headers(vec![CONTENT_TYPE, AUTHORIZATION])
         };
         let governor_conf = Box::new(
             GovernorConfigBuilder::default()
+                .key_extractor(ClientIpKeyExtractor {
+                    trust_proxy: self.trust_proxy,
+                })
                 .per_second(60)
                 .burst_size(self.rate_limit_per_minute)
                 .finish()
@@ -176,29 +617,328 @@ impl RagServer {
             }
         });
         let governor_layer = GovernorLayer::new(governor_conf);
+        let admin_state = AdminState {
+            qdrant_url: self.qdrant_url.clone(),
+            qdrant_tls: self.qdrant_tls,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            query_history,
+            cache_options: self.cache_options,
+        };
         let app = Router::new()
             .route("/queries", post(rag))
+            .route("/collections/{name}/search", post(search))
+            .route("/v1/chat/completions", post(chat_completions))
+            .merge(admin_router(self.admin_token.clone(), admin_state))
+            .layer(DefaultBodyLimit::max(self.max_request_body_bytes))
             .layer(governor_layer)
+            // added after the rate limiting layer, so that monitoring is never rate-limited
+            .route(
+                "/version",
+                get(version).layer(middleware::from_fn(cache_validation)),
+            )
+            // reject spoofed forwarding chains before they are used as rate limiting keys
+            .layer(middleware::from_fn_with_state(
+                ProxyPolicy {
+                    trust_proxy: self.trust_proxy,
+                    max_forwarded_hops: self.max_forwarded_hops,
+                },
+                check_forwarded_hops,
+            ))
             .layer(cors_layer)
+            // gzip and deflate request bodies are decompressed before the body limit is checked,
+            // and responses are compressed for the clients accepting it
+            .layer(RequestDecompressionLayer::new())
+            .layer(CompressionLayer::new())
+            // outermost layer, so that rate-limited and CORS-rejected requests are logged too
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(make_access_span)
+                    .on_response(log_access),
+            )
             .with_state(state);
         let addr = SocketAddr::from((self.host, self.port));
         tracing::info!("listening on {}", addr);
         let listener = tokio::net::TcpListener::bind(addr).await?;
-        let level_filter = LevelFilter::from_level(self.log_level);
-        let subscriber = tracing_subscriber::registry()
-            .with(level_filter)
-            .with((!self.log_json).then(|| fmt::layer().compact()))
-            .with((self.log_json).then(|| fmt::layer().json()));
-        subscriber.init();
         info!("Server listening on {}", addr.to_string());
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        *self
+            .shutdown_sender
+            .lock()
+            .expect("Should be able to lock the shutdown sender") = Some(shutdown_sender);
         axum::serve(
             listener,
             app.into_make_service_with_connect_info::<SocketAddr>(),
         )
+        .with_graceful_shutdown(async {
+            shutdown_receiver.await.ok();
+        })
         .await?;
 
         Ok(())
     }
+
+    /// Loads the collections named by the requests, besides the served one and its aliases
+    fn collection_loader(&self) -> CollectionLoader {
+        CollectionLoader {
+            allowed: self.allowed_collections.clone(),
+            qdrant_url: self.qdrant_url.clone(),
+            qdrant_tls: self.qdrant_tls,
+            openai_api_key: self.openai_api_key.clone(),
+            embedding_options: self.embedding_options.clone(),
+        }
+    }
+
+    /// Gracefully stop a running server (or a clone of it), e.g. at the end of a test
+    pub fn shutdown(self) -> anyhow::Result<()> {
+        let sender = self
+            .shutdown_sender
+            .lock()
+            .expect("Should be able to lock the shutdown sender")
+            .take();
+        match sender {
+            Some(s) => s
+                .send(())
+                .map_err(|_| anyhow::anyhow!("The server already stopped")),
+            None => Err(anyhow::anyhow!("The server is not running")),
+        }
+    }
+}
+
+/// Parse a collection alias given as `alias=collection_name`
+pub fn parse_collection_alias(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((alias, collection_name)) if !alias.is_empty() && !collection_name.is_empty() => {
+            Ok((alias.to_string(), collection_name.to_string()))
+        }
+        _ => Err(format!(
+            "'{}' should have the form alias=collection_name",
+            value
+        )),
+    }
+}
+
+/// Rate limiting key: the client IP reported by the reverse proxy when it is trusted,
+/// the socket address otherwise
+#[derive(Debug, Clone, Copy)]
+struct ClientIpKeyExtractor {
+    trust_proxy: bool,
+}
+
+impl KeyExtractor for ClientIpKeyExtractor {
+    type Key = IpAddr;
+
+    fn extract<T>(&self, req: &http::Request<T>) -> Result<Self::Key, GovernorError> {
+        if self.trust_proxy
+            && let Some(ip) = forwarded_client_ip(req.headers())
+        {
+            return Ok(ip);
+        }
+        PeerIpKeyExtractor.extract(req)
+    }
+}
+
+/// First address in `X-Forwarded-For`, falling back to `X-Real-IP`
+fn forwarded_client_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    let forwarded_for = headers
+        .get(X_FORWARDED_FOR)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.split(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+    forwarded_for.or_else(|| {
+        headers
+            .get(X_REAL_IP)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+    })
+}
+
+fn forwarded_hops(headers: &HeaderMap) -> usize {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter(|ip| !ip.trim().is_empty())
+        .count()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ProxyPolicy {
+    trust_proxy: bool,
+    max_forwarded_hops: usize,
+}
+
+async fn check_forwarded_hops(
+    State(policy): State<ProxyPolicy>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let hops = forwarded_hops(request.headers());
+    if policy.trust_proxy && hops > policy.max_forwarded_hops {
+        warn!(
+            event = "ForwardedHopsRejected",
+            hops, "Rejecting request forwarded through too many proxies"
+        );
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(RagError {
+                status_code: 400,
+                detail: format!(
+                    "X-Forwarded-For contains {} addresses, but at most {} are accepted",
+                    hops, policy.max_forwarded_hops
+                ),
+            }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Endpoints managing the collections, which require the admin token
+fn admin_router<S: Clone + Send + Sync + 'static>(
+    admin_token: Option<String>,
+    state: AdminState,
+) -> Router<S> {
+    Router::new()
+        .route("/collections/{name}/reload", post(reload))
+        .route("/jobs/{id}", get(job_status))
+        .route("/queries/history", get(query_history))
+        .route_layer(middleware::from_fn_with_state(
+            admin_token,
+            require_admin_token,
+        ))
+        .with_state(state)
+}
+
+/// Add `Cache-Control` and an `ETag` derived from the hash of the body to the successful
+/// responses, and answer with a 304 status code when the client already has the same body.
+/// The `ETag` is weak, since the compression layer may change the encoding of the body.
+async fn cache_validation(request: Request, next: Next) -> Response {
+    let if_none_match = request.headers().get(IF_NONE_MATCH).cloned();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!(
+                event = "ResponseBodyError",
+                "Could not read the response body: {}", e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let etag = HeaderValue::from_str(&format!("W/\"{}\"", &content_hash(&body)[..16]))
+        .expect("Should be a valid header value");
+    parts.headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static(GET_RESPONSE_MAX_AGE),
+    );
+    parts.headers.insert(ETAG, etag.clone());
+    let not_modified = if_none_match
+        .as_ref()
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| {
+            h.split(',')
+                .any(|t| t.trim() == "*" || t.trim() == etag.to_str().unwrap_or_default())
+        });
+    if not_modified {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(CONTENT_TYPE);
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+async fn require_admin_token(
+    State(admin_token): State<Option<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admin_token) = admin_token else {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(RagError {
+                status_code: 403,
+                detail: "Admin endpoints are disabled, start the server with an admin token to enable them".to_string(),
+            }),
+        )
+            .into_response();
+    };
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    if token != Some(admin_token.as_str()) {
+        warn!(
+            event = "AdminTokenRejected",
+            uri = %request.uri(),
+            "Rejecting admin request without a valid token"
+        );
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(RagError {
+                status_code: 401,
+                detail: "A valid admin token should be sent as `Authorization: Bearer <token>`"
+                    .to_string(),
+            }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+fn make_access_span<B>(request: &http::Request<B>) -> Span {
+    info_span!(
+        "http_request",
+        method = %request.method(),
+        uri = %request.uri(),
+    )
+}
+
+// emitted inside the request span, which carries the `method` and `uri` fields
+fn log_access<B>(response: &http::Response<B>, latency: std::time::Duration, _span: &Span) {
+    info!(
+        event = "HttpResponse",
+        status = response.status().as_u16(),
+        latency_ms = latency.as_millis() as u64,
+        "Request completed"
+    );
+}
+
+/// Rate limits and server errors are transient, unlike e.g. an exhausted quota
+fn openai_retry_policy(err: &OpenAIError) -> RetryPolicy {
+    match err {
+        OpenAIError::ApiError(api_error) => {
+            if api_error.code.as_deref() == Some("rate_limit_exceeded") {
+                RetryPolicy::After(OPENAI_RATE_LIMIT_DELAY)
+            } else if api_error.r#type.is_none()
+                && api_error.code.is_none()
+                && api_error.param.is_none()
+            {
+                // server errors are the only ones whose body is not an OpenAI error object
+                RetryPolicy::Backoff
+            } else {
+                RetryPolicy::Never
+            }
+        }
+        OpenAIError::Reqwest(e) if e.status().is_some_and(|s| s.is_server_error()) => {
+            RetryPolicy::Backoff
+        }
+        _ => RetryPolicy::Never,
+    }
+}
+
+async fn version() -> Json<VersionResponse> {
+    let info = BuildInfo::current();
+    Json(VersionResponse {
+        version: info.version,
+        git_commit: info.git_commit,
+        rustc: info.rustc,
+    })
 }
 
 #[instrument]
@@ -206,8 +946,80 @@ async fn rag(
     State(state): State<AppState>,
     Json(payload): Json<RagRequest>,
 ) -> Result<Json<RagResponse>, RagError> {
-    let query_text = payload.query.clone();
-    let embedding = embed_text(query_text);
+    let language = match payload
+        .language
+        .as_deref()
+        .map(normalize_language_code)
+        .transpose()
+    {
+        Ok(l) => l,
+        Err(e) => {
+            return Err(RagError {
+                status_code: 400,
+                detail: format!("Invalid language filter: {}", e),
+            });
+        }
+    };
+    if let Some(filter) = &payload.filter
+        && let Err(e) = payload_conditions(filter)
+    {
+        return Err(RagError {
+            status_code: 400,
+            detail: format!("Invalid filter: {}", e),
+        });
+    }
+    let loaded: CollectionState;
+    let collection = match payload.collection.as_deref() {
+        None => &state.collection,
+        Some(alias) => match state.aliases.get(alias) {
+            Some(c) => c,
+            None if let Some(result) = state.load_allowed_collection(alias).await => match result {
+                Ok(c) => {
+                    loaded = c;
+                    &loaded
+                }
+                Err(e) => {
+                    return Err(RagError {
+                        status_code: 404,
+                        detail: format!("Could not load collection '{}': {}", alias, e),
+                    });
+                }
+            },
+            None => {
+                let mut available: Vec<&str> = state.aliases.keys().map(|a| a.as_str()).collect();
+                available.sort();
+                return Err(RagError {
+                    status_code: 400,
+                    detail: format!(
+                        "Unknown collection alias '{}'. Available aliases: {}",
+                        alias,
+                        available.join(", ")
+                    ),
+                });
+            }
+        },
+    };
+    let embedding = match collection.embedder.embed_query(&payload.query).await {
+        Ok(e) => e,
+        Err(e) => {
+            return Err(RagError {
+                status_code: 500,
+                detail: format!("Could not embed the query because of {}", e),
+            });
+        }
+    };
+    let mut negative_embeddings: Vec<EmbeddingVector> = vec![];
+    for negative_query in payload.negative_queries.iter().flatten() {
+        match collection.embedder.embed_query(negative_query).await {
+            Ok(e) => negative_embeddings.push(e),
+            Err(e) => {
+                return Err(RagError {
+                    status_code: 500,
+                    detail: format!("Could not embed the negative query because of {}", e),
+                });
+            }
+        }
+    }
     let search_limit = match payload.limit {
         Some(l) => l,
         None => DEFAULT_SEARCH_LIMIT,
@@ -218,7 +1030,22 @@ async fn rag(
     };
     info!(event="RagSearchStart", data_id = %payload.query, "Starting vector search operation");
     let now = tokio::time::Instant::now();
-    let results = match state.vectordb.search(embedding, search_limit).await {
+    let include_vectors = payload.include_vectors.unwrap_or(false);
+    let results = match collection
+        .vectordb
+        .clone()
+        .search(
+            embedding,
+            SearchOptions {
+                limit: search_limit,
+                include_vectors,
+                language,
+                pre_filter: payload.filter.clone(),
+                negative_embeddings,
+            },
+        )
+        .await
+    {
         Ok(v) => v,
         Err(e) => {
             return Err(RagError {
@@ -230,9 +1057,17 @@ async fn rag(
     let elapsed = now.elapsed().as_millis();
     debug!(event="SearchResultsReport", data_id = %payload.query, "Total retrieved results: {}/{}", results.len(), search_limit);
     info!(event="RagSearchEnd", data_id = %payload.query, "Ended vector search operation in {} ms", elapsed);
-    let context = &results.join("\n\n---\n\n");
+    let retrieved_chunks: Vec<Chunk> = results
+        .iter()
+        .map(|r| Chunk {
+            source: r.source.clone(),
+            language: r.language.clone(),
+            ..Chunk::from_content(r.content.clone())
+        })
+        .collect();
+    let context = Chunk::merge(&retrieved_chunks, "\n\n---\n\n").content;
     let request = CreateResponseArgs::default()
-        .model(openai_model)
+        .model(openai_model.clone())
         .input(format!("Based on this context:\n\n```text\n{}\n```\n\n, reply to this query:\n\n```text\n{}\n```", context, payload.query))
         .build();
     info!(event="OpenAIResponseStart", data_id = %payload.query, "Starting OpenAI response generation");
@@ -246,7 +1081,14 @@ async fn rag(
             });
         }
     };
-    let openai_response = state.openai_client.responses().create(openai_request).await;
+    let openai_response = with_retry(state.openai_max_retries, openai_retry_policy, || async {
+        state
+            .openai_client
+            .responses()
+            .create(openai_request.clone())
+            .await
+    })
+    .await;
     let response_text = match openai_response {
         Ok(r) => match r.output_text() {
             Some(s) => s,
@@ -267,29 +1109,978 @@ async fn rag(
     let elapsed_resp = now_resp.elapsed().as_millis();
     info!(event="OpenAIResponseEnd", data_id = %payload.query, "Finished OpenAI response generation in {} ms", elapsed_resp);
     debug!(event="OverallLatencyReport", data_id = %payload.query, "Total latency: {} ms", elapsed + elapsed_resp);
+    state.query_history.push(QueryRecord {
+        query: payload.query.clone(),
+        timestamp: unix_timestamp(),
+        latency_ms: (elapsed + elapsed_resp) as u64,
+        model: openai_model,
+        retrieved_count: results.len(),
+    });
 
-    Ok(Json(RagResponse::new(response_text, results)))
+    Ok(Json(RagResponse::new(
+        response_text,
+        results,
+        include_vectors,
+        detect_language(&payload.query),
+    )))
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// Search a served collection, by name or alias, without generating a response
+#[instrument]
+async fn search(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(payload): Json<SearchRequest>,
+) -> Result<Json<SearchResponse>, RagError> {
+    let loaded: CollectionState;
+    let collection = match state.collection_named(&name) {
+        Some(c) => c,
+        None => match state.load_allowed_collection(&name).await {
+            Some(Ok(c)) => {
+                loaded = c;
+                &loaded
+            }
+            Some(Err(e)) => {
+                return Err(RagError {
+                    status_code: 404,
+                    detail: format!("Could not load collection '{}': {}", name, e),
+                });
+            }
+            None => {
+                return Err(RagError {
+                    status_code: 404,
+                    detail: format!("Unknown collection '{}'", name),
+                });
+            }
+        },
+    };
+    let embedding = match collection.embedder.embed_query(&payload.query).await {
+        Ok(e) => e,
+        Err(e) => {
+            return Err(RagError {
+                status_code: 500,
+                detail: format!("Could not embed the query because of {}", e),
+            });
+        }
+    };
+    let results = match collection
+        .vectordb
+        .clone()
+        .search(
+            embedding,
+            SearchOptions {
+                limit: payload.limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+                ..SearchOptions::default()
+            },
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(RagError {
+                status_code: 500,
+                detail: format!("Could not retrieve results because of {}", e),
+            });
+        }
+    };
+    let threshold = payload.score_threshold.unwrap_or(f32::MIN);
+    Ok(Json(SearchResponse {
+        results: results
+            .into_iter()
+            .filter(|r| r.score >= threshold)
+            .map(|r| SearchHit {
+                content: r.content,
+                score: r.score,
+                source_file: r.source,
+            })
+            .collect(),
+    }))
+}
 
-    use crate::pipeline::Pipeline;
-    use axum::{
-        body::Body,
-        http::{Request, StatusCode},
+/// Answer the last user message of an OpenAI-format conversation as a query
+async fn chat_completions(
+    State(state): State<AppState>,
+    Json(payload): Json<ChatCompletionRequest>,
+) -> Result<Json<ChatCompletionResponse>, RagError> {
+    let Some(query) = last_user_message(&payload.messages) else {
+        return Err(RagError {
+            status_code: 400,
+            detail: "The messages should contain at least one user message".to_string(),
+        });
     };
-    use tower::Service;
+    let model = payload
+        .model
+        .unwrap_or_else(|| DEFAULT_OPENAI_MODEL.to_string());
+    let Json(response) = rag(
+        State(state),
+        Json(RagRequest {
+            query: query.to_string(),
+            openai_model: Some(model.clone()),
+            ..RagRequest::default()
+        }),
+    )
+    .await?;
+    Ok(Json(ChatCompletionResponse {
+        id: format!("chatcmpl-{}", uuid::Uuid::new_v4()),
+        object: "chat.completion".to_string(),
+        created: unix_timestamp(),
+        model,
+        choices: vec![ChatCompletionChoice {
+            index: 0,
+            message: ChatMessage {
+                role: "assistant".to_string(),
+                content: response.response,
+            },
+            finish_reason: "stop".to_string(),
+        }],
+    }))
+}
 
-    #[tokio::test]
-    async fn test_api_endpoint() {
-        let qdrant_url_var = std::env::var("QDRANT_URL");
-        let qdrant_url = match qdrant_url_var {
-            Ok(s) => s.to_string(),
-            Err(_) => {
-                println!("Skipping test because Qdrant is not available");
-                return;
+fn last_user_message(messages: &[ChatMessage]) -> Option<&str> {
+    messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map(|m| m.content.as_str())
+}
+
+/// Seconds since the Unix epoch
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Run the load pipeline for the directory in a background task, whose status is polled with
+/// `GET /jobs/{id}`
+#[instrument(skip(state))]
+async fn reload(
+    State(state): State<AdminState>,
+    Path(collection_name): Path<String>,
+    Json(payload): Json<ReloadRequest>,
+) -> Result<Json<ReloadResponse>, RagError> {
+    if let Err(e) = validate_collection_name(&collection_name) {
+        return Err(RagError {
+            status_code: 400,
+            detail: e.to_string(),
+        });
+    }
+    if !std::path::Path::new(&payload.directory).is_dir() {
+        return Err(RagError {
+            status_code: 400,
+            detail: format!("Directory {} does not exist", payload.directory),
+        });
+    }
+    let job_id = uuid::Uuid::new_v4().to_string();
+    {
+        let mut jobs = state.jobs.lock().expect("Should be able to lock the jobs");
+        // concurrent runs would upload the same documents twice
+        if jobs
+            .values()
+            .any(|j| j.collection_name == collection_name && j.status == JobStatus::Running)
+        {
+            return Err(RagError {
+                status_code: 409,
+                detail: format!("Collection {} is already being reloaded", collection_name),
+            });
+        }
+        jobs.insert(
+            job_id.clone(),
+            Job {
+                collection_name: collection_name.clone(),
+                status: JobStatus::Running,
+            },
+        );
+    }
+    let mut pipeline = Pipeline::new(
+        Some(payload.directory),
+        payload.chunk_size.unwrap_or(DEFAULT_RELOAD_CHUNK_SIZE),
+        state.qdrant_url,
+        collection_name,
+        true,
+        None,
+        None,
+    );
+    pipeline.qdrant_tls = state.qdrant_tls;
+    pipeline.cache_options = state.cache_options;
+    // reloading replaces the chunks of the documents in the collection served
+    pipeline.append = true;
+    pipeline.replace = true;
+    let jobs = state.jobs;
+    let id = job_id.clone();
+    // spawned tasks do not inherit the current span: attach it, so that the events of the job
+    // are nested in the span of the request that started it
+    let span = Span::current();
+    tokio::spawn(
+        async move {
+            info!(event = "ReloadJobStart", job_id = %id, "Starting reload job");
+            let status = match pipeline.run().await {
+                Ok(result) => {
+                    info!(event = "ReloadJobEnd", job_id = %id, "Reload job completed");
+                    JobStatus::Completed {
+                        skipped_files: result.skipped_files,
+                    }
+                }
+                Err(e) => {
+                    // with the causes, e.g. the file that could not be parsed
+                    let error = format!("{:#}", e);
+                    warn!(event = "ReloadJobFailed", job_id = %id, "Reload job failed: {}", error);
+                    JobStatus::Failed { error }
+                }
+            };
+            if let Some(job) = jobs
+                .lock()
+                .expect("Should be able to lock the jobs")
+                .get_mut(&id)
+            {
+                job.status = status;
+            }
+        }
+        .instrument(span),
+    );
+    Ok(Json(ReloadResponse { job_id }))
+}
+
+async fn job_status(
+    State(state): State<AdminState>,
+    Path(job_id): Path<String>,
+) -> Result<Json<Job>, RagError> {
+    let jobs = state.jobs.lock().expect("Should be able to lock the jobs");
+    match jobs.get(&job_id) {
+        Some(job) => Ok(Json(job.clone())),
+        None => Err(RagError {
+            status_code: 404,
+            detail: format!("Job {} not found", job_id),
+        }),
+    }
+}
+
+async fn query_history(State(state): State<AdminState>) -> Json<Vec<QueryRecord>> {
+    Json(state.query_history.records())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use tower::Service;
+
+    /// State serving an empty collection, without a Qdrant instance or an OpenAI key
+    fn test_state() -> AppState {
+        AppState {
+            collection: CollectionState {
+                vectordb: VectorDB::new(String::new(), String::new(), false),
+                embedder: EmbeddingConfig::default().build_embedder(None).unwrap(),
+            },
+            aliases: HashMap::new(),
+            openai_client: Client::with_config(OpenAIConfig::new()),
+            query_history: QueryHistory::new(DEFAULT_QUERY_HISTORY_SIZE),
+            openai_max_retries: 0,
+            collection_loader: None,
+        }
+    }
+
+    #[test]
+    fn test_default_server() {
+        let server = RagServer::default();
+        assert_eq!(server.qdrant_url, "");
+        assert_eq!(server.openai_api_key, "");
+        assert_eq!(server.collection_name, "");
+        assert_eq!(server.port, DEFAULT_PORT);
+        assert_eq!(server.host, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+        assert_eq!(server.rate_limit_per_minute, DEFAULT_RATE_LIMIT);
+        assert!(server.cors.is_none());
+        assert_eq!(server.log_level, Level::INFO);
+        assert!(!server.log_json);
+        let server_1 = RagServer {
+            port: 9000,
+            ..Default::default()
+        };
+        assert_eq!(server_1.port, 9000);
+        assert_eq!(server_1.rate_limit_per_minute, DEFAULT_RATE_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn test_access_log_layer() {
+        let mut app = Router::new()
+            .route("/queries", post(|| async { "ok" }))
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(make_access_span)
+                    .on_response(log_access),
+            );
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/queries")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let not_found = app
+            .call(
+                Request::builder()
+                    .uri("/missing")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(not_found.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_shutdown_not_running() {
+        let server = RagServer::default();
+        assert!(server.shutdown().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_and_shutdown() {
+        let qdrant_url = match std::env::var("QDRANT_URL") {
+            Ok(s) => s,
+            Err(_) => {
+                println!("Skipping test because Qdrant is not available");
+                return;
+            }
+        };
+        let pipeline = Pipeline::new(
+            Some("testfiles/".to_string()),
+            1024_usize,
+            qdrant_url.clone(),
+            "test-shutdown-collection".to_string(),
+            true,
+            None,
+            None,
+        );
+        assert!(pipeline.run().await.is_ok());
+        let server = RagServer {
+            qdrant_url,
+            openai_api_key: "test-key".to_string(),
+            collection_name: "test-shutdown-collection".to_string(),
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8123,
+            ..Default::default()
+        };
+        let running = server.clone();
+        let handle = tokio::spawn(async move { running.serve().await });
+        let mut connected = false;
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect("127.0.0.1:8123")
+                .await
+                .is_ok()
+            {
+                connected = true;
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        assert!(connected);
+        server.shutdown().unwrap();
+        assert!(handle.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_forwarded_client_ip() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(forwarded_client_ip(&headers), None);
+        headers.insert(X_REAL_IP, HeaderValue::from_static("10.0.0.2"));
+        assert_eq!(
+            forwarded_client_ip(&headers),
+            Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))
+        );
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("203.0.113.7, 10.0.0.1"),
+        );
+        assert_eq!(
+            forwarded_client_ip(&headers),
+            Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)))
+        );
+        assert_eq!(forwarded_hops(&headers), 2);
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.3"));
+        assert_eq!(forwarded_hops(&headers), 3);
+    }
+
+    #[test]
+    fn test_client_ip_key_extractor() {
+        let request = Request::builder()
+            .header(X_FORWARDED_FOR, "203.0.113.7")
+            .extension(axum::extract::ConnectInfo(SocketAddr::from((
+                [127, 0, 0, 1],
+                8000,
+            ))))
+            .body(())
+            .unwrap();
+        let trusted = ClientIpKeyExtractor { trust_proxy: true };
+        assert_eq!(
+            trusted.extract(&request).unwrap(),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))
+        );
+        let untrusted = ClientIpKeyExtractor { trust_proxy: false };
+        assert_eq!(
+            untrusted.extract(&request).unwrap(),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_forwarded_hops() {
+        let mut app = Router::new()
+            .route("/queries", post(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                ProxyPolicy {
+                    trust_proxy: true,
+                    max_forwarded_hops: DEFAULT_MAX_FORWARDED_HOPS,
+                },
+                check_forwarded_hops,
+            ));
+        let accepted = app
+            .call(
+                Request::builder()
+                    .uri("/queries")
+                    .method("POST")
+                    .header(X_FORWARDED_FOR, "203.0.113.7, 10.0.0.1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(accepted.status(), StatusCode::OK);
+        let rejected = app
+            .call(
+                Request::builder()
+                    .uri("/queries")
+                    .method("POST")
+                    .header(X_FORWARDED_FOR, "1.1.1.1, 203.0.113.7, 10.0.0.1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_version_endpoint() {
+        let mut app: Router = Router::new().route("/version", get(version));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/version")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let version: VersionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(version.git_commit, BuildInfo::current().git_commit);
+    }
+
+    #[tokio::test]
+    async fn test_cache_validation() {
+        let mut app: Router = Router::new()
+            .route(
+                "/version",
+                get(version).layer(middleware::from_fn(cache_validation)),
+            )
+            .route(
+                "/missing",
+                get(|| async { StatusCode::NOT_FOUND })
+                    .layer(middleware::from_fn(cache_validation)),
+            );
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/version")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=5");
+        let etag = response.headers()[ETAG].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(serde_json::from_slice::<VersionResponse>(&body).is_ok());
+        let revalidate = |if_none_match: &str| {
+            Request::builder()
+                .uri("/version")
+                .header(IF_NONE_MATCH, if_none_match)
+                .body(Body::empty())
+                .unwrap()
+        };
+        // the body did not change
+        let response = app.call(revalidate(etag.to_str().unwrap())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+        let response = app
+            .call(revalidate(&format!(
+                "W/\"0123\", {}",
+                etag.to_str().unwrap()
+            )))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = app.call(revalidate("W/\"0123\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // errors are not cached
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/missing")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get(ETAG).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_filters() {
+        let state = test_state();
+        let result = rag(
+            State(state.clone()),
+            Json(RagRequest {
+                query: "Is this a test?".to_string(),
+                language: Some("klingon".to_string()),
+                ..RagRequest::default()
+            }),
+        )
+        .await;
+        let err = result.err().unwrap();
+        assert_eq!(err.status_code, 400);
+        assert!(err.detail.contains("language"));
+        let result = rag(
+            State(state),
+            Json(RagRequest {
+                query: "Is this a test?".to_string(),
+                filter: Some(HashMap::from([(
+                    "source_file".to_string(),
+                    serde_json::json!({"any": "report.pdf"}),
+                )])),
+                ..RagRequest::default()
+            }),
+        )
+        .await;
+        let err = result.err().unwrap();
+        assert_eq!(err.status_code, 400);
+        assert!(err.detail.starts_with("Invalid filter: Unsupported value"));
+    }
+
+    #[tokio::test]
+    async fn test_chat_completions_without_user_message() {
+        let state = test_state();
+        let request: ChatCompletionRequest = serde_json::from_str(
+            r#"{"model": "gpt-4.1-mini", "messages": [{"role": "system", "content": "Be brief"}]}"#,
+        )
+        .unwrap();
+        let err = chat_completions(State(state), Json(request))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.status_code, 400);
+    }
+
+    #[test]
+    fn test_last_user_message() {
+        let message = |role: &str, content: &str| ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        };
+        let messages = vec![
+            message("system", "Be brief"),
+            message("user", "What is rag-rs?"),
+            message("assistant", "A RAG application"),
+            message("user", "Is this a test?"),
+        ];
+        assert_eq!(last_user_message(&messages), Some("Is this a test?"));
+        assert_eq!(last_user_message(&messages[..1]), None);
+    }
+
+    #[tokio::test]
+    async fn test_request_body_limit() {
+        let state = test_state();
+        let mut app = Router::new()
+            .route("/queries", post(rag))
+            .layer(DefaultBodyLimit::max(DEFAULT_MAX_REQUEST_BODY_BYTES))
+            .with_state(state);
+        let request_body = serde_json::to_string(&RagRequest {
+            query: "a".repeat(1024 * 1024),
+            ..RagRequest::default()
+        })
+        .unwrap();
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/queries")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(request_body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+        use std::io::{Read, Write};
+
+        let gzip = |bytes: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        };
+        let mut app = Router::new()
+            .route(
+                "/echo",
+                post(|Json(body): Json<serde_json::Value>| async move { Json(body) }),
+            )
+            .layer(DefaultBodyLimit::max(DEFAULT_MAX_REQUEST_BODY_BYTES))
+            .layer(RequestDecompressionLayer::new())
+            .layer(CompressionLayer::new());
+        let request_body = serde_json::json!({"query": "a gzip-compressed query ".repeat(10)});
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/echo")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("content-encoding", "gzip")
+                    .header("accept-encoding", "gzip")
+                    .body(Body::from(gzip(request_body.to_string().as_bytes())))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&decoded).unwrap(),
+            request_body
+        );
+        // the body limit applies to the decompressed body
+        let request_body = serde_json::json!({"query": "a".repeat(1024 * 1024)});
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/echo")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("content-encoding", "gzip")
+                    .body(Body::from(gzip(request_body.to_string().as_bytes())))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_openai_retry_policy() {
+        use async_openai::error::ApiError;
+        let api_error = |r#type: Option<&str>, code: Option<&str>| {
+            OpenAIError::ApiError(ApiError {
+                message: "error".to_string(),
+                r#type: r#type.map(String::from),
+                param: None,
+                code: code.map(String::from),
+            })
+        };
+        assert_eq!(
+            openai_retry_policy(&api_error(Some("requests"), Some("rate_limit_exceeded"))),
+            RetryPolicy::After(OPENAI_RATE_LIMIT_DELAY)
+        );
+        assert_eq!(
+            openai_retry_policy(&api_error(None, None)),
+            RetryPolicy::Backoff
+        );
+        assert_eq!(
+            openai_retry_policy(&api_error(
+                Some("insufficient_quota"),
+                Some("insufficient_quota")
+            )),
+            RetryPolicy::Never
+        );
+        assert_eq!(
+            openai_retry_policy(&api_error(
+                Some("invalid_request_error"),
+                Some("invalid_api_key")
+            )),
+            RetryPolicy::Never
+        );
+        assert_eq!(
+            openai_retry_policy(&OpenAIError::InvalidArgument("model".to_string())),
+            RetryPolicy::Never
+        );
+    }
+
+    #[test]
+    fn test_parse_collection_alias() {
+        assert_eq!(
+            parse_collection_alias("legal=legal-docs"),
+            Ok(("legal".to_string(), "legal-docs".to_string()))
+        );
+        assert!(parse_collection_alias("legal").is_err());
+        assert!(parse_collection_alias("=legal-docs").is_err());
+        assert!(parse_collection_alias("legal=").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search_unknown_collection() {
+        let collection = |name: &str| CollectionState {
+            vectordb: VectorDB::new(String::new(), name.to_string(), false),
+            embedder: EmbeddingConfig::default().build_embedder(None).unwrap(),
+        };
+        let state = AppState {
+            collection: collection("docs"),
+            aliases: HashMap::from([("legal".to_string(), collection("legal-docs"))]),
+            ..test_state()
+        };
+        for name in ["docs", "legal", "legal-docs"] {
+            assert!(state.collection_named(name).is_some());
+        }
+        let result = search(
+            State(state),
+            Path("finance".to_string()),
+            Json(SearchRequest {
+                query: "Is this a test?".to_string(),
+                limit: None,
+                score_threshold: None,
+            }),
+        )
+        .await;
+        let err = result.err().unwrap();
+        assert_eq!(err.status_code, 404);
+        assert_eq!(err.detail, "Unknown collection 'finance'");
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let qdrant_url = match std::env::var("QDRANT_URL") {
+            Ok(s) => s,
+            Err(_) => {
+                println!("Skipping test because Qdrant is not available");
+                return;
+            }
+        };
+        let pipeline = Pipeline::new(
+            Some("testfiles/".to_string()),
+            1024_usize,
+            qdrant_url.clone(),
+            "test-search-collection".to_string(),
+            true,
+            None,
+            None,
+        );
+        assert!(pipeline.run().await.is_ok());
+        let state = AppState {
+            collection: CollectionState {
+                vectordb: VectorDB::new(qdrant_url, "test-search-collection".to_string(), false),
+                embedder: EmbeddingConfig::default().build_embedder(None).unwrap(),
+            },
+            ..test_state()
+        };
+        let Json(response) = search(
+            State(state.clone()),
+            Path("test-search-collection".to_string()),
+            Json(SearchRequest {
+                query: "Is this a test?".to_string(),
+                limit: Some(2),
+                score_threshold: None,
+            }),
+        )
+        .await
+        .ok()
+        .unwrap();
+        assert!(!response.results.is_empty() && response.results.len() <= 2);
+        assert!(response.results.iter().all(|r| r.source_file.is_some()));
+        let Json(response) = search(
+            State(state),
+            Path("test-search-collection".to_string()),
+            Json(SearchRequest {
+                query: "Is this a test?".to_string(),
+                limit: Some(2),
+                score_threshold: Some(f32::MAX),
+            }),
+        )
+        .await
+        .ok()
+        .unwrap();
+        assert!(response.results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allowed_collections() {
+        let loader = CollectionLoader {
+            allowed: vec!["tenant-a".to_string()],
+            // nothing listens there, so that loading the allowed collections fails
+            qdrant_url: "http://localhost:1".to_string(),
+            qdrant_tls: false,
+            openai_api_key: "secret".to_string(),
+            embedding_options: EmbeddingOptions::default(),
+        };
+        assert!(loader.allows("tenant-a"));
+        assert!(!loader.allows("tenant-b"));
+        assert!(!format!("{:?}", loader).contains("secret"));
+        let any = CollectionLoader {
+            allowed: vec![ALL_COLLECTIONS.to_string()],
+            ..loader.clone()
+        };
+        assert!(any.allows("tenant-b"));
+        let state = AppState {
+            collection_loader: Some(loader),
+            ..test_state()
+        };
+        let request = |collection: &str| RagRequest {
+            query: "Is this a test?".to_string(),
+            collection: Some(collection.to_string()),
+            ..RagRequest::default()
+        };
+        let err = rag(State(state.clone()), Json(request("tenant-a")))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.status_code, 404);
+        assert!(
+            err.detail
+                .starts_with("Could not load collection 'tenant-a': ")
+        );
+        let err = rag(State(state), Json(request("tenant-b")))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.status_code, 400);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_collection_alias() {
+        let collection = test_state().collection;
+        let state = AppState {
+            aliases: HashMap::from([
+                ("tech".to_string(), collection.clone()),
+                ("legal".to_string(), collection),
+            ]),
+            ..test_state()
+        };
+        let result = rag(
+            State(state),
+            Json(RagRequest {
+                query: "Is this a test?".to_string(),
+                collection: Some("finance".to_string()),
+                ..RagRequest::default()
+            }),
+        )
+        .await;
+        let err = result.err().unwrap();
+        assert_eq!(err.status_code, 400);
+        assert_eq!(
+            err.detail,
+            "Unknown collection alias 'finance'. Available aliases: legal, tech"
+        );
+    }
+
+    #[test]
+    fn test_citations() {
+        let result =
+            |source: &str, page_start: Option<usize>, page_end: Option<usize>| SearchResult {
+                content: String::new(),
+                score: 0.5,
+                source: Some(source.to_string()),
+                language: None,
+                chunk_index: None,
+                span: None,
+                page_start,
+                page_end,
+                vector: None,
+            };
+        let response = RagResponse::new(
+            String::new(),
+            vec![
+                result("report.pdf", Some(12), Some(13)),
+                result("report.pdf", Some(2), Some(2)),
+                result("notes.md", None, None),
+                result("report.pdf", Some(12), Some(13)),
+            ],
+            false,
+            None,
+        );
+        assert_eq!(
+            response.citations,
+            vec!["report.pdf, p. 12–13", "report.pdf, p. 2", "notes.md"]
+        );
+    }
+
+    #[test]
+    fn test_query_language_is_debug_info() {
+        let results = vec![SearchResult {
+            content: "Il gatto dorme sul divano".to_string(),
+            score: 0.5,
+            source: None,
+            language: Some("it".to_string()),
+            chunk_index: None,
+            span: None,
+            page_start: None,
+            page_end: None,
+            vector: None,
+        }];
+        let plain = RagResponse::new(
+            String::new(),
+            results.clone(),
+            false,
+            Some("it".to_string()),
+        );
+        assert!(plain.query_language.is_none());
+        assert!(plain.citations.is_empty());
+        let debug = RagResponse::new(String::new(), results, true, Some("it".to_string()));
+        assert_eq!(debug.query_language, Some("it".to_string()));
+        assert_eq!(
+            debug.search_results.unwrap()[0].language,
+            Some("it".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_endpoint() {
+        let qdrant_url_var = std::env::var("QDRANT_URL");
+        let qdrant_url = match qdrant_url_var {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                println!("Skipping test because Qdrant is not available");
+                return;
             }
         };
         let openai_api_key_var = std::env::var("OPENAI_API_KEY");
@@ -301,7 +2092,7 @@ mod test {
             }
         };
         let pipeline = Pipeline::new(
-            "testfiles/".to_string(),
+            Some("testfiles/".to_string()),
             1024_usize,
             qdrant_url.clone(),
             "test-serving-collection".to_string(),
@@ -311,16 +2102,27 @@ mod test {
         );
         let result = pipeline.run().await;
         assert!(result.is_ok());
-        let vectordb = VectorDB::new(qdrant_url, "test-serving-collection".to_string());
+        let vectordb = VectorDB::new(qdrant_url, "test-serving-collection".to_string(), false);
+        let embedding_config =
+            EmbeddingConfig::from_metadata(&vectordb.get_metadata().await.unwrap())
+                .unwrap()
+                .unwrap();
         let state = AppState {
-            vectordb: vectordb,
+            collection: CollectionState {
+                vectordb,
+                embedder: embedding_config
+                    .build_embedder(Some(&openai_api_key))
+                    .unwrap(),
+            },
             openai_client: Client::with_config(OpenAIConfig::new().with_api_key(openai_api_key)),
+            ..test_state()
         };
+        let query_history = state.query_history.clone();
         let mut app = Router::new().route("/queries", post(rag)).with_state(state);
         let request_body = serde_json::to_string(&RagRequest {
             query: "Is this a test?".to_string(),
             limit: Some(1_u64),
-            openai_model: None,
+            ..RagRequest::default()
         })
         .unwrap();
         let response = app
@@ -335,5 +2137,193 @@ mod test {
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
+        let records = query_history.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].query, "Is this a test?");
+        assert_eq!(records[0].model, DEFAULT_OPENAI_MODEL);
+        assert_eq!(records[0].retrieved_count, 1);
+    }
+
+    fn admin_request(method: &str, uri: &str, token: Option<&str>, body: &str) -> Request<Body> {
+        let mut builder = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    async fn json_body<T: serde::de::DeserializeOwned>(response: Response) -> T {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn admin_state() -> AdminState {
+        AdminState {
+            // nothing listens there, so that reload jobs fail once the documents are parsed
+            qdrant_url: "http://localhost:1".to_string(),
+            qdrant_tls: false,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            query_history: QueryHistory::new(DEFAULT_QUERY_HISTORY_SIZE),
+            cache_options: CacheOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_token_required() {
+        let mut disabled: Router = admin_router(None, admin_state());
+        let response = disabled
+            .call(admin_request("GET", "/jobs/1", Some("secret"), ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let mut app: Router = admin_router(Some("secret".to_string()), admin_state());
+        for token in [None, Some("wrong")] {
+            let response = app
+                .call(admin_request("GET", "/jobs/1", token, ""))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = app
+            .call(admin_request("GET", "/jobs/1", Some("secret"), ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let err: RagError = json_body(response).await;
+        assert_eq!(err.status_code, 404);
+    }
+
+    #[tokio::test]
+    async fn test_query_history() {
+        let state = admin_state();
+        let history = QueryHistory::new(2);
+        let mut app: Router = admin_router(
+            Some("secret".to_string()),
+            AdminState {
+                query_history: history.clone(),
+                ..state
+            },
+        );
+        for i in 0..3 {
+            history.push(QueryRecord {
+                query: format!("query {}", i),
+                timestamp: 0,
+                latency_ms: 10,
+                model: DEFAULT_OPENAI_MODEL.to_string(),
+                retrieved_count: 1,
+            });
+        }
+        let unauthorized = app
+            .call(admin_request("GET", "/queries/history", None, ""))
+            .await
+            .unwrap();
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+        let response = app
+            .call(admin_request("GET", "/queries/history", Some("secret"), ""))
+            .await
+            .unwrap();
+        let records: Vec<QueryRecord> = json_body(response).await;
+        let queries: Vec<&str> = records.iter().map(|r| r.query.as_str()).collect();
+        assert_eq!(queries, vec!["query 1", "query 2"]);
+        let disabled = QueryHistory::new(0);
+        disabled.push(records[0].clone());
+        assert!(disabled.records().is_empty());
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_reload_job_span() {
+        let mut app: Router = admin_router(Some("secret".to_string()), admin_state());
+        let response = app
+            .call(admin_request(
+                "POST",
+                "/collections/test-span-collection/reload",
+                Some("secret"),
+                r#"{"directory": "testfiles/"}"#,
+            ))
+            .await
+            .unwrap();
+        let job_id = json_body::<ReloadResponse>(response).await.job_id;
+        for _ in 0..100 {
+            if logs_contain("ReloadJobFailed") || logs_contain("ReloadJobEnd") {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        // the events of the background job are nested in the span of the reload request
+        logs_assert(|lines: &[&str]| {
+            match lines.iter().any(|l| {
+                l.contains("reload{collection_name=\"test-span-collection\"")
+                    && l.contains("Starting reload job")
+                    && l.contains(&job_id)
+            }) {
+                true => Ok(()),
+                false => Err("The reload job was not logged in the request span".to_string()),
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_reload_job() {
+        let mut app: Router = admin_router(Some("secret".to_string()), admin_state());
+        let invalid = app
+            .call(admin_request(
+                "POST",
+                "/collections/test.collection/reload",
+                Some("secret"),
+                r#"{"directory": "testfiles/"}"#,
+            ))
+            .await
+            .unwrap();
+        // the status code is sent as the HTTP status too, e.g. for CI webhooks
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body::<RagError>(invalid).await.status_code, 400);
+        let missing = app
+            .call(admin_request(
+                "POST",
+                "/collections/test-reload-collection/reload",
+                Some("secret"),
+                r#"{"directory": "missing-directory/"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(json_body::<RagError>(missing).await.status_code, 400);
+        let response = app
+            .call(admin_request(
+                "POST",
+                "/collections/test-reload-collection/reload",
+                Some("secret"),
+                r#"{"directory": "testfiles/", "chunk_size": 512}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let job_id = json_body::<ReloadResponse>(response).await.job_id;
+        let mut job: Option<Job> = None;
+        for _ in 0..100 {
+            let response = app
+                .call(admin_request(
+                    "GET",
+                    &format!("/jobs/{}", job_id),
+                    Some("secret"),
+                    "",
+                ))
+                .await
+                .unwrap();
+            let polled: Job = json_body(response).await;
+            if polled.status != JobStatus::Running {
+                job = Some(polled);
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        let job = job.expect("The reload job should finish");
+        assert_eq!(job.collection_name, "test-reload-collection");
+        assert!(matches!(job.status, JobStatus::Failed { .. }));
     }
 }
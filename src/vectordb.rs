@@ -1,50 +1,186 @@
+use async_trait::async_trait;
 use bm25::Embedding;
 use qdrant_client::{
     Payload, Qdrant,
     qdrant::{
-        CreateCollectionBuilder, NamedVectors, PointStruct, QueryPointsBuilder,
-        SparseVectorParamsBuilder, SparseVectorsConfigBuilder, UpsertPointsBuilder, Vector,
+        CollectionConfig, Condition, CountPointsBuilder, CreateCollectionBuilder,
+        CreateFieldIndexCollectionBuilder, DeletePointsBuilder, Distance, FieldType, Filter,
+        Fusion, NamedVectors, PointId, PointStruct, PrefetchQueryBuilder, Query,
+        QueryPointsBuilder, RecommendInputBuilder, RecommendStrategy, ScrollPointsBuilder,
+        SetPayloadPointsBuilder, SparseVectorParamsBuilder, SparseVectorsConfigBuilder,
+        UpdateCollectionBuilder, UpsertPointsBuilder, Value, Vector, VectorInput,
+        VectorParamsBuilder, VectorsConfigBuilder, point_id::PointIdOptions, vector_output,
+        vectors_config,
     },
 };
-use std::collections::HashMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::Range,
+    sync::Arc,
+};
+
+use crate::{
+    chunking::Chunk,
+    embedding::{EmbeddingProvider, EmbeddingVector},
+};
+
+// Payload field holding the ISO 639-1 code of the language of each chunk
+const LANGUAGE_PAYLOAD_FIELD: &str = "lang";
+// Name of the `source` payload field in the search results, accepted in the filters as well
+const SOURCE_FILE_FILTER_FIELD: &str = "source_file";
+const MAX_COLLECTION_NAME_LENGTH: usize = 255;
+// number of point IDs read per request when looking for the largest one
+const MAX_ID_SCROLL_LIMIT: u32 = 10_000;
+
+/// Parameters of `VectorDB::search` besides the query embedding
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Number of chunks retrieved
+    pub limit: u64,
+    /// Return the stored vector of each chunk, to inspect why it matched
+    pub include_vectors: bool,
+    /// Only retrieve the chunks in this language
+    pub language: Option<String>,
+    /// Only score the chunks whose payload matches every field, see `payload_conditions`
+    pub pre_filter: Option<HashMap<String, serde_json::Value>>,
+    /// Push the chunks close to these embeddings (e.g. of unwanted topics) down the results
+    pub negative_embeddings: Vec<EmbeddingVector>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub content: String,
+    pub score: f32,
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Position of the chunk in its document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<usize>,
+    /// Byte offsets of the chunk in the text of its document, e.g. to cite it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Range<usize>>,
+    /// First and last pages of the chunk, for the documents that have pages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_start: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_end: Option<usize>,
+    /// The stored vector, only retrieved when requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<(u32, f32)>>,
+}
+
+/// Builds the Qdrant clients of a `VectorDB`, e.g. to inject another client in tests
+#[async_trait]
+pub trait QdrantClientFactory: Send + Sync + fmt::Debug {
+    async fn build_client(&self) -> anyhow::Result<Qdrant>;
+}
+
+/// Clients of the instance at `url`, authenticated with the `QDRANT_API_KEY` environment
+/// variable when it is set, and connected with TLS when `use_tls` is set
+#[derive(Debug)]
+pub struct DefaultQdrantClientFactory {
+    url: String,
+    use_tls: bool,
+}
+
+impl DefaultQdrantClientFactory {
+    pub fn new(url: String, use_tls: bool) -> Self {
+        Self { url, use_tls }
+    }
+}
 
-use crate::chunking::Chunk;
+#[async_trait]
+impl QdrantClientFactory for DefaultQdrantClientFactory {
+    async fn build_client(&self) -> anyhow::Result<Qdrant> {
+        Ok(
+            Qdrant::from_url(&tls_qdrant_url(self.url.clone(), self.use_tls)?)
+                .api_key(std::env::var("QDRANT_API_KEY"))
+                .build()?,
+        )
+    }
+}
+
+/// The URL of a Qdrant instance, with the `https` scheme when `use_tls` is set: the client
+/// then connects with TLS, and verifies the server with the system root certificates
+fn tls_qdrant_url(url: String, use_tls: bool) -> anyhow::Result<String> {
+    if !use_tls || url.starts_with("https://") {
+        return Ok(url);
+    }
+    if let Some(address) = url.strip_prefix("http://") {
+        return Ok(format!("https://{}", address));
+    }
+    if let Some((scheme, _)) = url.split_once("://") {
+        return Err(anyhow::anyhow!(
+            "Cannot connect with TLS to the Qdrant URL {}: unsupported scheme {}",
+            url,
+            scheme
+        ));
+    }
+    Ok(format!("https://{}", url))
+}
 
 #[derive(Debug, Clone)]
 pub struct VectorDB {
     pub collection_name: String,
-    pub url: String,
+    pub client_factory: Arc<dyn QdrantClientFactory>,
 }
 
 impl VectorDB {
-    pub fn new(url: String, collection_name: String) -> Self {
+    pub fn new(url: String, collection_name: String, use_tls: bool) -> Self {
         Self {
             collection_name,
-            url,
+            client_factory: Arc::new(DefaultQdrantClientFactory::new(url, use_tls)),
         }
     }
 
-    pub async fn create_collection(&self) -> anyhow::Result<()> {
-        let client = Qdrant::from_url(&self.url)
-            .api_key(std::env::var("QDRANT_API_KEY"))
-            .build()?;
+    pub async fn exists(&self) -> anyhow::Result<bool> {
+        let client = self.client_factory.build_client().await?;
+        Ok(client.collection_exists(&self.collection_name).await?)
+    }
+
+    /// Create the collection with the vector type produced by the embedding provider. An
+    /// existing collection is reused only if it has the vectors the provider needs.
+    pub async fn create_collection(&self, provider: EmbeddingProvider) -> anyhow::Result<()> {
+        let client = self.client_factory.build_client().await?;
         println!("Starting to create collection {}", self.collection_name);
         let collection_exists = client.collection_exists(&self.collection_name).await?;
         if collection_exists {
             println!("Collection {} already exists", self.collection_name);
-            return Ok(());
+            let mismatches = schema_mismatches(&self.get_collection_config().await?, provider);
+            if !mismatches.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Schema mismatch: collection {} cannot store {} embeddings, {}",
+                    self.collection_name,
+                    provider,
+                    mismatches.join(", ")
+                ));
+            }
+            // collections created before languages were stored lack the index
+            return self.create_language_index(&client).await;
         }
-        let mut sparse_vector_config = SparseVectorsConfigBuilder::default();
-        sparse_vector_config.add_named_vector_params("text", SparseVectorParamsBuilder::default());
-        let response = client
-            .create_collection(
-                CreateCollectionBuilder::new(&self.collection_name)
-                    .sparse_vectors_config(sparse_vector_config),
-            )
-            .await?;
+        let mut collection = CreateCollectionBuilder::new(&self.collection_name);
+        if let (Some(name), Some(size)) =
+            (provider.dense_vector_name(), provider.dense_dimensions())
+        {
+            let mut vector_config = VectorsConfigBuilder::default();
+            vector_config
+                .add_named_vector_params(name, VectorParamsBuilder::new(size, Distance::Cosine));
+            collection = collection.vectors_config(vector_config);
+        }
+        if let Some(name) = provider.sparse_vector_name() {
+            let mut sparse_vector_config = SparseVectorsConfigBuilder::default();
+            sparse_vector_config
+                .add_named_vector_params(name, SparseVectorParamsBuilder::default());
+            collection = collection.sparse_vectors_config(sparse_vector_config);
+        }
+        let response = client.create_collection(collection).await?;
         if response.result {
             println!("Collection {} successfully created", self.collection_name);
-            Ok(())
+            self.create_language_index(&client).await
         } else {
             eprintln!(
                 "There was an error creating collection: {}",
@@ -56,21 +192,103 @@ impl VectorDB {
         }
     }
 
-    pub async fn upload_embeddings(&self, chunks: Vec<Chunk>) -> anyhow::Result<()> {
+    /// Index the language of the chunks, so that searches can be filtered by language
+    async fn create_language_index(&self, client: &Qdrant) -> anyhow::Result<()> {
+        client
+            .create_field_index(CreateFieldIndexCollectionBuilder::new(
+                &self.collection_name,
+                LANGUAGE_PAYLOAD_FIELD,
+                FieldType::Keyword,
+            ))
+            .await?;
+        Ok(())
+    }
+
+    /// Retrieve the configuration of the collection, including its vectors and metadata
+    pub async fn get_collection_config(&self) -> anyhow::Result<CollectionConfig> {
+        let client = self.client_factory.build_client().await?;
+        let result = client.collection_info(&self.collection_name).await?;
+        match result.result.and_then(|r| r.config) {
+            Some(config) => Ok(config),
+            None => {
+                eprintln!("Could not retrieve collection information");
+                Err(anyhow::anyhow!("Could not retrieve collection information"))
+            }
+        }
+    }
+
+    /// Retrieve the metadata stored alongside the collection
+    pub async fn get_metadata(&self) -> anyhow::Result<HashMap<String, serde_json::Value>> {
+        Ok(self
+            .get_collection_config()
+            .await?
+            .metadata
+            .into_iter()
+            .map(|(k, v)| (k, serde_json::Value::from(v)))
+            .collect())
+    }
+
+    /// Store metadata alongside the collection, merging it with the already stored one
+    pub async fn set_metadata(
+        &self,
+        metadata: HashMap<String, serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        let client = self.client_factory.build_client().await?;
+        let response = client
+            .update_collection(
+                UpdateCollectionBuilder::new(&self.collection_name).metadata(metadata),
+            )
+            .await?;
+        if response.result {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "There was an error updating the metadata of the Qdrant collection"
+            ))
+        }
+    }
+
+    /// Create a snapshot of the collection on the Qdrant server, returning its name
+    pub async fn create_snapshot(&self) -> anyhow::Result<String> {
+        let client = self.client_factory.build_client().await?;
+        let response = client.create_snapshot(&self.collection_name).await?;
+        match response.snapshot_description {
+            Some(snapshot) => Ok(snapshot.name),
+            None => Err(anyhow::anyhow!(
+                "Qdrant did not return the description of the snapshot of {}",
+                self.collection_name
+            )),
+        }
+    }
+
+    /// Names of the snapshots of the collection, from the oldest to the newest
+    pub async fn list_snapshots(&self) -> anyhow::Result<Vec<String>> {
+        let client = self.client_factory.build_client().await?;
+        let mut snapshots = client
+            .list_snapshots(&self.collection_name)
+            .await?
+            .snapshot_descriptions;
+        snapshots.sort_by_key(|s| s.creation_time.map(|t| (t.seconds, t.nanos)));
+        Ok(snapshots.into_iter().map(|s| s.name).collect())
+    }
+
+    /// ID after which the points of an upload get theirs, see `upload_embeddings`. In append
+    /// mode, this is the largest ID in the collection; otherwise, `None` is returned for a
+    /// collection that already has points, where nothing should be uploaded.
+    pub async fn upload_base_id(&self, append_mode: bool) -> anyhow::Result<Option<u64>> {
         let collection_ready = self.check_collection_ready().await;
-        let mut base_id = match collection_ready {
+        match collection_ready {
+            // not ready -> exists but does not contain points
+            Ok(0) => Ok(Some(0)),
+            // ready -> exists and contains points
+            Ok(_) if !append_mode => Ok(None),
             Ok(num_points) => {
-                // not ready -> exists but does not contain points
-                if num_points == 0 {
-                    num_points
-                } else {
-                    // ready -> exists and contains points
-                    eprintln!(
-                        "WARNING: collection already has {:?} points, preparing to upload more...",
-                        num_points
-                    );
+                eprintln!(
+                    "WARNING: collection already has {:?} points, preparing to upload more...",
                     num_points
-                }
+                );
+                // the points may have been deleted since, so their number is not enough
+                Ok(Some(self.max_point_id().await?))
             }
             // error: does not exist or fails to check for points
             Err(e) => {
@@ -78,14 +296,23 @@ impl VectorDB {
                     "There was an error during the collection health check: {}",
                     e,
                 );
-                return Err(anyhow::anyhow!(
+                Err(anyhow::anyhow!(
                     "There was an error during the collection health check"
-                ));
+                ))
             }
-        };
-        let client = Qdrant::from_url(&self.url)
-            .api_key(std::env::var("QDRANT_API_KEY"))
-            .build()?;
+        }
+    }
+
+    /// Upload the chunks, with the named vectors of the provider they were embedded with. The
+    /// points get the IDs following `base_id`, and the last one is returned for the next upload.
+    pub async fn upload_embeddings(
+        &self,
+        chunks: Vec<Chunk>,
+        provider: EmbeddingProvider,
+        base_id: u64,
+    ) -> anyhow::Result<u64> {
+        let mut last_id = base_id;
+        let client = self.client_factory.build_client().await?;
         println!(
             "Starting to upload embeddings to collection {}",
             self.collection_name
@@ -102,32 +329,43 @@ impl VectorDB {
         }
         let mut points: Vec<PointStruct> = vec![];
         for chunk in chunks {
-            base_id += 1;
-            let embd = match chunk.embedding {
-                Some(e) => e,
-                None => {
-                    eprintln!(
-                        "Embedding {:?} does not have an associated embedding, skipping...",
-                        base_id
-                    );
-                    continue;
-                }
-            };
-            let mut index_map: HashMap<u32, f32> = HashMap::new();
-            for token in &embd.0 {
-                *index_map.entry(token.index).or_insert(0.0) += token.value;
-            }
-            let mut index_value_pairs: Vec<_> = index_map.into_iter().collect();
-            index_value_pairs.sort_by_key(|(idx, _)| *idx);
-            let (indices, values): (Vec<u32>, Vec<f32>) = index_value_pairs.into_iter().unzip();
-            let vector = Vector::new_sparse(indices, values);
+            last_id += 1;
+            let mut vectors = NamedVectors::default();
+            if let (Some(name), Some(e)) = (provider.sparse_vector_name(), chunk.sparse_embedding) {
+                vectors = vectors.add_vector(name, sparse_vector(&e));
+            }
+            if let (Some(name), Some(d)) = (provider.dense_vector_name(), chunk.dense_embedding) {
+                vectors = vectors.add_vector(name, Vector::new_dense(d));
+            }
+            if vectors.vectors.is_empty() {
+                eprintln!(
+                    "Embedding {:?} does not have an associated embedding, skipping...",
+                    last_id
+                );
+                continue;
+            }
             let mut payload = Payload::new();
             payload.insert("content", chunk.content);
-            let point = PointStruct::new(
-                base_id,
-                NamedVectors::default().add_vector("text", vector),
-                payload,
-            );
+            if let Some(source) = chunk.source {
+                payload.insert("source", source);
+            }
+            if let Some(language) = chunk.language {
+                payload.insert(LANGUAGE_PAYLOAD_FIELD, language);
+            }
+            if let Some(heading_path) = chunk.heading_path {
+                payload.insert("heading_path", heading_path);
+            }
+            if let Some(chunk_index) = chunk.chunk_index {
+                payload.insert("chunk_index", chunk_index as i64);
+            }
+            if let Some(span) = chunk.span {
+                payload.insert("span", serde_json::json!(span));
+            }
+            if let (Some(page_start), Some(page_end)) = (chunk.page_start, chunk.page_end) {
+                payload.insert("page_start", page_start as i64);
+                payload.insert("page_end", page_end as i64);
+            }
+            let point = PointStruct::new(last_id, vectors, payload);
             points.push(point);
         }
         let response = client
@@ -144,13 +382,116 @@ impl VectorDB {
                 ));
             }
         }
-        Ok(())
+        Ok(last_id)
+    }
+
+    /// Largest numeric ID of the points of the collection, or 0 without points
+    async fn max_point_id(&self) -> anyhow::Result<u64> {
+        let client = self.client_factory.build_client().await?;
+        let mut max_id = 0;
+        let mut offset: Option<PointId> = None;
+        loop {
+            let mut scroll = ScrollPointsBuilder::new(&self.collection_name)
+                .limit(MAX_ID_SCROLL_LIMIT)
+                .with_payload(false)
+                .with_vectors(false);
+            if let Some(offset) = offset.take() {
+                scroll = scroll.offset(offset);
+            }
+            let response = client.scroll(scroll).await?;
+            for point in response.result {
+                if let Some(PointIdOptions::Num(id)) = point.id.and_then(|id| id.point_id_options) {
+                    max_id = max_id.max(id);
+                }
+            }
+            match response.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => return Ok(max_id),
+            }
+        }
+    }
+
+    /// Set the `key` payload field of the chunks of `source_file` (e.g. a document version),
+    /// without embedding them again. Returns the number of updated points.
+    pub async fn update_payload(
+        &self,
+        source_file: &str,
+        key: &str,
+        value: serde_json::Value,
+    ) -> anyhow::Result<u64> {
+        let filter = Filter::must([Condition::matches("source", source_file.to_string())]);
+        self.set_payload_matching(filter, key, value).await
+    }
+
+    /// Set the `key` payload field of the points lacking it (e.g. stored before the field
+    /// was introduced). Returns the number of updated points.
+    pub async fn fill_missing_payload(
+        &self,
+        key: &str,
+        value: serde_json::Value,
+    ) -> anyhow::Result<u64> {
+        self.set_payload_matching(Filter::must([Condition::is_empty(key)]), key, value)
+            .await
+    }
+
+    /// Delete the chunks of `source_file`, e.g. before uploading its new version. Returns the
+    /// number of deleted points.
+    pub async fn delete_source_points(&self, source_file: &str) -> anyhow::Result<u64> {
+        let client = self.client_factory.build_client().await?;
+        let filter = Filter::must([Condition::matches("source", source_file.to_string())]);
+        let count = self.count_matching(&client, filter.clone()).await?;
+        if count == 0 {
+            return Ok(0);
+        }
+        client
+            .delete_points(
+                DeletePointsBuilder::new(&self.collection_name)
+                    .points(filter)
+                    .wait(true),
+            )
+            .await?;
+        Ok(count)
+    }
+
+    // updating and deleting points do not report how many points matched the filter
+    async fn count_matching(&self, client: &Qdrant, filter: Filter) -> anyhow::Result<u64> {
+        Ok(client
+            .count(
+                CountPointsBuilder::new(&self.collection_name)
+                    .filter(filter)
+                    .exact(true),
+            )
+            .await?
+            .result
+            .map(|r| r.count)
+            .unwrap_or_default())
+    }
+
+    async fn set_payload_matching(
+        &self,
+        filter: Filter,
+        key: &str,
+        value: serde_json::Value,
+    ) -> anyhow::Result<u64> {
+        let client = self.client_factory.build_client().await?;
+        let count = self.count_matching(&client, filter.clone()).await?;
+        if count == 0 {
+            return Ok(0);
+        }
+        let mut payload = Payload::new();
+        payload.insert(key, value);
+        client
+            .set_payload(
+                SetPayloadPointsBuilder::new(&self.collection_name, payload)
+                    .points_selector(filter)
+                    .wait(true),
+            )
+            .await?;
+        Ok(count)
     }
 
     pub async fn check_collection_ready(&self) -> anyhow::Result<u64> {
-        let client = Qdrant::from_url(&self.url)
-            .api_key(std::env::var("QDRANT_API_KEY"))
-            .build()?;
+        let client = self.client_factory.build_client().await?;
         let collection_exists = client.collection_exists(&self.collection_name).await?;
         if !collection_exists {
             eprintln!(
@@ -186,21 +527,90 @@ impl VectorDB {
         }
     }
 
-    pub async fn search(self, embedding: Embedding, limit: u64) -> anyhow::Result<Vec<String>> {
-        let client = Qdrant::from_url(&self.url)
-            .api_key(std::env::var("QDRANT_API_KEY"))
-            .build()?;
-        let mut indices_values: Vec<(u32, f32)> = vec![];
-        for token in &embedding.0 {
-            indices_values.push((token.index, token.value));
+    /// Retrieve the chunks closest to the query embedding, see `SearchOptions`
+    pub async fn search(
+        self,
+        embedding: EmbeddingVector,
+        options: SearchOptions,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let SearchOptions {
+            limit,
+            include_vectors,
+            language,
+            pre_filter,
+            negative_embeddings: negatives,
+        } = options;
+        let client = self.client_factory.build_client().await?;
+        let mut conditions = match &pre_filter {
+            Some(pre_filter) => payload_conditions(pre_filter)?,
+            None => vec![],
+        };
+        if let Some(language) = language {
+            conditions.push(Condition::matches(LANGUAGE_PAYLOAD_FIELD, language));
+        }
+        let filter = (!conditions.is_empty()).then(|| Filter::must(conditions));
+        let sparse_negatives: Vec<VectorInput> = negatives
+            .iter()
+            .filter_map(|n| match n {
+                EmbeddingVector::Sparse(s) | EmbeddingVector::Hybrid { sparse: s, .. } => {
+                    Some(sparse_input(s))
+                }
+                EmbeddingVector::Dense(_) => None,
+            })
+            .collect();
+        let dense_negatives: Vec<VectorInput> = negatives
+            .into_iter()
+            .filter_map(|n| match n {
+                EmbeddingVector::Dense(d) | EmbeddingVector::Hybrid { dense: d, .. } => {
+                    Some(VectorInput::new_dense(d))
+                }
+                EmbeddingVector::Sparse(_) => None,
+            })
+            .collect();
+        let mut query = match embedding {
+            EmbeddingVector::Sparse(e) => QueryPointsBuilder::new(&self.collection_name)
+                .query(example_query(sparse_input(&e), sparse_negatives))
+                .using("text"),
+            EmbeddingVector::Dense(d) => QueryPointsBuilder::new(&self.collection_name)
+                .query(example_query(VectorInput::new_dense(d), dense_negatives))
+                .using("text"),
+            // retrieve candidates with both vectors, and merge them with reciprocal rank fusion
+            EmbeddingVector::Hybrid { sparse, dense } => {
+                let sparse_name = EmbeddingProvider::Hybrid
+                    .sparse_vector_name()
+                    .unwrap_or("text");
+                let dense_name = EmbeddingProvider::Hybrid
+                    .dense_vector_name()
+                    .unwrap_or("dense");
+                let mut sparse_prefetch = PrefetchQueryBuilder::default()
+                    .query(example_query(sparse_input(&sparse), sparse_negatives))
+                    .using(sparse_name)
+                    .limit(limit);
+                let mut dense_prefetch = PrefetchQueryBuilder::default()
+                    .query(example_query(
+                        VectorInput::new_dense(dense),
+                        dense_negatives,
+                    ))
+                    .using(dense_name)
+                    .limit(limit);
+                if let Some(filter) = &filter {
+                    sparse_prefetch = sparse_prefetch.filter(filter.clone());
+                    dense_prefetch = dense_prefetch.filter(filter.clone());
+                }
+                QueryPointsBuilder::new(&self.collection_name)
+                    .add_prefetch(sparse_prefetch)
+                    .add_prefetch(dense_prefetch)
+                    .query(Query::new_fusion(Fusion::Rrf))
+            }
+        }
+        .limit(limit)
+        .with_payload(true)
+        .with_vectors(include_vectors);
+        if let Some(filter) = filter {
+            query = query.filter(filter);
         }
-        let query = QueryPointsBuilder::new(&self.collection_name)
-            .query(indices_values)
-            .limit(limit)
-            .with_payload(true)
-            .using("text");
         let results = client.query(query).await?;
-        let mut contents: Vec<String> = vec![];
+        let mut search_results: Vec<SearchResult> = vec![];
         for res in results.result {
             if res.payload.contains_key("content") {
                 let content: String = match res.payload.get("content") {
@@ -210,12 +620,472 @@ impl VectorDB {
                         continue;
                     }
                 };
-                contents.push(content);
+                let vector = res
+                    .vectors
+                    .as_ref()
+                    .and_then(|v| v.get_vector_by_name("text"))
+                    .map(indices_values);
+                search_results.push(SearchResult {
+                    content,
+                    score: res.score,
+                    source: res.payload.get("source").and_then(|s| s.as_str()).cloned(),
+                    language: res
+                        .payload
+                        .get(LANGUAGE_PAYLOAD_FIELD)
+                        .and_then(|s| s.as_str())
+                        .cloned(),
+                    chunk_index: payload_usize(&res.payload, "chunk_index"),
+                    span: res
+                        .payload
+                        .get("span")
+                        .and_then(|s| serde_json::from_value(s.clone().into_json()).ok()),
+                    page_start: payload_usize(&res.payload, "page_start"),
+                    page_end: payload_usize(&res.payload, "page_end"),
+                    vector,
+                });
             } else {
                 eprintln!("Point does not have an associated text content");
             }
         }
 
-        Ok(contents)
+        Ok(search_results)
+    }
+}
+
+/// Conditions matching the payload fields of the map with its values: strings, integers,
+/// booleans, or arrays of strings or of integers (matching any of them). `source_file` is
+/// accepted for the `source` field, as it is named in the search results.
+pub fn payload_conditions(
+    filter: &HashMap<String, serde_json::Value>,
+) -> anyhow::Result<Vec<Condition>> {
+    let mut fields: Vec<&String> = filter.keys().collect();
+    fields.sort();
+    let mut conditions: Vec<Condition> = vec![];
+    for field in fields {
+        let value = &filter[field];
+        let key = match field.as_str() {
+            SOURCE_FILE_FILTER_FIELD => "source",
+            f => f,
+        };
+        let unsupported = || {
+            anyhow::anyhow!(
+                "Unsupported value for the '{}' filter field: {} (expected a string, an integer, a boolean, or an array of strings or of integers)",
+                field,
+                value
+            )
+        };
+        let condition = match value {
+            serde_json::Value::String(s) => Condition::matches(key, s.clone()),
+            serde_json::Value::Bool(b) => Condition::matches(key, *b),
+            serde_json::Value::Number(n) => {
+                Condition::matches(key, n.as_i64().ok_or_else(unsupported)?)
+            }
+            serde_json::Value::Array(values) if values.iter().all(|v| v.is_string()) => {
+                let keywords: Vec<String> = values
+                    .iter()
+                    .filter_map(|v| v.as_str().map(String::from))
+                    .collect();
+                Condition::matches(key, keywords)
+            }
+            serde_json::Value::Array(values) if values.iter().all(|v| v.is_i64()) => {
+                let integers: Vec<i64> = values.iter().filter_map(|v| v.as_i64()).collect();
+                Condition::matches(key, integers)
+            }
+            _ => return Err(unsupported()),
+        };
+        conditions.push(condition);
+    }
+    Ok(conditions)
+}
+
+/// Parse a payload value given as JSON on the command line, see `VectorDB::update_payload`
+pub fn parse_payload_value(value: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(value).map_err(|e| {
+        format!(
+            "'{}' should be a JSON value, with strings in double quotes (e.g. '\"draft\"'): {}",
+            value, e
+        )
+    })
+}
+
+/// Differences between the vectors of an existing collection and the ones the provider needs
+fn schema_mismatches(config: &CollectionConfig, provider: EmbeddingProvider) -> Vec<String> {
+    let params = config.params.as_ref();
+    let dense: HashMap<String, u64> = match params
+        .and_then(|p| p.vectors_config.as_ref())
+        .and_then(|v| v.config.as_ref())
+    {
+        Some(vectors_config::Config::ParamsMap(m)) => {
+            m.map.iter().map(|(k, v)| (k.clone(), v.size)).collect()
+        }
+        // a single unnamed vector, as created by other tools
+        Some(vectors_config::Config::Params(p)) => HashMap::from([(String::new(), p.size)]),
+        None => HashMap::new(),
+    };
+    let sparse: HashSet<String> = params
+        .and_then(|p| p.sparse_vectors_config.as_ref())
+        .map(|s| s.map.keys().cloned().collect())
+        .unwrap_or_default();
+    let expected_dense: HashMap<String, u64> = provider
+        .dense_vector_name()
+        .zip(provider.dense_dimensions())
+        .map(|(name, size)| (name.to_string(), size))
+        .into_iter()
+        .collect();
+    let expected_sparse: HashSet<String> = provider
+        .sparse_vector_name()
+        .map(|name| name.to_string())
+        .into_iter()
+        .collect();
+    let describe = |mut names: Vec<String>| {
+        names.sort();
+        match names.is_empty() {
+            true => "none".to_string(),
+            false => names.join(", "),
+        }
+    };
+    let describe_dense = |vectors: &HashMap<String, u64>| {
+        describe(
+            vectors
+                .iter()
+                .map(|(name, size)| match name.is_empty() {
+                    true => format!("unnamed ({} dimensions)", size),
+                    false => format!("'{}' ({} dimensions)", name, size),
+                })
+                .collect(),
+        )
+    };
+    let describe_sparse =
+        |vectors: &HashSet<String>| describe(vectors.iter().map(|n| format!("'{}'", n)).collect());
+    let mut mismatches: Vec<String> = vec![];
+    if dense != expected_dense {
+        mismatches.push(format!(
+            "dense vectors: {} expected, {} found",
+            describe_dense(&expected_dense),
+            describe_dense(&dense)
+        ));
+    }
+    if sparse != expected_sparse {
+        mismatches.push(format!(
+            "sparse vectors: {} expected, {} found",
+            describe_sparse(&expected_sparse),
+            describe_sparse(&sparse)
+        ));
+    }
+    mismatches
+}
+
+/// Check that Qdrant accepts the collection name: at most 255 characters among
+/// letters, digits, underscores and hyphens
+pub fn validate_collection_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        return Err(anyhow::anyhow!("The collection name should not be empty"));
+    }
+    let length = name.chars().count();
+    if length > MAX_COLLECTION_NAME_LENGTH {
+        return Err(anyhow::anyhow!(
+            "The collection name is {} characters long, but at most {} are allowed",
+            length,
+            MAX_COLLECTION_NAME_LENGTH
+        ));
+    }
+    let invalid_character = Regex::new(r"[^A-Za-z0-9_-]")?;
+    if let Some(m) = invalid_character.find(name) {
+        return Err(anyhow::anyhow!(
+            "Invalid character '{}' at position {} of collection name '{}': only letters, digits, underscores and hyphens are allowed",
+            m.as_str(),
+            name[..m.start()].chars().count(),
+            name
+        ));
+    }
+    Ok(())
+}
+
+fn sparse_vector(embedding: &Embedding) -> Vector {
+    // a token can appear several times in an embedding, but Qdrant requires unique indices
+    let mut index_map: HashMap<u32, f32> = HashMap::new();
+    for token in &embedding.0 {
+        *index_map.entry(token.index).or_insert(0.0) += token.value;
+    }
+    let mut index_value_pairs: Vec<_> = index_map.into_iter().collect();
+    index_value_pairs.sort_by_key(|(idx, _)| *idx);
+    let (indices, values): (Vec<u32>, Vec<f32>) = index_value_pairs.into_iter().unzip();
+    Vector::new_sparse(indices, values)
+}
+
+fn sparse_input(embedding: &Embedding) -> VectorInput {
+    let mut indices_values: Vec<(u32, f32)> = vec![];
+    for token in &embedding.0 {
+        indices_values.push((token.index, token.value));
+    }
+    indices_values.as_slice().into()
+}
+
+/// Nearest neighbours of the query vector or, with negative examples, recommendations
+/// from the query vector as the only positive example
+fn example_query(positive: VectorInput, negatives: Vec<VectorInput>) -> Query {
+    if negatives.is_empty() {
+        return Query::new_nearest(positive);
+    }
+    Query::new_recommend(
+        RecommendInputBuilder::default()
+            .positive(vec![positive])
+            .negative(negatives)
+            // compare the candidates with every example instead of averaging them, so that
+            // the results close to any of the negatives are pushed down
+            .strategy(RecommendStrategy::BestScore),
+    )
+}
+
+/// An integer payload field, e.g. the index of a chunk
+fn payload_usize(payload: &HashMap<String, Value>, field: &str) -> Option<usize> {
+    payload
+        .get(field)
+        .and_then(|i| i.as_integer())
+        .map(|i| i as usize)
+}
+
+/// Indices and values of a stored vector. Dense vectors are indexed by position.
+fn indices_values(vector: vector_output::Vector) -> Vec<(u32, f32)> {
+    match vector {
+        vector_output::Vector::Sparse(s) => s.indices.into_iter().zip(s.values).collect(),
+        vector_output::Vector::Dense(d) => (0_u32..).zip(d.data).collect(),
+        vector_output::Vector::MultiDense(m) => (0_u32..)
+            .zip(m.vectors.into_iter().flat_map(|v| v.data))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use qdrant_client::qdrant::{
+        CollectionParams, DenseVector, SparseVector, SparseVectorConfig, SparseVectorParams,
+        VectorParams, VectorParamsMap, VectorsConfig,
+    };
+
+    /// Clients of a preconfigured URL, or an error when there is none, counting the builds
+    #[derive(Debug, Default)]
+    struct MockQdrantClientFactory {
+        url: Option<String>,
+        builds: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl QdrantClientFactory for MockQdrantClientFactory {
+        async fn build_client(&self) -> anyhow::Result<Qdrant> {
+            self.builds
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            match &self.url {
+                Some(url) => Ok(Qdrant::from_url(url).build()?),
+                None => Err(anyhow::anyhow!("No Qdrant client is configured")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_factory() {
+        let factory = Arc::new(MockQdrantClientFactory::default());
+        let mut vectordb = VectorDB::new(String::new(), "test-collection".to_string(), false);
+        vectordb.client_factory = factory.clone();
+        let err = vectordb.check_collection_ready().await.unwrap_err();
+        assert_eq!(err.to_string(), "No Qdrant client is configured");
+        assert!(vectordb.create_snapshot().await.is_err());
+        assert_eq!(factory.builds.load(std::sync::atomic::Ordering::Relaxed), 2);
+        // nothing listens on the discard port
+        vectordb.client_factory = Arc::new(MockQdrantClientFactory {
+            url: Some("http://127.0.0.1:9".to_string()),
+            ..Default::default()
+        });
+        assert!(vectordb.exists().await.is_err());
+    }
+
+    #[test]
+    fn test_indices_values() {
+        let sparse = vector_output::Vector::Sparse(SparseVector {
+            indices: vec![3, 7],
+            values: vec![0.5, 1.5],
+        });
+        assert_eq!(indices_values(sparse), vec![(3, 0.5), (7, 1.5)]);
+        let dense = vector_output::Vector::Dense(DenseVector {
+            data: vec![0.1, 0.2],
+        });
+        assert_eq!(indices_values(dense), vec![(0, 0.1), (1, 0.2)]);
+    }
+
+    fn collection_config(
+        vectors_config: Option<vectors_config::Config>,
+        sparse_names: &[&str],
+    ) -> CollectionConfig {
+        CollectionConfig {
+            params: Some(CollectionParams {
+                vectors_config: vectors_config.map(|c| VectorsConfig { config: Some(c) }),
+                sparse_vectors_config: Some(SparseVectorConfig {
+                    map: sparse_names
+                        .iter()
+                        .map(|n| (n.to_string(), SparseVectorParams::default()))
+                        .collect(),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_schema_mismatches() {
+        let dense = |name: &str, size: u64| {
+            vectors_config::Config::ParamsMap(VectorParamsMap {
+                map: HashMap::from([(
+                    name.to_string(),
+                    VectorParams {
+                        size,
+                        ..Default::default()
+                    },
+                )]),
+            })
+        };
+        let bm25 = collection_config(None, &["text"]);
+        assert!(schema_mismatches(&bm25, EmbeddingProvider::Bm25).is_empty());
+        let openai = collection_config(Some(dense("text", 1536)), &[]);
+        assert!(schema_mismatches(&openai, EmbeddingProvider::OpenAI).is_empty());
+        let hybrid = collection_config(Some(dense("dense", 1536)), &["text"]);
+        assert!(schema_mismatches(&hybrid, EmbeddingProvider::Hybrid).is_empty());
+        assert_eq!(
+            schema_mismatches(&bm25, EmbeddingProvider::OpenAI),
+            vec![
+                "dense vectors: 'text' (1536 dimensions) expected, none found".to_string(),
+                "sparse vectors: none expected, 'text' found".to_string(),
+            ]
+        );
+        let other_size = collection_config(Some(dense("text", 768)), &[]);
+        assert_eq!(
+            schema_mismatches(&other_size, EmbeddingProvider::OpenAI).len(),
+            1
+        );
+        let unnamed = collection_config(
+            Some(vectors_config::Config::Params(VectorParams {
+                size: 1536,
+                ..Default::default()
+            })),
+            &[],
+        );
+        assert!(schema_mismatches(&unnamed, EmbeddingProvider::OpenAI)[0].contains("unnamed"));
+    }
+
+    #[test]
+    fn test_example_query() {
+        let positive = VectorInput::new_dense(vec![1.0, 0.0]);
+        assert_eq!(
+            example_query(positive.clone(), vec![]),
+            Query::new_nearest(positive.clone())
+        );
+        let negative = VectorInput::new_dense(vec![0.0, 1.0]);
+        match example_query(positive.clone(), vec![negative.clone()]).variant {
+            Some(qdrant_client::qdrant::query::Variant::Recommend(r)) => {
+                assert_eq!(r.positive, vec![positive]);
+                assert_eq!(r.negative, vec![negative]);
+                assert_eq!(r.strategy, Some(RecommendStrategy::BestScore as i32));
+            }
+            other => panic!("Expected a recommendation query, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_qdrant_url() {
+        let url = "http://localhost:6334".to_string();
+        assert_eq!(tls_qdrant_url(url.clone(), false).unwrap(), url);
+        assert_eq!(tls_qdrant_url(url, true).unwrap(), "https://localhost:6334");
+        let cloud = "https://xyz.cloud.qdrant.io:6334".to_string();
+        assert_eq!(tls_qdrant_url(cloud.clone(), true).unwrap(), cloud);
+        assert_eq!(
+            tls_qdrant_url("qdrant:6334".to_string(), true).unwrap(),
+            "https://qdrant:6334"
+        );
+        let err = tls_qdrant_url("grpc://qdrant:6334".to_string(), true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot connect with TLS to the Qdrant URL grpc://qdrant:6334: unsupported scheme grpc"
+        );
+        // the clients of a VectorDB use the URL rewritten for TLS
+        let factory = DefaultQdrantClientFactory::new("grpc://qdrant:6334".to_string(), true);
+        assert!(factory.build_client().await.is_err());
+        let factory = DefaultQdrantClientFactory::new("grpc://qdrant:6334".to_string(), false);
+        assert!(factory.build_client().await.is_ok());
+    }
+
+    #[test]
+    fn test_parse_payload_value() {
+        assert_eq!(parse_payload_value("2").unwrap(), serde_json::json!(2));
+        assert_eq!(
+            parse_payload_value(r#"["hr", "legal"]"#).unwrap(),
+            serde_json::json!(["hr", "legal"])
+        );
+        assert!(parse_payload_value("draft").unwrap_err().starts_with(
+            "'draft' should be a JSON value, with strings in double quotes (e.g. '\"draft\"')"
+        ));
+    }
+
+    #[test]
+    fn test_payload_conditions() {
+        let filter: HashMap<String, serde_json::Value> = serde_json::from_str(
+            r#"{"source_file": "report.pdf", "chunk_index": 2, "lang": ["en", "fr"]}"#,
+        )
+        .unwrap();
+        let conditions = payload_conditions(&filter).unwrap();
+        assert_eq!(
+            conditions,
+            vec![
+                Condition::matches("chunk_index", 2),
+                Condition::matches("lang", vec!["en".to_string(), "fr".to_string()]),
+                Condition::matches("source", "report.pdf".to_string()),
+            ]
+        );
+        assert!(payload_conditions(&HashMap::new()).unwrap().is_empty());
+        for invalid in [r#"{"score": 0.5}"#, r#"{"a": null}"#, r#"{"a": [1, "b"]}"#] {
+            let filter: HashMap<String, serde_json::Value> = serde_json::from_str(invalid).unwrap();
+            assert!(payload_conditions(&filter).is_err());
+        }
+        let filter = HashMap::from([("page_start".to_string(), serde_json::json!({"gt": 1}))]);
+        assert!(
+            payload_conditions(&filter)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Unsupported value for the 'page_start' filter field")
+        );
+    }
+
+    #[test]
+    fn test_validate_collection_name() {
+        assert!(validate_collection_name("test-collection_1").is_ok());
+        assert!(validate_collection_name("").is_err());
+        assert!(validate_collection_name(&"a".repeat(256)).is_err());
+        assert!(validate_collection_name(&"a".repeat(255)).is_ok());
+        let err = validate_collection_name("my collection").unwrap_err();
+        assert!(err.to_string().contains("' ' at position 2"));
+        let err_1 = validate_collection_name("données").unwrap_err();
+        assert!(err_1.to_string().contains("'é' at position 4"));
+    }
+
+    #[test]
+    fn test_sparse_vector_merges_duplicate_indices() {
+        let embedding = Embedding(vec![
+            bm25::TokenEmbedding {
+                index: 9,
+                value: 1.0,
+            },
+            bm25::TokenEmbedding {
+                index: 2,
+                value: 0.5,
+            },
+            bm25::TokenEmbedding {
+                index: 9,
+                value: 1.0,
+            },
+        ]);
+        assert_eq!(
+            sparse_vector(&embedding),
+            Vector::new_sparse(vec![2, 9], vec![0.5, 2.0])
+        );
     }
 }